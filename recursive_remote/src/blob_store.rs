@@ -0,0 +1,291 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use gix::prelude::Write as GixPreludeWrite;
+use gix_hash::ObjectId;
+use sha2::Digest;
+
+/// Somewhere the chunks of encoded blobs may be kept, each named by a key the
+/// store chooses. The sha256 in a `BlobRef` covers the whole decoded blob, so
+/// stores need not be trusted, but each also checks its chunks against their
+/// keys so that a bad one is reported as such.
+pub trait BlobStore {
+    type Key;
+
+    /// Stores a chunk of `len` bytes, returning the key to read it back by.
+    fn put(&self, chunk: &mut dyn Read, len: u64) -> Result<Self::Key>;
+
+    /// Reads back the chunk stored under `key`. Stores that check chunks
+    /// against their keys fail the read at the end of one that doesn't match,
+    /// so it must be read to the end.
+    fn get<'a>(&'a self, key: &Self::Key) -> Result<Box<dyn Read + 'a>>;
+
+    /// Whether a chunk is stored under `key`, without reading it.
+    fn contains(&self, key: &Self::Key) -> bool;
+
+    /// Reads the whole of the chunk stored under `key`.
+    fn get_all(&self, key: &Self::Key) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.get(key)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Keeps chunks as blobs in a git repository, such as the tracking repo.
+pub struct GitStore<'a>(pub &'a gix::Repository);
+
+impl BlobStore for GitStore<'_> {
+    type Key = ObjectId;
+
+    fn put(&self, chunk: &mut dyn Read, len: u64) -> Result<ObjectId> {
+        self.0
+            .write_stream(gix::object::Kind::Blob, len, chunk)
+            .map_err(|err| anyhow::anyhow!("create blob from file: {}", err))
+    }
+
+    fn get<'a>(&'a self, key: &ObjectId) -> Result<Box<dyn Read + 'a>> {
+        let mut blob = self
+            .0
+            .find_blob(*key)
+            .with_context(|| format!("find blob {}", key))?;
+        Ok(Box::new(std::io::Cursor::new(blob.take_data())))
+    }
+
+    fn contains(&self, key: &ObjectId) -> bool {
+        self.0.has_object(key)
+    }
+}
+
+/// A store for blobs kept out of band rather than in the upstream branch, such
+/// as large packs. Chunks are named by their content, so stores may be shared
+/// between clones and namespaces. Stores may be read from several threads at
+/// once, as when fetching packs in parallel.
+pub type Annex = dyn BlobStore<Key = String> + Send + Sync;
+
+/// Keeps annexed chunks in a local directory, such as a shared or synced folder.
+pub struct AnnexDir {
+    dir: PathBuf,
+}
+
+impl AnnexDir {
+    pub fn new(dir: PathBuf) -> AnnexDir {
+        AnnexDir { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Spread over subdirectories by the leading hash byte, so that no one
+    // directory grows too large.
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let (_size, sha256) = parse_content_key(key)?;
+        Ok(self.dir.join(hex::encode(&sha256[..1])).join(key))
+    }
+}
+
+impl BlobStore for AnnexDir {
+    type Key = String;
+
+    fn put(&self, chunk: &mut dyn Read, _len: u64) -> Result<String> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("create annex dir {:?}", &self.dir))?;
+
+        // Written aside and moved into place once its key is known, so that a
+        // chunk under its key is always complete.
+        let mut file = tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempfile_in(&self.dir)
+            .context("create annex temp file")?;
+        let key = copy_with_content_key(chunk, &mut file).context("write annex chunk")?;
+        let path = self.path(&key)?;
+        let parent = path.parent().context("annex path")?;
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create annex dir {:?}", parent))?;
+        file.persist(&path)
+            .with_context(|| format!("store annex chunk {:?}", &path))?;
+        Ok(key)
+    }
+
+    fn get<'a>(&'a self, key: &String) -> Result<Box<dyn Read + 'a>> {
+        let path = self.path(key)?;
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("annex chunk {} not found at {:?}", key, &path))?;
+        Ok(Box::new(ContentKeyReader::new(file, key)?))
+    }
+
+    fn contains(&self, key: &String) -> bool {
+        self.path(key).is_ok_and(|path| path.is_file())
+    }
+}
+
+/// Names `chunk` by its size and sha256, in git-annex's SHA256 backend format,
+/// so that a chunk can also be handed to git-annex with
+/// `git annex reinject --known`.
+pub fn content_key(chunk: &[u8]) -> String {
+    format_content_key(chunk.len() as u64, sha2::Sha256::digest(chunk).into())
+}
+
+fn format_content_key(size: u64, sha256: [u8; 32]) -> String {
+    format!("SHA256-s{}--{}", size, hex::encode(sha256))
+}
+
+/// Copies `chunk` to `writer`, returning its content key.
+pub fn copy_with_content_key(chunk: &mut dyn Read, writer: &mut dyn Write) -> Result<String> {
+    let mut hasher = sha2::Sha256::default();
+    let mut size = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = chunk.read(&mut buf).context("read chunk")?;
+        if n == 0 {
+            return Ok(format_content_key(size, hasher.finalize().into()));
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n]).context("write chunk")?;
+        size += n as u64;
+    }
+}
+
+/// Fails unless `chunk` is the one `key` names.
+pub fn check_content_key(key: &str, chunk: &[u8]) -> Result<()> {
+    parse_content_key(key)?;
+    if content_key(chunk) != key {
+        anyhow::bail!("chunk {} does not match its key", key);
+    }
+    Ok(())
+}
+
+/// Reads a chunk named by a content key, failing at its end unless it is the
+/// chunk the key names. A chunk longer than the key says fails as soon as it
+/// is, so that a bad store can't have us read without end.
+pub struct ContentKeyReader<R> {
+    inner: R,
+    key: String,
+    want_size: u64,
+    want_sha256: [u8; 32],
+    size: u64,
+    hasher: sha2::Sha256,
+}
+
+impl<R: Read> ContentKeyReader<R> {
+    pub fn new(inner: R, key: &str) -> Result<ContentKeyReader<R>> {
+        let (want_size, want_sha256) = parse_content_key(key)?;
+        Ok(ContentKeyReader {
+            inner,
+            key: key.to_string(),
+            want_size,
+            want_sha256,
+            size: 0,
+            hasher: sha2::Sha256::default(),
+        })
+    }
+}
+
+impl<R: Read> Read for ContentKeyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Asks for a byte more than the key allows, to tell a chunk that is
+        // too long.
+        let limit = (self.want_size + 1 - self.size).min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..limit])?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        if self.size > self.want_size
+            || (n == 0
+                && (self.size != self.want_size
+                    || self.hasher.finalize_reset()[..] != self.want_sha256))
+        {
+            return Err(std::io::Error::other(format!(
+                "chunk {} does not match its key",
+                &self.key
+            )));
+        }
+        Ok(n)
+    }
+}
+
+/// Splits a content key into its size and sha256. Keys come from upstream, so
+/// are checked strictly before being used in a path or URL.
+pub fn parse_content_key(key: &str) -> Result<(u64, [u8; 32])> {
+    let (size, sha256) = key
+        .strip_prefix("SHA256-s")
+        .and_then(|rest| rest.split_once("--"))
+        .with_context(|| format!("malformed content key {:?}", key))?;
+    let size = size
+        .parse()
+        .with_context(|| format!("malformed content key size {:?}", key))?;
+    let mut buf = [0u8; 32];
+    hex::decode_to_slice(sha256, &mut buf)
+        .with_context(|| format!("malformed content key hash {:?}", key))?;
+    if hex::encode(buf) != sha256 {
+        anyhow::bail!("content key hash must be lower case {:?}", key);
+    }
+    Ok((size, buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_keys_name_and_check_chunks() {
+        let key = content_key(b"big pack");
+        assert_eq!(
+            key,
+            format!(
+                "SHA256-s8--{}",
+                hex::encode(sha2::Sha256::digest(b"big pack"))
+            )
+        );
+        check_content_key(&key, b"big pack").expect("matches");
+        let err = check_content_key(&key, b"bad pack").expect_err("mismatch");
+        assert!(format!("{err}").contains("does not match its key"));
+
+        assert!(parse_content_key("SHA256-s8--../../etc/passwd").is_err());
+        assert!(parse_content_key(&key.to_uppercase()).is_err());
+        assert!(parse_content_key("git:1234").is_err());
+    }
+
+    #[test]
+    fn git_store_roundtrip() {
+        let tmp = tempfile::Builder::new()
+            .prefix("blob-store-tests")
+            .tempdir()
+            .expect("tempdir");
+        let repo = gix::init_bare(tmp.path()).expect("init bare");
+        let store = GitStore(&repo);
+        let oid = store.put(&mut &b"chunk"[..], 5).expect("put");
+        assert_eq!(store.get_all(&oid).expect("get"), b"chunk");
+    }
+
+    #[test]
+    fn annex_dir_chunks_roundtrip_and_are_verified() {
+        let tmp = tempfile::Builder::new()
+            .prefix("annex-tests")
+            .tempdir()
+            .expect("tempdir");
+        let annex = AnnexDir::new(tmp.path().join("annex"));
+
+        let key = annex.put(&mut &b"big pack"[..], 8).expect("put");
+        assert_eq!(key, content_key(b"big pack"));
+        assert_eq!(annex.get_all(&key).expect("get"), b"big pack");
+
+        std::fs::write(annex.path(&key).expect("path"), b"bad pack").expect("corrupt");
+        let err = annex.get_all(&key).expect_err("corrupt chunk");
+        assert!(format!("{err}").contains("does not match its key"));
+
+        // Nor is more read of a chunk than its key allows.
+        std::fs::write(annex.path(&key).expect("path"), b"big pack and more").expect("grow");
+        let err = annex.get_all(&key).expect_err("long chunk");
+        assert!(format!("{err}").contains("does not match its key"));
+
+        assert!(
+            annex
+                .get(&"SHA256-s8--../../etc/passwd".to_string())
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use eseb::{KeyMaterial, SymmetricKey};
+use sha2::Digest;
+
+// Content-defined chunks average about this size at most, whatever the max
+// object size, so that a small change to a large blob rewrites little of it.
+const MAX_AVERAGE_CHUNK_SIZE: usize = 512 * 1024;
+
+/// Finds content-defined chunk boundaries with a gear rolling hash, so that an
+/// insertion or deletion only moves the boundaries near it. Boundaries depend
+/// on the last 64 bytes read, and on `seed`, which is derived from the key for
+/// encrypted blobs so that chunk sizes say less about their contents.
+pub struct Chunker {
+    gear: Box<[u64; 256]>,
+    hash: u64,
+    len: usize,
+    min_size: usize,
+    max_size: usize,
+    shift: u32,
+}
+
+impl Chunker {
+    /// Chunks will be no larger than `max_size` bytes.
+    pub fn new(max_size: usize, seed: u64) -> Chunker {
+        let average = std::cmp::max(std::cmp::min(max_size / 4, MAX_AVERAGE_CHUNK_SIZE), 2);
+        let bits = average.ilog2();
+        Chunker {
+            gear: gear_table(seed),
+            hash: 0,
+            len: 0,
+            min_size: average / 4,
+            max_size,
+            shift: 64 - bits,
+        }
+    }
+
+    /// A chunker that ignores content, cutting chunks of exactly `max_size`
+    /// bytes.
+    pub fn fixed(max_size: usize) -> Chunker {
+        Chunker {
+            min_size: max_size,
+            ..Chunker::new(max_size, 0)
+        }
+    }
+
+    /// Reads `buf` as the continuation of the current chunk, returning how much
+    /// of it belongs to the chunk if the chunk ends within it. The chunker is
+    /// then positioned at the start of the next chunk.
+    pub fn find_boundary(&mut self, buf: &[u8]) -> Option<usize> {
+        for (i, byte) in buf.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(self.gear[*byte as usize]);
+            self.len += 1;
+            if self.len >= self.max_size
+                || (self.len >= self.min_size && self.hash >> self.shift == 0)
+            {
+                self.hash = 0;
+                self.len = 0;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
+
+// Fills the gear table from splitmix64, which is all we need of a PRNG here.
+fn gear_table(seed: u64) -> Box<[u64; 256]> {
+    let mut table = Box::new([0u64; 256]);
+    let mut state = seed;
+    for entry in table.iter_mut() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *entry = z ^ (z >> 31);
+    }
+    table
+}
+
+/// Names the plaintext of a chunk as it is written, keyed by the encryption key
+/// if any, so that the same chunk encrypted under the same key can be found
+/// again without revealing anything to those without it. Padded and unpadded
+/// chunks are named apart.
+pub struct ChunkHasher(sha2::Sha256);
+
+impl ChunkHasher {
+    pub fn new(encryption: Option<&SymmetricKey>, padded: bool) -> ChunkHasher {
+        let mut hasher = sha2::Sha256::default();
+        hasher.update(b"recursive_remote chunk\0");
+        if let Some(key) = encryption {
+            hasher.update(key.serialize_to_string().as_bytes());
+        }
+        hasher.update(b"\0");
+        if padded {
+            hasher.update(b"padded\0");
+        }
+        ChunkHasher(hasher)
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        self.0.update(buf);
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        self.0.finalize().into()
+    }
+}
+
+/// The seed for the chunk boundaries of blobs encrypted with `encryption`.
+pub fn chunker_seed(encryption: Option<&SymmetricKey>) -> u64 {
+    let mut hasher = ChunkHasher::new(encryption, /*padded=*/ false);
+    hasher.update(b"gear");
+    let id = hasher.finalize();
+    u64::from_le_bytes(id[..8].try_into().expect("8 bytes"))
+}
+
+/// Remembers where each encrypted chunk we have written or read is stored, by
+/// the keyed hash of its plaintext. Encryption is randomized, so encrypting an
+/// unchanged chunk again would store a new blob; with the cache we reuse the
+/// old one instead. Lives beside the tracking repo and is only ever a hint: a
+/// missing or stale entry costs a rewrite of the chunk, nothing more.
+pub struct ChunkCache {
+    dir: PathBuf,
+}
+
+impl ChunkCache {
+    pub fn new(dir: PathBuf) -> ChunkCache {
+        ChunkCache { dir }
+    }
+
+    /// The cache for chunks stored in `repo`.
+    pub fn for_repo(repo: &gix::Repository) -> ChunkCache {
+        ChunkCache::new(repo.path().join("recursive_remote_chunks"))
+    }
+
+    fn path(&self, id: &[u8; 32]) -> PathBuf {
+        self.dir
+            .join(hex::encode(&id[..1]))
+            .join(hex::encode(&id[1..]))
+    }
+
+    pub fn get(&self, id: &[u8; 32]) -> Option<String> {
+        std::fs::read_to_string(self.path(id)).ok()
+    }
+
+    /// Notes that the chunk `id` is stored under `key`. Failing to is only
+    /// logged, the cache being a hint.
+    pub fn insert(&self, id: &[u8; 32], key: &str) {
+        if self.get(id).as_deref() == Some(key) {
+            return;
+        }
+        if let Err(err) = self.try_insert(id, key) {
+            log::warn!("Unable to cache chunk location: {:#}", err);
+        }
+    }
+
+    fn try_insert(&self, id: &[u8; 32], key: &str) -> Result<()> {
+        let path = self.path(id);
+        let parent = path.parent().context("chunk cache path")?;
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("create chunk cache dir {:?}", parent))?;
+        let mut file = tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempfile_in(parent)
+            .context("create chunk cache temp file")?;
+        file.write_all(key.as_bytes())
+            .context("write chunk cache entry")?;
+        file.persist(&path)
+            .with_context(|| format!("store chunk cache entry {:?}", &path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boundaries(chunker: &mut Chunker, buf: &[u8]) -> Vec<usize> {
+        let mut cuts = Vec::new();
+        let mut offset = 0;
+        while let Some(n) = chunker.find_boundary(&buf[offset..]) {
+            offset += n;
+            cuts.push(offset);
+        }
+        cuts
+    }
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        (1..)
+            .flat_map(|seed| gear_table(seed).to_vec())
+            .flat_map(u64::to_le_bytes)
+            .take(len)
+            .collect()
+    }
+
+    #[test]
+    fn boundaries_follow_content() {
+        let data = pseudo_random(64 * 1024);
+        let cuts = boundaries(&mut Chunker::new(4096, 0), &data);
+        assert!(cuts.len() > 4);
+        let mut last = 0;
+        for cut in cuts.iter() {
+            assert!(cut - last <= 4096);
+            last = *cut;
+        }
+
+        // Inserting bytes near the start leaves the later boundaries in place.
+        let mut grown = b"inserted".to_vec();
+        grown.extend_from_slice(&data);
+        let grown_cuts = boundaries(&mut Chunker::new(4096, 0), &grown);
+        let shifted: Vec<_> = grown_cuts.iter().map(|cut| cut - 8).collect();
+        let kept = cuts.iter().filter(|cut| shifted.contains(cut)).count();
+        assert!(kept * 4 >= cuts.len() * 3);
+    }
+
+    #[test]
+    fn boundaries_depend_on_seed() {
+        let data = pseudo_random(64 * 1024);
+        assert_ne!(
+            boundaries(&mut Chunker::new(4096, 0), &data),
+            boundaries(&mut Chunker::new(4096, 1), &data)
+        );
+    }
+
+    #[test]
+    fn fixed_boundaries_ignore_content() {
+        let data = pseudo_random(10_000);
+        assert_eq!(
+            boundaries(&mut Chunker::fixed(4096), &data),
+            vec![4096, 8192]
+        );
+    }
+
+    #[test]
+    fn cache_roundtrip() {
+        let tmp = tempfile::Builder::new()
+            .prefix("chunker-tests")
+            .tempdir()
+            .expect("tempdir");
+        let cache = ChunkCache::new(tmp.path().join("chunks"));
+        let id = [3; 32];
+        assert_eq!(cache.get(&id), None);
+        cache.insert(&id, "key");
+        assert_eq!(cache.get(&id), Some("key".to_string()));
+    }
+}
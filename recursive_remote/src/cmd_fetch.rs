@@ -1,13 +1,20 @@
-use std::collections::HashSet;
-use std::convert::TryInto;
-use std::io::BufRead;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufRead, Seek, Write};
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+
+use eseb::SymmetricKey;
 
 use anyhow::{Context, Result};
 use gix::Repository;
 
+use crate::blob_store::Annex;
 use crate::config::*;
 use crate::encoding::*;
+use crate::options::Options;
+use crate::progress::Progress;
 use crate::serialization::*;
 use crate::update::*;
 use crate::util::*;
@@ -27,19 +34,23 @@ use crate::util::*;
 // is to fetch multiple thin packs, fix them (which requires deltas from each
 // other and the base repo cloned), then repack them all into one big pack,
 // since that's how the special remote protocol prefers to handle locking.
-pub fn fetch(config: &Config, revs: &[String]) -> Result<()> {
+pub fn fetch(config: &Config, options: &Options, revs: &[String]) -> Result<()> {
     let (state_identifier, state, basis_ref, _root_id, commit_id) =
         update_branches(config).context("fetch")?;
 
     let tracking_repo = Rc::new(config.tracking_repo()?);
 
-    let ordered_packs = materialize_ordered_pack_list(
-        config,
+    let mut progress = Progress::new("Reading state history", None, options.progress);
+    let ordered_packs = ordered_pack_list(
+        &config.nacl_keys,
         &tracking_repo,
+        &config.namespace,
         state_identifier.as_ref(),
         &state,
         basis_ref.as_ref(),
+        &mut progress,
     )?;
+    progress.done();
 
     // Clean up the temporary refs for this remote from any previous ops, since
     // we do have a per-remote lock.
@@ -48,21 +59,46 @@ pub fn fetch(config: &Config, revs: &[String]) -> Result<()> {
         &format!("refs/recursive_remote/{}/tmp/", &config.remote_name),
     )?;
 
-    // Fix the thin packs, and insert their objects into the all objects repo.
-    for pack_ref in ordered_packs.iter().rev() {
-        fetch_pack(config, &tracking_repo, pack_ref.clone())?;
+    // This also creates the all objects repo with the user repo's object format
+    // if need be, which must happen before any packs are indexed into it.
+    let all_objects_ever_repo = config.all_objects_ever_repo()?;
+    let namespace = state.namespace(&config.namespace, &config.nacl_keys, &tracking_repo)?;
+    if let Some(namespace) = namespace.as_ref() {
+        namespace
+            .check_object_hash(all_objects_ever_repo.object_hash())
+            .with_context(|| format!("fetch namespace {}", &config.namespace))?;
     }
 
+    // Fix the thin packs, and insert their objects into the all objects repo.
+    let total = Some(ordered_packs.len() as u64);
+    let mut receiving = Progress::new("Receiving packs", total, options.progress).with_throughput();
+    let oldest_first: Vec<_> = ordered_packs.iter().rev().cloned().collect();
+    fetch_packs(config, &tracking_repo, &oldest_first, &mut receiving)?;
+
     // We want to keep all refs reachable so no objects are ever gc'd (.keep,
     // gc.pruneExpire=never, gc.cruftPacks, etc all do similar things, but each
     // has downsides), mostly due to being intended to eventually get rid of
     // objects -- e.g., git tries to keep only a single cruft pack.
     //
     // This is per-remote since our exclusive locking is.
-    let all_objects_ever_repo = config.all_objects_ever_repo()?;
-
     let fetch_revs = parse_fetch_revs(revs);
 
+    // Git would notice missing objects itself when fetching into the user
+    // repo, but couldn't say why they are missing.
+    if options.check_connectivity && !fetch_revs.is_empty() {
+        let tips = fetch_revs
+            .iter()
+            .map(|rev| gix_hash::ObjectId::from_hex(rev.as_bytes()).context("oid"))
+            .collect::<Result<Vec<_>>>()?;
+        check_connectivity(
+            &all_objects_ever_repo,
+            &config.user_repo()?,
+            &tips,
+            namespace.as_ref(),
+        )
+        .context("check connectivity")?;
+    }
+
     if !fetch_revs.is_empty() {
         let mut cmd = crate::util::git_command();
         cmd.arg("fetch")
@@ -110,12 +146,91 @@ pub fn fetch(config: &Config, revs: &[String]) -> Result<()> {
     Ok(())
 }
 
+/// The packs pushed to `namespace` since `basis_ref`, newest first. This reads
+/// the namespace's pack manifest where it has one, and only walks the state
+/// history for packs pushed before the manifest was started.
+pub fn ordered_pack_list(
+    keys: &EncryptionKeys,
+    tracking_repo: &Rc<gix::Repository>,
+    namespace: &str,
+    state_identifier: Option<&StateRef>,
+    state: &State,
+    basis_ref: Option<&StateRef>,
+    progress: &mut Progress,
+) -> Result<Vec<PackRef>> {
+    let Some(manifest) = state
+        .namespace(namespace, keys, tracking_repo)?
+        .and_then(|namespace| namespace.manifest)
+    else {
+        return materialize_ordered_pack_list(
+            keys,
+            tracking_repo,
+            namespace,
+            state_identifier,
+            state,
+            basis_ref,
+            progress,
+        );
+    };
+
+    let basis_manifest = match basis_ref {
+        Some(basis_ref) => decode_state(tracking_repo, basis_ref, keys)?
+            .namespace(namespace, keys, tracking_repo)?
+            .and_then(|namespace| namespace.manifest),
+        None => None,
+    };
+
+    // The basis is usually an earlier version of the same manifest, in which
+    // case only the packs appended since are new. After a forced push it may
+    // not be, and then every pack is fetched again, which is harmless. Reading
+    // from the basis's newest pack on walks the segments only once.
+    if let Some(basis_manifest) = basis_manifest
+        && basis_manifest.history == manifest.history
+        && basis_manifest.count <= manifest.count
+    {
+        let mut packs = crate::manifest::packs_since(
+            tracking_repo,
+            &manifest,
+            basis_manifest.count.saturating_sub(1),
+            keys,
+        )
+        .context("read pack manifest")?;
+        let continues = basis_manifest.count == 0
+            || packs.pop()
+                == crate::manifest::newest_pack(tracking_repo, &basis_manifest, keys)
+                    .context("read basis pack manifest")?;
+        if continues {
+            progress.set(packs.len() as u64);
+            return Ok(packs);
+        }
+    }
+
+    let mut packs = crate::manifest::packs_since(tracking_repo, &manifest, 0, keys)
+        .context("read pack manifest")?;
+    progress.set(packs.len() as u64);
+    if let Some(history) = manifest.history.as_ref() {
+        let history_state = decode_state(tracking_repo, history, keys)?;
+        packs.extend(materialize_ordered_pack_list(
+            keys,
+            tracking_repo,
+            namespace,
+            Some(history),
+            &history_state,
+            basis_ref,
+            progress,
+        )?);
+    }
+    Ok(packs)
+}
+
 pub fn materialize_ordered_pack_list(
-    config: &Config,
+    keys: &EncryptionKeys,
     tracking_repo: &Rc<gix::Repository>,
+    namespace: &str,
     state_identifier: Option<&StateRef>,
     state: &State,
     basis_ref: Option<&StateRef>,
+    progress: &mut Progress,
 ) -> Result<Vec<PackRef>> {
     let mut stack = vec![(state_identifier.cloned(), Some(state))];
 
@@ -135,7 +250,7 @@ pub fn materialize_ordered_pack_list(
                     _sh = Some(crate::encoding::decode_state(
                         tracking_repo,
                         &state_identifier,
-                        &config.nacl_keys,
+                        keys,
                     )?);
                     _sh.as_ref().expect("")
                 }
@@ -145,19 +260,146 @@ pub fn materialize_ordered_pack_list(
             },
         };
 
-        let namespace = state
-            .namespace(&config.namespace, &config.nacl_keys, tracking_repo)?
-            .context("traverse")?;
+        // The namespace didn't exist yet in this state, nor in its history.
+        let Some(recorded) = state.namespace(namespace, keys, tracking_repo)? else {
+            continue;
+        };
 
-        ordered_packs.extend(namespace.pack);
+        ordered_packs.extend(recorded.pack);
+        progress.inc();
 
-        for parent in state.parents.iter() {
+        // A namespace that an epoch didn't consolidate still needs the packs
+        // from before it.
+        for parent in state.parents.iter().chain(state.supersedes.as_ref()) {
             stack.push((Some(parent.clone()), None));
         }
     }
     Ok(ordered_packs)
 }
 
+// Fails naming the missing objects, and the shallow basis refs that the
+// upstream relies on to supply them, unless everything reachable from `tips`
+// is in either the all objects repo or the user repo.
+fn check_connectivity(
+    all_objects_ever_repo: &gix::Repository,
+    user_repo: &gix::Repository,
+    tips: &[gix_hash::ObjectId],
+    namespace: Option<&Namespace>,
+) -> Result<()> {
+    let missing =
+        find_missing_objects(all_objects_ever_repo, |oid| user_repo.has_object(oid), tips)?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    const MAX_LISTED: usize = 10;
+    let mut message = format!(
+        "{} object(s) reachable from the fetched refs are missing: ",
+        missing.len()
+    );
+    let listed: Vec<_> = missing
+        .iter()
+        .take(MAX_LISTED)
+        .map(|oid| oid.to_string())
+        .collect();
+    message.push_str(&listed.join(", "));
+    if missing.len() > MAX_LISTED {
+        message.push_str(&format!(" and {} more", missing.len() - MAX_LISTED));
+    }
+    message.push('.');
+
+    let mut shallow_basis: Vec<_> = namespace
+        .map(|namespace| namespace.shallow_basis.iter().collect())
+        .unwrap_or_default();
+    shallow_basis.sort_by(|a, b| a.0.cmp(b.0));
+    if shallow_basis.is_empty() {
+        message.push_str(" The upstream records no shallow basis that would have supplied them.");
+    } else {
+        message.push_str(
+            " Pushes to the upstream left out objects reachable from its shallow basis, which \
+             must be fetched from elsewhere first:",
+        );
+        for (name, basis) in shallow_basis {
+            match basis.oid_at_time() {
+                Some(oid) if user_repo.has_object(oid) => {
+                    message.push_str(&format!("\n\t{} ({})", name, oid));
+                }
+                Some(oid) => {
+                    message.push_str(&format!("\n\t{} ({}, not in this repository)", name, oid));
+                }
+                None => message.push_str(&format!("\n\t{}", name)),
+            }
+        }
+    }
+    anyhow::bail!(message)
+}
+
+/// Walks everything reachable from `tips`, returning the objects neither in
+/// `repo` nor `supplied` from elsewhere. Whatever supplies objects is assumed to
+/// be connected already, so the walk stops at them. For a fetch, that is the
+/// user repo, and the walk in practice covers only newly fetched history.
+pub fn find_missing_objects(
+    repo: &gix::Repository,
+    supplied: impl Fn(&gix_hash::ObjectId) -> bool,
+    tips: &[gix_hash::ObjectId],
+) -> Result<Vec<gix_hash::ObjectId>> {
+    let has_object = |oid: &gix_hash::ObjectId| supplied(oid) || repo.has_object(oid);
+
+    let mut missing = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = tips.to_vec();
+    while let Some(oid) = stack.pop() {
+        if !seen.insert(oid) || supplied(&oid) {
+            continue;
+        }
+        let Some(object) = repo
+            .try_find_object(oid)
+            .with_context(|| format!("read object {}", oid))?
+        else {
+            missing.push(oid);
+            continue;
+        };
+
+        match object.kind {
+            gix_object::Kind::Commit => {
+                let commit = object.into_commit();
+                let commit = commit
+                    .decode()
+                    .with_context(|| format!("decode commit {}", oid))?;
+                stack.push(commit.tree());
+                stack.extend(commit.parents());
+            }
+            gix_object::Kind::Tree => {
+                let tree = object.into_tree();
+                let tree = tree
+                    .decode()
+                    .with_context(|| format!("decode tree {}", oid))?;
+                for entry in tree.entries.iter() {
+                    let entry_oid = entry.oid.to_owned();
+                    if entry.mode.is_tree() {
+                        stack.push(entry_oid);
+                    } else if entry.mode.is_commit() {
+                        // Submodule commits live in another repository.
+                    } else if seen.insert(entry_oid) && !has_object(&entry_oid) {
+                        // Blobs have nothing to walk, so don't read them.
+                        missing.push(entry_oid);
+                    }
+                }
+            }
+            gix_object::Kind::Tag => {
+                let target = object
+                    .into_tag()
+                    .target_id()
+                    .with_context(|| format!("decode tag {}", oid))?;
+                stack.push(target.detach());
+            }
+            gix_object::Kind::Blob => {}
+        }
+    }
+    missing.sort();
+    Ok(missing)
+}
+
 fn compact_ref_reachability(repo: &gix::Repository, remote_name: &str) -> Result<()> {
     let mut ref_commits = Vec::default();
     let mut ref_names: Vec<String> = Vec::default();
@@ -225,31 +467,231 @@ pub fn delete_refs_glob(repo: &gix::Repository, glob: &str) -> Result<()> {
     Ok(())
 }
 
+// The most packs decoded and indexed at once when fetching.
+const MAX_FETCH_JOBS: usize = 8;
+
 pub fn fetch_pack(
     config: &Config,
     tracking_repo: &Rc<Repository>,
     pack_ref: PackRef,
-) -> Result<Option<[u8; 20]>> {
-    let mut cmd = crate::util::git_command()
-        .current_dir(&config.all_objects_ever_repo_path)
-        .arg("index-pack")
-        .arg("--fix-thin")
-        .arg("--stdin")
-        .arg("--keep")
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .context("Failed to spawn git pack-objects.")?;
+    progress: &mut Progress,
+) -> Result<Option<Vec<u8>>> {
+    let mut cmd = crate::util::git_command();
+    cmd.current_dir(&config.all_objects_ever_repo_path);
+    index_pack(config, tracking_repo, cmd, pack_ref, progress)
+}
+
+/// Fetches `packs`, given oldest first, into the all objects repo. Several are
+/// decoded and indexed at once. Those thin against a pack still being indexed
+/// are indexed again as soon as every pack before them is in place.
+///
+/// `receiving` counts the bytes decoded and each pack once it is indexed, even
+/// if that has to wait.
+pub fn fetch_packs(
+    config: &Config,
+    tracking_repo: &Rc<Repository>,
+    packs: &[PackRef],
+    receiving: &mut Progress,
+) -> Result<()> {
+    let jobs = std::thread::available_parallelism()
+        .map_or(1, usize::from)
+        .min(MAX_FETCH_JOBS)
+        .min(packs.len());
+    if jobs <= 1 {
+        // Decoding streams straight into index-pack, so both finish together.
+        for pack_ref in packs.iter() {
+            fetch_pack(config, tracking_repo, pack_ref.clone(), receiving)?;
+            receiving.inc();
+        }
+        receiving.done();
+        return Ok(());
+    }
+
+    let shared_repo = (**tracking_repo).clone().into_sync();
+    let annex = config.annex.as_deref();
+    let key = config.nacl_keys.namespace_key();
+    let repo_path = config.all_objects_ever_repo_path.as_path();
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            let tx = tx.clone();
+            let (shared_repo, next) = (&shared_repo, &next);
+            scope.spawn(move || {
+                let repo = shared_repo.to_thread_local();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(pack_ref) = packs.get(i) else {
+                        break;
+                    };
+                    let result = decode_and_index(&repo, annex, key, repo_path, pack_ref, &tx);
+                    let failed = result.is_err();
+                    if tx.send(FetchEvent::Done(i, result)).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        // Stops the others once they finish the pack in hand.
+        let fail = |i: usize, err: anyhow::Error| {
+            next.store(packs.len(), Ordering::Relaxed);
+            Err(err.context(format!("fetch pack {}", packs[i].blob_ref)))
+        };
+
+        let mut indexed = vec![false; packs.len()];
+        let mut deferred = BTreeMap::new();
+        // Every pack before this one is indexed.
+        let mut in_place = 0;
+        for event in rx {
+            match event {
+                FetchEvent::Bytes(n) => receiving.add_bytes(n),
+                FetchEvent::Done(i, Ok(None)) => {
+                    receiving.inc();
+                    indexed[i] = true;
+                }
+                FetchEvent::Done(i, Ok(Some(file))) => {
+                    deferred.insert(i, file);
+                }
+                FetchEvent::Done(i, Err(err)) => return fail(i, err),
+            }
+
+            // A deferred pack's bases are in place once every pack before it
+            // is, so it is indexed then, while later packs are still decoded.
+            loop {
+                while in_place < packs.len() && indexed[in_place] {
+                    in_place += 1;
+                }
+                let Some(file) = deferred.remove(&in_place) else {
+                    break;
+                };
+                if let Err(err) = index_pack_file(repo_path, &file) {
+                    return fail(in_place, err.context("index deferred pack"));
+                }
+                receiving.inc();
+                indexed[in_place] = true;
+            }
+        }
+        Ok(())
+    })?;
+    receiving.done();
+    Ok(())
+}
+
+enum FetchEvent {
+    Bytes(usize),
+
+    // The index of the pack in those being fetched, and the decoded pack if
+    // indexing it must wait for the packs before it.
+    Done(usize, Result<Option<std::fs::File>>),
+}
+
+// Passes the bytes written through to the fetch's progress.
+struct ReportingWriter<'a, W> {
+    inner: W,
+    tx: &'a Sender<FetchEvent>,
+}
+
+impl<W: Write> Write for ReportingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let _ = self.tx.send(FetchEvent::Bytes(n));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Decodes the pack to a temp file and tries to index it, returning the file if
+// that fails so that it can be indexed again later.
+fn decode_and_index(
+    tracking_repo: &Repository,
+    annex: Option<&Annex>,
+    key: Option<&SymmetricKey>,
+    repo_path: &Path,
+    pack_ref: &PackRef,
+    tx: &Sender<FetchEvent>,
+) -> Result<Option<std::fs::File>> {
+    let mut file = tempfile::tempfile().context("Unable to create temp file.")?;
+    let (_blob_ref, size) = decode_with_progress(
+        tracking_repo,
+        annex,
+        &pack_ref.blob_ref,
+        ReportingWriter {
+            inner: &mut file,
+            tx,
+        },
+        key,
+        &mut Progress::disabled(),
+    )
+    .context("decode pack")?;
+
+    // As in index_pack, an empty pack has nothing to index.
+    if size == 0 {
+        return Ok(None);
+    }
+
+    match index_pack_file(repo_path, &file) {
+        Ok(_name) => Ok(None),
+        Err(err) if err.downcast_ref::<MissingBase>().is_some() => {
+            log::debug!("Indexing pack {} later: {:#}", &pack_ref.blob_ref, err);
+            Ok(Some(file))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// A thin pack whose bases are not yet in the repo it is indexed into, as when
+/// they are in a pack still being fetched.
+#[derive(thiserror::Error, Debug)]
+#[error("pack is thin against objects not yet indexed")]
+pub struct MissingBase;
+
+// Indexes the decoded pack in `file` into the repo at `repo_path`, returning the
+// name of the pack written.
+fn index_pack_file(repo_path: &Path, mut file: &std::fs::File) -> Result<Vec<u8>> {
+    file.seek(std::io::SeekFrom::Start(0))
+        .context("rewind decoded pack")?;
+    let mut cmd = crate::util::git_command();
+    cmd.current_dir(repo_path)
+        .stdin(std::process::Stdio::from(file.try_clone()?));
+    let mut child = spawn_index_pack(&mut cmd)?;
+    let stdout = child.stdout.take().context("No stdout.")?;
+    if let Err(err) = wait_subprocess(&mut child) {
+        // git is run in the C locale, so its message can be relied on.
+        if format!("{:#}", err).contains("unresolved delta") {
+            return Err(err.context(MissingBase));
+        }
+        return Err(err.context("git index-pack"));
+    }
+    read_kept_pack_name(stdout)
+}
+
+// Decodes the pack into `git index-pack`, run by `cmd` in the repo it is to be
+// indexed into, and returns the name of the pack written, if any.
+pub fn index_pack(
+    config: &Config,
+    tracking_repo: &Rc<Repository>,
+    mut cmd: std::process::Command,
+    pack_ref: PackRef,
+    progress: &mut Progress,
+) -> Result<Option<Vec<u8>>> {
+    let mut cmd = spawn_index_pack(cmd.stdin(std::process::Stdio::piped()))?;
 
     let stdin = cmd.stdin.take().context("No stdin.")?;
     let stdout = cmd.stdout.take().context("No stdout.")?;
 
-    let (_blob_ref, size) = decode(
+    let (_blob_ref, size) = decode_with_progress(
         tracking_repo,
+        config.annex.as_deref(),
         &pack_ref.blob_ref,
         stdin,
         config.nacl_keys.namespace_key(),
+        progress,
     )
     .context("decode pack")?;
 
@@ -270,14 +712,29 @@ pub fn fetch_pack(
         }
     }
 
+    read_kept_pack_name(stdout).map(Some)
+}
+
+// Runs `git index-pack` on the pack `cmd` gives it on stdin.
+fn spawn_index_pack(cmd: &mut std::process::Command) -> Result<std::process::Child> {
+    cmd.arg("index-pack")
+        .arg("--fix-thin")
+        .arg("--stdin")
+        .arg("--keep")
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("Failed to spawn git index-pack.")
+}
+
+fn read_kept_pack_name(stdout: std::process::ChildStdout) -> Result<Vec<u8>> {
     if let Some(line) = std::io::BufReader::new(stdout).lines().next() {
         let line = line?;
         let tok: Vec<_> = line.split_ascii_whitespace().collect();
         if tok.len() != 2 {
             anyhow::bail!("expected a line like 'keep <packname>'");
         }
-        let name = hex::decode(tok[1]).context("decode hex written pack name")?;
-        return Ok(Some(name.try_into().expect("")));
+        return hex::decode(tok[1]).context("decode hex written pack name");
     }
 
     anyhow::bail!("no pack was written");
@@ -367,6 +824,61 @@ mod tests {
         );
     }
 
+    #[test]
+    fn check_connectivity_names_missing_objects_and_shallow_basis() {
+        let (_tmp, repo, commit) = setup_repo();
+        let user_tmp = tempfile::Builder::new()
+            .prefix("cmd-fetch-tests")
+            .tempdir()
+            .expect("tempdir");
+        let user_repo = gix::init_bare(user_tmp.path().join("user")).expect("init user repo");
+
+        check_connectivity(&repo, &user_repo, &[commit], None).expect("connected");
+
+        let dangling =
+            gix_hash::ObjectId::from_hex(b"1111111111111111111111111111111111111111").expect("oid");
+        let tree = repo
+            .empty_tree()
+            .edit()
+            .expect("edit")
+            .write()
+            .expect("write tree");
+        let sig = rr_signature();
+        let mut committer_time = gix_date::parse::TimeBuf::default();
+        let mut author_time = gix_date::parse::TimeBuf::default();
+        let child = repo
+            .commit_as(
+                sig.to_ref(&mut committer_time),
+                sig.to_ref(&mut author_time),
+                "refs/heads/child",
+                "Recursive.",
+                tree,
+                [dangling, commit],
+            )
+            .expect("commit")
+            .detach();
+
+        assert_eq!(
+            find_missing_objects(&repo, |oid| user_repo.has_object(oid), &[child]).expect("walk"),
+            vec![dangling]
+        );
+
+        let err = check_connectivity(&repo, &user_repo, &[child], None).expect_err("dangling");
+        assert!(format!("{err}").contains(&dangling.to_string()));
+        assert!(format!("{err}").contains("records no shallow basis"));
+
+        let mut namespace = Namespace::new();
+        namespace
+            .shallow_basis
+            .insert("refs/tags/base".to_string(), Ref::Direct(dangling));
+        let err = check_connectivity(&repo, &user_repo, &[child], Some(&namespace))
+            .expect_err("dangling");
+        assert!(format!("{err}").contains(&format!(
+            "refs/tags/base ({}, not in this repository)",
+            dangling
+        )));
+    }
+
     #[test]
     fn compact_ref_reachability_compacts_when_many_refs() {
         let (_tmp, repo, commit) = setup_repo();
@@ -385,4 +897,65 @@ mod tests {
 
         assert_eq!(count_prefixed_refs(&repo, "refs/heads/origin"), 1);
     }
+
+    fn git(dir: &Path, args: &[&str], stdin: &[u8]) -> Vec<u8> {
+        let mut child = crate::util::git_command()
+            .current_dir(dir)
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .expect("spawn git");
+        child
+            .stdin
+            .take()
+            .expect("piped stdin")
+            .write_all(stdin)
+            .expect("stdin");
+        let output = child.wait_with_output().expect("git");
+        assert!(output.status.success(), "git {:?}", args);
+        output.stdout
+    }
+
+    #[test]
+    fn thin_pack_indexes_once_its_base_is_present() {
+        let tmp = tempfile::Builder::new()
+            .prefix("cmd-fetch-tests")
+            .tempdir()
+            .expect("tempdir");
+        let src = tmp.path().join("src");
+        let dest = tmp.path().join("dest");
+        gix::init(&src).expect("init src");
+        gix::init_bare(&dest).expect("init dest");
+
+        let body: String = (0..200).map(|i| format!("line {i}\n")).collect();
+        std::fs::write(src.join("file"), &body).expect("write base");
+        git(&src, &["add", "file"], b"");
+        git(&src, &["commit", "-qm", "base"], b"");
+        std::fs::write(src.join("file"), body + "more\n").expect("write child");
+        git(&src, &["commit", "-qam", "child"], b"");
+
+        let pack = |revs: &[u8], thin: bool| {
+            let mut args = vec!["pack-objects", "--stdout", "--revs"];
+            if thin {
+                args.push("--thin");
+            }
+            let mut file = tempfile::tempfile().expect("pack file");
+            file.write_all(&git(&src, &args, revs)).expect("write pack");
+            file
+        };
+        let base = pack(b"HEAD~1\n", false);
+        let thin = pack(b"HEAD\n^HEAD~1\n", true);
+
+        let err = index_pack_file(&dest, &thin).expect_err("base missing");
+        assert!(err.downcast_ref::<MissingBase>().is_some(), "{err:#}");
+        index_pack_file(&dest, &base).expect("index base");
+        index_pack_file(&dest, &thin).expect("index thin");
+
+        // Other failures aren't taken for a missing base.
+        let mut corrupt = tempfile::tempfile().expect("pack file");
+        corrupt.write_all(b"PACK not really").expect("write pack");
+        let err = index_pack_file(&dest, &corrupt).expect_err("corrupt");
+        assert!(err.downcast_ref::<MissingBase>().is_none(), "{err:#}");
+    }
 }
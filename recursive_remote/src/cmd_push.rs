@@ -4,15 +4,17 @@ use std::rc::Rc;
 
 use anyhow::{Context, Result};
 use gix::diff::object::FindHeader;
+use rand::Rng;
 
 use crate::config::*;
+use crate::options::Options;
 use crate::persistence::*;
 use crate::serialization::*;
 use crate::update::*;
 use crate::util::*;
 
 enum PushResult {
-    Ok(HashMap<String, bool>),
+    Ok(HashMap<String, RefStatus>),
     Retry,
 }
 
@@ -34,6 +36,44 @@ fn classify_failed_push_for_retry(
     }
 }
 
+// The branch as an update attempt found it.
+struct BranchState {
+    identifier: Option<StateRef>,
+    state: State,
+    root_id: Option<gix_hash::ObjectId>,
+}
+
+// Makes `update` from the branch as it stands and publishes it, retrying with
+// the branch as it then stands whenever another client moved it first. `what`
+// names the update in errors.
+fn retry_state_update<F>(
+    config: &Config,
+    what: &str,
+    mut update: F,
+) -> Result<HashMap<String, RefStatus>>
+where
+    F: FnMut(BranchState) -> Result<PushResult>,
+{
+    for _ in 0..25 {
+        let (identifier, state, _basis_state, root_id, _commit_id) =
+            update_branches(config).with_context(|| what.to_string())?;
+        let branch = BranchState {
+            identifier,
+            state,
+            root_id,
+        };
+        match update(branch)? {
+            PushResult::Ok(push_status) => return Ok(push_status),
+            PushResult::Retry => {}
+        }
+    }
+
+    anyhow::bail!(
+        "After many tries, unable to {} due to conflicts in the backing repo.",
+        what
+    )
+}
+
 fn parse_push_specs(
     specs: &[String],
     pushes: &mut Vec<(String, String)>,
@@ -61,7 +101,7 @@ fn start_pack_process(
     namespace: &Namespace,
     pushes: &HashMap<String, Ref>,
     force_pushes: &HashMap<String, Option<Ref>>,
-    shallow_basis: &[Ref],
+    shallow_basis: &[(String, Ref)],
 ) -> Result<std::process::Child> {
     let mut cmd = crate::util::git_command()
         .arg("pack-objects")
@@ -91,7 +131,7 @@ fn start_pack_process(
     for oid in namespace
         .refs
         .values()
-        .chain(shallow_basis.iter())
+        .chain(shallow_basis.iter().map(|(_, basis)| basis))
         .filter_map(|r| r.oid_at_time())
     {
         // We must skip excluding refs that are absent in the repo. This is
@@ -155,14 +195,13 @@ fn convert_specs_to_refs(
 // commit lacks state.bincode, which we treat as lacking a logical parent (but it
 // still needs a physical one for git).
 fn do_commit(
-    namespace_name: &str,
+    config: &Config,
+    changes: NamespaceChanges<'_>,
     tracking_repo: &Rc<gix::Repository>,
-    local_ref: &str,
     future: &State,
     root_id: Option<gix_hash::ObjectId>,
-    encrypt: &EncryptionKeys,
-    max_object_size: usize,
 ) -> Result<()> {
+    let local_ref = &config.pushing_ref;
     let root = match root_id {
         None => None,
         Some(oid) => {
@@ -185,16 +224,8 @@ fn do_commit(
         None => tracking_repo.empty_tree().edit(),
     }?;
 
-    let tree = create_commit_tree(
-        tracking_repo,
-        namespace_name,
-        root,
-        tracking_repo,
-        future,
-        encrypt,
-        max_object_size,
-    )
-    .context("create commit tree")?;
+    let tree = create_commit_tree(config, tracking_repo, changes, root, tracking_repo, future)
+        .context("create commit tree")?;
     anyhow_ref_commit(tracking_repo, local_ref, "Recursive.", tree)
         .with_context(|| format!("failed to commit tree {} to ref {}", &tree, &local_ref))
         .map(|_| ())
@@ -202,20 +233,59 @@ fn do_commit(
 
 fn attempt_push(
     config: &Config,
+    options: &Options,
+    branch: BranchState,
     pushes: &[(String, String)],
     force_pushes: &[(String, String)],
 ) -> Result<PushResult> {
-    let (state_identifier, state, _basis_state, root_id, _commit_id) =
-        update_branches(config).context("push")?;
+    let BranchState {
+        identifier: state_identifier,
+        state,
+        root_id,
+    } = branch;
 
-    let namespace = {
+    let mut namespace = {
         let tracking_repo = Rc::new(config.tracking_repo()?);
         state
             .namespace(&config.namespace, &config.nacl_keys, &tracking_repo)?
-            .unwrap_or_else(Namespace::new)
+            .unwrap_or_else(|| Namespace {
+                format: state.format.map(|_| crate::format::FormatHeader::current()),
+                manifest: state.format.map(|_| PackManifest::default()),
+                ..Namespace::new()
+            })
     };
 
+    // Packs from before the namespace kept a manifest are found by walking the
+    // history back from the state this push builds on. Unversioned namespaces
+    // go without, since older clients couldn't be told to read it.
+    if namespace.manifest.is_none() && namespace.format.is_some() {
+        namespace.manifest = Some(PackManifest {
+            history: state_identifier.clone(),
+            ..PackManifest::default()
+        });
+        crate::format::note_chunk_features(
+            &mut namespace.format,
+            crate::format::chunk_features(state.format.as_ref()),
+        );
+    }
+
     let user_repo = Rc::new(config.user_repo()?);
+    let all_objects_ever_repo = Rc::new(config.all_objects_ever_repo()?);
+    namespace
+        .check_object_hash(user_repo.object_hash())
+        .with_context(|| format!("push to namespace {}", &config.namespace))?;
+
+    // If this push creates the namespace's HEAD, point it at wherever the
+    // user's checked out branch is being pushed.
+    let user_head = user_repo
+        .head_name()
+        .context("read user repo HEAD")?
+        .map(|name| name.as_bstr().to_string());
+    let preferred_head = pushes
+        .iter()
+        .chain(force_pushes)
+        .find(|(source, _)| Some(source) == user_head.as_ref())
+        .map(|(_, dest)| dest.clone());
 
     let pushes = convert_specs_to_refs(&user_repo, pushes).context("pushes")?;
     let force_pushes: HashMap<_, _> =
@@ -249,8 +319,19 @@ fn attempt_push(
                 "Recursive",
             )
             .with_context(|| format!("create tmp ref in user_repo -> {}", &rev))?;
+
+        // Git refuses to point a branch at anything but a commit, so annotated
+        // tags are kept reachable with a tag instead.
+        let kind = user_repo
+            .find_header(rev)
+            .with_context(|| format!("find object kind for {}", &rev))?
+            .kind();
+        let keep_prefix = match kind {
+            gix_object::Kind::Commit => "refs/heads",
+            _ => "refs/tags",
+        };
         cmd.arg(format!(
-            "{tmp_ref}:refs/heads/{}/rev{rev}",
+            "{tmp_ref}:{keep_prefix}/{}/rev{rev}",
             &config.remote_name
         ));
     }
@@ -264,7 +345,6 @@ fn attempt_push(
         &format!("refs/recursive_remote/{}/tmp/", &config.remote_name),
     )?;
 
-    let all_objects_ever_repo = Rc::new(config.all_objects_ever_repo()?);
     let pack_process = start_pack_process(
         &all_objects_ever_repo,
         &namespace,
@@ -281,29 +361,86 @@ fn attempt_push(
         &all_objects_ever_repo,
         &namespace,
         pack_process,
-        &pushes,
-        &force_pushes,
+        RefUpdates {
+            refs: &pushes,
+            force_refs: &force_pushes,
+        },
+        options,
     )
     .context("update_namespace_with_push")?;
-    let future = update_state_with_push(
+
+    // A dry run stops once we know what would happen to each ref, as does an
+    // atomic push with a rejected ref. Nothing has been written to the tracking
+    // repo's refs, and upstream is untouched.
+    let mut future_namespace = match future_namespace {
+        Some(future_namespace) if !options.dry_run => future_namespace,
+        _ => return Ok(PushResult::Ok(push_status)),
+    };
+    set_default_head(&mut future_namespace, preferred_head.as_deref());
+
+    publish_namespace(
         config,
         &tracking_repo,
         &state,
-        &future_namespace,
         &state_identifier,
+        root_id,
+        &future_namespace,
+        push_status,
     )
-    .context("update_state_with_push")?;
+}
 
-    do_commit(
-        &config.namespace,
-        &tracking_repo,
-        &config.pushing_ref,
-        &future,
+// Commits a new state holding the namespace to the tracking repo and pushes it
+// upstream, as publish_state does.
+fn publish_namespace(
+    config: &Config,
+    tracking_repo: &Rc<gix::Repository>,
+    state: &State,
+    state_identifier: &Option<StateRef>,
+    root_id: Option<gix_hash::ObjectId>,
+    namespace: &Namespace,
+    push_status: HashMap<String, RefStatus>,
+) -> Result<PushResult> {
+    // Each update describes only itself, so a record is never carried over from
+    // the namespace it replaces.
+    let mut namespace = namespace.clone();
+    namespace.audit = match config.audit_identity.as_deref() {
+        Some(pusher) => {
+            let previous = state.namespace(&config.namespace, &config.nacl_keys, tracking_repo)?;
+            Some(audit_record(pusher, previous.as_ref(), &namespace))
+        }
+        None => None,
+    };
+
+    let future = update_state_with_push(config, tracking_repo, state, &namespace, state_identifier)
+        .context("update_state_with_push")?;
+
+    publish_state(
+        config,
+        tracking_repo,
+        state_identifier,
         root_id,
-        &config.nacl_keys,
-        config.max_object_size,
+        &future,
+        NamespaceChanges {
+            written: &[&config.namespace],
+            deleted: &[],
+        },
+        push_status,
     )
-    .context("commit")?;
+}
+
+// Commits `future`, which made `changes` to the namespaces, to the tracking repo
+// and pushes it upstream. A retry is requested if upstream moved since
+// `state_identifier`.
+fn publish_state(
+    config: &Config,
+    tracking_repo: &Rc<gix::Repository>,
+    state_identifier: &Option<StateRef>,
+    root_id: Option<gix_hash::ObjectId>,
+    future: &State,
+    changes: NamespaceChanges<'_>,
+    push_status: HashMap<String, RefStatus>,
+) -> Result<PushResult> {
+    do_commit(config, changes, tracking_repo, future, root_id).context("commit")?;
 
     let push_result = execute_subprocess2(
         crate::util::git_command()
@@ -317,46 +454,483 @@ fn attempt_push(
     .context("git push");
 
     if push_result.is_ok() {
+        // Upstream now holds our state, so later commands this session start
+        // from it rather than fetching it back.
+        cache_pushing_branch(config).context("cache pushed state")?;
         return Ok(PushResult::Ok(push_status));
     }
 
+    // The cached state is what we failed to push on top of. The fresh state
+    // fetched here is cached in its place for the retry.
     let (new_state_identifier, _new_state, _basis_state, _root_id, _commit_id) =
-        update_branches(config).context("push secondary update")?;
+        refresh_branches(config).context("push secondary update")?;
     classify_failed_push_for_retry(
-        &state_identifier,
+        state_identifier,
         &new_state_identifier,
         &push_result.expect_err("checked is_ok above"),
     )
 }
 
-pub fn push(config: &Config, specs: &[String]) -> Result<()> {
+pub fn push(config: &Config, options: &Options, specs: &[String]) -> Result<()> {
     let mut pushes = Vec::new();
     let mut force_pushes = Vec::new();
     parse_push_specs(specs, &mut pushes, &mut force_pushes)
         .with_context(|| format!("parse push specs: {:?}", specs))?;
 
-    for _ in 0..25 {
-        let success = attempt_push(config, &pushes, &force_pushes)?;
-
-        match success {
-            PushResult::Ok(push_status) => {
-                for (name, status) in push_status {
-                    if status {
-                        log::trace!("push {} status: OK", &name);
-                        println!("ok {}\n", &name);
-                    } else {
-                        log::trace!("push {} status: rejected", &name);
-                        println!("error {} rejected\n", &name);
-                    }
+    let push_status = retry_state_update(config, "push", |branch| {
+        attempt_push(config, options, branch, &pushes, &force_pushes)
+    })?;
+
+    for (name, status) in push_status {
+        match status {
+            RefStatus::Ok => {
+                log::trace!("push {} status: OK", &name);
+                println!("ok {}", &name);
+            }
+            RefStatus::Rejected(why) => {
+                log::trace!("push {} status: {}", &name, &why);
+                println!("error {} {}", &name, &why);
+            }
+        }
+    }
+    // Git reads statuses until a blank line, so only the last ends with one,
+    // lest the statuses after the first go unread.
+    println!();
+
+    Ok(())
+}
+
+/// Points the namespace's HEAD at `target`, which clones will then check out.
+/// The target must already be a ref in the namespace.
+pub fn set_head(config: &Config, target: &str) -> Result<()> {
+    retry_state_update(config, "set HEAD", |branch| {
+        let BranchState {
+            identifier: state_identifier,
+            state,
+            root_id,
+        } = branch;
+
+        let tracking_repo = Rc::new(config.tracking_repo()?);
+        let mut namespace = state
+            .namespace(&config.namespace, &config.nacl_keys, &tracking_repo)?
+            .unwrap_or_else(Namespace::new);
+        if !namespace.refs.contains_key(target) {
+            anyhow::bail!("{} is not a ref in namespace {}", target, &config.namespace);
+        }
+
+        // This update carries no objects and no push options of its own.
+        namespace
+            .refs
+            .insert(HEAD.to_string(), Ref::Symbolic(target.to_string(), None));
+        namespace.pack = None;
+        namespace.push_options.clear();
+
+        publish_namespace(
+            config,
+            &tracking_repo,
+            &state,
+            &state_identifier,
+            root_id,
+            &namespace,
+            HashMap::new(),
+        )
+    })
+    .map(|_| ())
+}
+
+/// Rewrites the branch state and the namespace in the current format. Older
+/// clients can no longer read them afterwards. The new state descends from the
+/// old as with any push, so the sha256 ratchet is unaffected.
+pub fn migrate(config: &Config) -> Result<()> {
+    let is_current = |format: Option<crate::format::FormatHeader>| {
+        format.is_some_and(|format| format.version == crate::format::FORMAT_VERSION)
+    };
+    retry_state_update(config, "migrate", |branch| {
+        let BranchState {
+            identifier: state_identifier,
+            mut state,
+            root_id,
+        } = branch;
+
+        let tracking_repo = Rc::new(config.tracking_repo()?);
+        let mut namespace = state
+            .namespace(&config.namespace, &config.nacl_keys, &tracking_repo)?
+            .with_context(|| format!("namespace {} does not exist", &config.namespace))?;
+        if is_current(state.format) && is_current(namespace.format) {
+            log::info!("Namespace {} is already current.", &config.namespace);
+            return Ok(PushResult::Ok(HashMap::new()));
+        }
+
+        state.format = Some(crate::format::FormatHeader::current());
+        namespace.format = Some(crate::format::FormatHeader::current());
+        namespace.pack = None;
+        namespace.push_options.clear();
+
+        publish_namespace(
+            config,
+            &tracking_repo,
+            &state,
+            &state_identifier,
+            root_id,
+            &namespace,
+            HashMap::new(),
+        )
+    })
+    .map(|_| ())
+}
+
+/// Removes the namespace from the branch, dropping its tree from future
+/// commits. Its packs are only left in the history of the branch.
+pub fn delete_namespace(config: &Config) -> Result<()> {
+    retry_state_update(config, "delete the namespace", |branch| {
+        let BranchState {
+            identifier: state_identifier,
+            state,
+            root_id,
+        } = branch;
+
+        let tracking_repo = Rc::new(config.tracking_repo()?);
+        let namespace = state
+            .namespace(&config.namespace, &config.nacl_keys, &tracking_repo)?
+            .with_context(|| format!("namespace {} does not exist", &config.namespace))?;
+
+        let mut future = State {
+            parents: state_identifier.iter().cloned().collect(),
+            supersedes: None,
+            ..state.clone()
+        };
+        future.namespaces.remove(&config.namespace);
+
+        publish_state(
+            config,
+            &tracking_repo,
+            &state_identifier,
+            root_id,
+            &future,
+            NamespaceChanges {
+                written: &[],
+                deleted: &[namespace.random_name],
+            },
+            HashMap::new(),
+        )
+    })
+    .map(|_| ())
+}
+
+/// Moves the namespace to `new_name`, keeping its refs, packs and tree. The
+/// manifest is first made to list every pack, since states from before the
+/// rename hold the namespace under its old name.
+pub fn rename_namespace(config: &Config, new_name: &str) -> Result<()> {
+    retry_state_update(config, "rename the namespace", |branch| {
+        let BranchState {
+            identifier: state_identifier,
+            state,
+            root_id,
+        } = branch;
+        if state.namespaces.contains_key(new_name) {
+            anyhow::bail!("namespace {} already exists", new_name);
+        }
+
+        let tracking_repo = Rc::new(config.tracking_repo()?);
+        let previous = state
+            .namespace(&config.namespace, &config.nacl_keys, &tracking_repo)?
+            .with_context(|| format!("namespace {} does not exist", &config.namespace))?;
+
+        // States from before the rename hold the packs under the old name, so
+        // only the manifest can list them.
+        if previous.format.is_none() {
+            anyhow::bail!("renaming needs the versioned format; run --migrate first");
+        }
+
+        // This update carries no objects and no push options of its own.
+        let mut namespace = previous.clone();
+        namespace.pack = None;
+        namespace.push_options.clear();
+        if namespace
+            .manifest
+            .as_ref()
+            .is_none_or(|manifest| manifest.history.is_some())
+        {
+            let packs = crate::cmd_fetch::ordered_pack_list(
+                &config.nacl_keys,
+                &tracking_repo,
+                &config.namespace,
+                state_identifier.as_ref(),
+                &state,
+                None,
+                &mut crate::progress::Progress::disabled(),
+            )?;
+            let mut manifest = PackManifest::default();
+            for pack in packs.into_iter().rev() {
+                crate::manifest::append(
+                    &tracking_repo,
+                    &mut manifest,
+                    &mut namespace.format,
+                    pack,
+                    &config.nacl_keys,
+                    config.max_object_size,
+                    config.encode_options,
+                )
+                .context("rebuild pack manifest")?;
+            }
+            namespace.manifest = Some(manifest);
+        }
+        namespace.audit = config
+            .audit_identity
+            .as_deref()
+            .map(|pusher| audit_record(pusher, Some(&previous), &namespace));
+
+        let mut future = State {
+            parents: state_identifier.iter().cloned().collect(),
+            supersedes: None,
+            ..state.clone()
+        };
+        future.namespaces.remove(&config.namespace);
+        future.namespaces.insert(
+            new_name.to_string(),
+            NamespaceRef(
+                crate::encoding::encode_namespace(
+                    &tracking_repo,
+                    &namespace,
+                    &config.nacl_keys,
+                    config.max_object_size,
+                    config.encode_options.list_in(&mut future.format),
+                )
+                .context("encode namespace")?,
+            ),
+        );
+
+        publish_state(
+            config,
+            &tracking_repo,
+            &state_identifier,
+            root_id,
+            &future,
+            NamespaceChanges {
+                written: &[new_name],
+                deleted: &[],
+            },
+            HashMap::new(),
+        )
+    })
+    .map(|_| ())
+}
+
+/// Begins a new epoch: a state that supersedes the current chain of states
+/// rather than descending from it. Each namespace our keys can read is
+/// consolidated into a single pack of the objects reachable from its refs, so
+/// that reading it needs nothing from before the epoch. Other namespaces are
+/// carried over as they are. Clients that had the superseded chain verify the
+/// epoch through its link to it.
+pub fn gc(config: &Config) -> Result<()> {
+    let mut summary = (0, 0);
+    retry_state_update(config, "collect garbage", |branch| {
+        let BranchState {
+            identifier: state_identifier,
+            state,
+            root_id,
+        } = branch;
+        let Some(superseded) = state_identifier.as_ref() else {
+            anyhow::bail!("the branch holds no state to collect");
+        };
+        if state.format.is_none() {
+            anyhow::bail!("epochs need the versioned format; run --migrate first");
+        }
+
+        let tracking_repo = Rc::new(config.tracking_repo()?);
+        let all_objects_ever_repo = config.all_objects_ever_repo()?;
+        let mut future = State {
+            parents: Vec::new(),
+            supersedes: Some(superseded.clone()),
+            ..state.clone()
+        };
+
+        let mut names: Vec<&String> = state.namespaces.keys().collect();
+        names.sort();
+        let mut consolidated = Vec::new();
+        for name in names {
+            let namespace = match state.namespace(name, &config.nacl_keys, &tracking_repo) {
+                Ok(namespace) => namespace.context("namespace listed in the state")?,
+                Err(err) => {
+                    log::info!(
+                        "Carrying over namespace {}, which we can't read: {:#}",
+                        name,
+                        err
+                    );
+                    continue;
                 }
+            };
+            if let Err(err) = namespace.check_object_hash(all_objects_ever_repo.object_hash()) {
+                log::info!("Carrying over namespace {}: {:#}", name, err);
+                continue;
+            }
+
+            let mut future_namespace =
+                consolidate_namespace(config, &tracking_repo, name, superseded, &state, &namespace)
+                    .with_context(|| format!("consolidate namespace {}", name))?;
+            future_namespace.audit = config
+                .audit_identity
+                .as_deref()
+                .map(|pusher| audit_record(pusher, Some(&namespace), &future_namespace));
+
+            let namespace_ref = NamespaceRef(
+                crate::encoding::encode_namespace(
+                    &tracking_repo,
+                    &future_namespace,
+                    &config.nacl_keys,
+                    config.max_object_size,
+                    config.encode_options.list_in(&mut future.format),
+                )
+                .context("encode namespace")?,
+            );
+            future.namespaces.insert(name.clone(), namespace_ref);
+            consolidated.push(name.as_str());
+        }
+        if consolidated.is_empty() {
+            anyhow::bail!("none of the namespaces can be read with our keys");
+        }
 
-                return Ok(());
+        summary = (consolidated.len(), state.namespaces.len());
+        publish_state(
+            config,
+            &tracking_repo,
+            &state_identifier,
+            root_id,
+            &future,
+            NamespaceChanges {
+                written: &consolidated,
+                deleted: &[],
+            },
+            HashMap::new(),
+        )
+    })?;
+
+    log::info!(
+        "Began a new epoch, consolidating {} of {} namespaces.",
+        summary.0,
+        summary.1
+    );
+    Ok(())
+}
+
+// Rewrites the namespace to hold a single pack of every object reachable from
+// its refs, less those its shallow basis supplies, with a manifest listing only
+// that pack.
+fn consolidate_namespace(
+    config: &Config,
+    tracking_repo: &Rc<gix::Repository>,
+    name: &str,
+    state_identifier: &StateRef,
+    state: &State,
+    namespace: &Namespace,
+) -> Result<Namespace> {
+    let packs = crate::cmd_fetch::ordered_pack_list(
+        &config.nacl_keys,
+        tracking_repo,
+        name,
+        Some(state_identifier),
+        state,
+        None,
+        &mut crate::progress::Progress::disabled(),
+    )?;
+    let oldest_first: Vec<_> = packs.into_iter().rev().collect();
+    crate::cmd_fetch::fetch_packs(
+        config,
+        tracking_repo,
+        &oldest_first,
+        &mut crate::progress::Progress::disabled(),
+    )?;
+
+    // The epoch already needs the versioned format to be read, which the
+    // manifest listing the consolidated pack needs too.
+    let mut future = Namespace {
+        pack: None,
+        push_options: Vec::new(),
+        manifest: Some(PackManifest::default()),
+        format: namespace
+            .format
+            .or(Some(crate::format::FormatHeader::current())),
+        ..namespace.clone()
+    };
+    let tips: Vec<_> = namespace
+        .refs
+        .values()
+        .filter_map(Ref::oid_at_time)
+        .collect();
+    if tips.is_empty() {
+        return Ok(future);
+    }
+
+    // The shallow basis was never pushed, so may only be in the user repo.
+    let user_repo = config.user_repo()?;
+    let all_objects_ever_repo = config.all_objects_ever_repo()?;
+    let mut cmd = crate::util::git_command()
+        .current_dir(&config.all_objects_ever_repo_path)
+        .env(
+            "GIT_ALTERNATE_OBJECT_DIRECTORIES",
+            user_repo.common_dir().join("objects"),
+        )
+        .arg("pack-objects")
+        .arg("--revs")
+        .arg("--stdout")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("Failed to spawn git pack-objects.")?;
+
+    {
+        let stdin = cmd.stdin.take().context("No stdin.")?;
+        let mut stdin = std::io::BufWriter::new(stdin);
+        for oid in tips.iter() {
+            writeln!(&mut stdin, "{}", oid).context("write include revs to git pack-objects")?;
+        }
+        for oid in namespace
+            .shallow_basis
+            .values()
+            .filter_map(Ref::oid_at_time)
+        {
+            let present = |repo: &gix::Repository| {
+                repo.objects
+                    .try_header(oid.as_ref())
+                    .is_ok_and(|header| header.is_some())
+            };
+            if present(&all_objects_ever_repo) || present(&user_repo) {
+                writeln!(&mut stdin, "^{}", oid)
+                    .context("write exclude revs to git pack-objects")?;
             }
-            PushResult::Retry => {}
         }
     }
 
-    None.context("After many tries, unable to push due to conflicts in the backing repo.")
+    let mut reader = std::io::BufReader::new(cmd.stdout.take().context("No stdout.")?);
+    let (blob_ref, size) = encode_pack(
+        config,
+        tracking_repo,
+        &mut future.format,
+        &mut reader,
+        &mut crate::progress::Progress::disabled(),
+    )
+    .context("encode pack file")?;
+    wait_subprocess(&mut cmd).context("git pack-objects")?;
+
+    if size > 0 {
+        let pack_ref = PackRef {
+            blob_ref,
+            random_name: rand::thread_rng().r#gen(),
+        };
+        crate::manifest::append(
+            tracking_repo,
+            future.manifest.as_mut().expect("set above"),
+            &mut future.format,
+            pack_ref.clone(),
+            &config.nacl_keys,
+            config.max_object_size,
+            config.encode_options,
+        )
+        .context("update pack manifest")?;
+        future.pack = Some(pack_ref);
+    }
+    Ok(future)
 }
 
 #[cfg(test)]
@@ -365,7 +939,7 @@ mod tests {
 
     fn fake_state_ref(tag: u8) -> StateRef {
         StateRef(BlobRef {
-            resource_key: ResourceKey::Annex(format!("annex-{tag}")),
+            resource_key: ResourceKey::Annex(vec![format!("annex-{tag}")]),
             sha256: [tag; 32],
         })
     }
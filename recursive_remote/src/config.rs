@@ -14,7 +14,10 @@ use log::{info, trace};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
+use crate::blob_store::{Annex, AnnexDir};
+use crate::encoding::{Compression, EncodeOptions, Padding};
 use crate::serialization::Ref;
+use crate::update::StateCache;
 use crate::util::*;
 
 #[derive(
@@ -27,6 +30,12 @@ pub enum ConfigKey {
     StateNaclKey,
     ShallowBasis,
     MaxObjectSize,
+    AnnexDir,
+    AnnexThreshold,
+    AnnexUrl,
+    AuditIdentity,
+    Compression,
+    Padding,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Debug, Clone)]
@@ -82,6 +91,12 @@ impl ConfigKey {
             ConfigKey::StateNaclKey => "recursive-state-nacl-key",
             ConfigKey::ShallowBasis => "recursive-shallow-basis",
             ConfigKey::MaxObjectSize => "recursive-max-object-size",
+            ConfigKey::AnnexDir => "recursive-annex-dir",
+            ConfigKey::AnnexThreshold => "recursive-annex-threshold",
+            ConfigKey::AnnexUrl => "recursive-annex-url",
+            ConfigKey::AuditIdentity => "recursive-audit-identity",
+            ConfigKey::Compression => "recursive-compression",
+            ConfigKey::Padding => "recursive-padding",
         }
     }
 
@@ -93,6 +108,12 @@ impl ConfigKey {
             ConfigKey::StateNaclKey => false,
             ConfigKey::ShallowBasis => false,
             ConfigKey::MaxObjectSize => true,
+            ConfigKey::AnnexDir => false,
+            ConfigKey::AnnexThreshold => true,
+            ConfigKey::AnnexUrl => false,
+            ConfigKey::AuditIdentity => false,
+            ConfigKey::Compression => false,
+            ConfigKey::Padding => false,
         }
     }
 
@@ -104,6 +125,12 @@ impl ConfigKey {
             ConfigKey::StateNaclKey => "d",
             ConfigKey::ShallowBasis => "e",
             ConfigKey::MaxObjectSize => "f",
+            ConfigKey::AnnexDir => "g",
+            ConfigKey::AnnexThreshold => "h",
+            ConfigKey::AnnexUrl => "i",
+            ConfigKey::AuditIdentity => "j",
+            ConfigKey::Compression => "k",
+            ConfigKey::Padding => "l",
         }
     }
 
@@ -115,6 +142,12 @@ impl ConfigKey {
             "d" => Some(ConfigKey::StateNaclKey),
             "e" => Some(ConfigKey::ShallowBasis),
             "f" => Some(ConfigKey::MaxObjectSize),
+            "g" => Some(ConfigKey::AnnexDir),
+            "h" => Some(ConfigKey::AnnexThreshold),
+            "i" => Some(ConfigKey::AnnexUrl),
+            "j" => Some(ConfigKey::AuditIdentity),
+            "k" => Some(ConfigKey::Compression),
+            "l" => Some(ConfigKey::Padding),
             _ => None,
         }
     }
@@ -160,6 +193,9 @@ pub struct Config {
     // The user's git repo path.
     pub user_repo_path: PathBuf,
 
+    // The user repo's object format, which the all objects ever repo shares.
+    pub object_hash: gix_hash::Kind,
+
     // Our tracking repo path.
     pub tracking_repo_path: PathBuf,
 
@@ -175,21 +211,90 @@ pub struct Config {
     pub remote_url: String,
     pub remote_ref: String,
     pub nacl_keys: EncryptionKeys,
-    pub shallow_basis: Vec<Ref>,
+    pub shallow_basis: Vec<(String, Ref)>,
     pub max_object_size: usize,
+
+    // How blobs are compressed, and encrypted ones padded, before they are
+    // stored.
+    pub encode_options: EncodeOptions,
+
+    // Where packs of at least annex_threshold bytes are kept instead of the
+    // upstream branch, and where such packs are read from.
+    pub annex: Option<Box<Annex>>,
+    pub annex_threshold: u64,
+
+    // Who to record as the pusher in each namespace update's audit record, or
+    // None to keep no audit trail.
+    pub audit_identity: Option<String>,
+
+    // The upstream state fetched earlier in this session, if still valid.
+    pub state_cache: StateCache,
 }
 
 impl Args {
     pub fn new(remote_name: &str, remote_url: &str) -> Result<Args> {
         let user_repo_path =
             std::fs::canonicalize(std::env::var("GIT_DIR").context("Git dir not defined.")?)?;
+        Ok(Args::at(user_repo_path, remote_name, remote_url))
+    }
+
+    /// Args for reading `branch` of the upstream at `url` with no remote
+    /// configured for it, from a scratch user repo that lasts as long as the
+    /// returned dir. `keys` are the paths of the state and namespace key files.
+    pub fn scratch(
+        url: &str,
+        branch: &str,
+        keys: Option<(&str, &str)>,
+    ) -> Result<(Args, tempfile::TempDir)> {
+        let scratch = tempfile::Builder::new()
+            .prefix("recursive_remote")
+            .tempdir()
+            .context("Unable to create temp dir.")?;
+        let user_repo_path = scratch.path().join("user_repo");
+        gix::init_bare(&user_repo_path).context("create scratch repo")?;
+
+        let remote_name = "upstream";
+        let mut git_config = gix_config::File::new(Metadata::default());
+        write_config(
+            remote_name,
+            ConfigKey::RemoteBranch,
+            &mut git_config,
+            branch,
+        )?;
+        for (key, path) in keys.into_iter().flat_map(|(state_key, namespace_key)| {
+            [
+                (ConfigKey::StateNaclKey, state_key),
+                (ConfigKey::NamespaceNaclKey, namespace_key),
+            ]
+        }) {
+            // Configured key files that don't exist are created, which here
+            // would only hide a typo.
+            let path = std::fs::canonicalize(expand_home(path)?)
+                .with_context(|| format!("key file {:?} not found", path))?;
+            write_config(
+                remote_name,
+                key,
+                &mut git_config,
+                &format!("file://{}", path.display()),
+            )?;
+        }
+        let mut fd = std::fs::OpenOptions::new()
+            .append(true)
+            .open(user_repo_path.join("config"))
+            .context("open scratch repo config")?;
+        git_config.write_to(&mut fd)?;
+
+        Ok((Args::at(user_repo_path, remote_name, url), scratch))
+    }
 
+    /// As new, for the user repo at `user_repo_path` rather than `GIT_DIR`.
+    pub fn at(user_repo_path: PathBuf, remote_name: &str, remote_url: &str) -> Args {
         let state_path = user_repo_path.join("recursive_remote");
         let tracking_repo_path = state_path.join("tracking_repo");
         let all_objects_ever_repo_path = state_path.join("all_objects_ever_repo");
         let lock_path = state_path.join("locks");
 
-        Ok(Args {
+        Args {
             user_repo_path,
             lock_path,
             tracking_repo_path,
@@ -197,7 +302,7 @@ impl Args {
             state_path,
             remote_name: remote_name.to_string(),
             remote_url: remote_url.to_string(),
-        })
+        }
     }
 
     pub fn user_repo(&self) -> Result<gix::Repository> {
@@ -208,8 +313,11 @@ impl Args {
         open_create_bare_repository(&self.tracking_repo_path).context("open user repo.")
     }
 
+    // Objects are moved between this and the user repo with git push and
+    // fetch, so they must agree on the object format.
     pub fn all_objects_ever_repo(&self) -> Result<gix::Repository> {
-        open_create_bare_repository(&self.all_objects_ever_repo_path)
+        let object_hash = self.user_repo()?.object_hash();
+        open_create_bare_repository_with_format(&self.all_objects_ever_repo_path, object_hash)
             .context("open all_objects_ever repo.")
     }
 }
@@ -274,6 +382,28 @@ impl Config {
             anyhow::bail!("max_object_size must be <= 1024 * 1024 * 1024");
         }
 
+        let compression = read_config(&args, ConfigKey::Compression, &user_config)?
+            .map(|compression| compression.to_string().parse::<Compression>())
+            .transpose()
+            .context("compression")?;
+        let padding = read_config(&args, ConfigKey::Padding, &user_config)?
+            .map(|padding| padding.to_string().parse::<Padding>())
+            .transpose()
+            .context("padding")?
+            .unwrap_or_default();
+        let encode_options = EncodeOptions::new(compression, padding).context("compression")?;
+
+        let annex = configure_annex(&args, &user_config).context("annex config")?;
+        let annex_threshold = read_config_i64(&args, ConfigKey::AnnexThreshold, &user_config)
+            .context("annex threshold")?
+            .map(u64::try_from)
+            .transpose()
+            .context("annex threshold must be >= 0")?
+            .unwrap_or(max_object_size as u64);
+
+        let audit_identity = read_config(&args, ConfigKey::AuditIdentity, &user_config)?
+            .map(|identity| identity.to_string());
+
         let subsection: &BStr = args.remote_name.as_bytes().into();
         tracking_config.remove_section("remote", Some(subsection));
         configure_tracking_config(&args, &user_config, &mut tracking_config)
@@ -319,6 +449,7 @@ impl Config {
         Ok(Config {
             namespace,
             user_repo_path: args.user_repo_path,
+            object_hash: user_repo.object_hash(),
             tracking_repo_path: args.tracking_repo_path,
             state_path: args.state_path,
             all_objects_ever_repo_path: args.all_objects_ever_repo_path,
@@ -332,11 +463,16 @@ impl Config {
             nacl_keys,
             shallow_basis,
             max_object_size,
+            encode_options,
+            annex,
+            annex_threshold,
+            audit_identity,
+            state_cache: StateCache::default(),
         })
     }
 
     pub fn all_objects_ever_repo(&self) -> Result<gix::Repository> {
-        open_create_bare_repository(&self.all_objects_ever_repo_path)
+        open_create_bare_repository_with_format(&self.all_objects_ever_repo_path, self.object_hash)
             .context("open all_objects_ever repo.")
     }
 
@@ -383,29 +519,25 @@ fn configure_nacl_key(
 }
 
 fn configure_nacl_key_file(value: &str) -> Result<Option<eseb::SymmetricKey>> {
+    let path = expand_home(value)?;
     let key = match std::fs::OpenOptions::new()
         .write(true)
         .create_new(true)
-        .open(value)
+        .open(&path)
     {
         Ok(mut fd) => {
-            info!("Storing newly created NaCl key in file {:?}.", &value);
+            info!("Storing newly created NaCl key in file {:?}.", &path);
             let key = eseb::SymmetricKey::gen_key().context("gen key file")?;
             fd.write_all(key.serialize_to_string().as_bytes())?;
             key
         }
         Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
-            trace!("Reading key file: {:?}", &value);
-            let mut fd = std::fs::File::open(value)?;
+            trace!("Reading key file: {:?}", &path);
+            let mut fd = std::fs::File::open(&path)?;
             let mut s = String::default();
             fd.read_to_string(&mut s).context("Failed to read key.")?;
             eseb::SymmetricKey::from_str(s.trim()).context("decode key")?
         }
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound && value.starts_with("~/") => {
-            let home = std::env::var("HOME").context("read env var HOME")?;
-            let value = PathBuf::from(home).join(&value[2..]);
-            return configure_nacl_key_file(&value.to_string_lossy());
-        }
         Err(e) => return Err(e.into()),
     };
 
@@ -426,6 +558,64 @@ pub fn configure_nacl(
     }
 }
 
+/// Expands a leading `~/` in `path` to the home directory, as a shell would.
+pub fn expand_home(path: &str) -> Result<PathBuf> {
+    match path.strip_prefix("~/") {
+        Some(rest) => {
+            Ok(PathBuf::from(std::env::var("HOME").context("read env var HOME")?).join(rest))
+        }
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Reads a keyring of further namespace keys, one per line, each given inline or
+/// as `file://` and a path as in git config. Unlike the configured keys, keys
+/// named by path are never created. Blank lines and those starting with `#` are
+/// skipped.
+pub fn read_keyring(path: &std::path::Path) -> Result<Vec<eseb::SymmetricKey>> {
+    let keyring =
+        std::fs::read_to_string(path).with_context(|| format!("read keyring {:?}", path))?;
+    let mut keys = Vec::default();
+    for (i, line) in keyring.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let key = match line.strip_prefix("file://") {
+            Some(key_path) => {
+                let key_path = expand_home(key_path)?;
+                let key = std::fs::read_to_string(&key_path)
+                    .with_context(|| format!("read key file {:?}", &key_path))?;
+                eseb::SymmetricKey::from_str(key.trim())
+            }
+            None => eseb::SymmetricKey::from_str(line),
+        }
+        .with_context(|| format!("parse key on line {} of keyring {:?}", i + 1, path))?;
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+fn configure_annex(args: &Args, git_config: &gix_config::File) -> Result<Option<Box<Annex>>> {
+    match (
+        read_config(args, ConfigKey::AnnexDir, git_config)?,
+        read_config(args, ConfigKey::AnnexUrl, git_config)?,
+    ) {
+        (None, None) => Ok(None),
+        (Some(value), None) => Ok(Some(Box::new(AnnexDir::new(expand_home(
+            &value.to_string(),
+        )?)))),
+        (None, Some(url)) => Ok(Some(Box::new(crate::s3::S3Store::from_env(
+            &url.to_string(),
+        )?))),
+        (Some(..), Some(..)) => anyhow::bail!(
+            "at most one of {} and {} may be set",
+            ConfigKey::AnnexDir,
+            ConfigKey::AnnexUrl
+        ),
+    }
+}
+
 pub fn configure_remote_branch(args: &Args, git_config: &gix_config::File) -> Result<String> {
     Ok(
         match read_config(args, ConfigKey::RemoteBranch, git_config)? {
@@ -445,17 +635,18 @@ pub fn configure_shallow_basis(
     user_repo: &gix::Repository,
     args: &Args,
     git_config: &gix_config::File,
-) -> Result<Vec<Ref>> {
+) -> Result<Vec<(String, Ref)>> {
     let mut refs = Vec::default();
     for spec in read_config(args, ConfigKey::ShallowBasis, git_config)?
         .unwrap_or_default()
         .to_string()
         .split_whitespace()
     {
-        refs.push(
+        refs.push((
+            spec.to_string(),
             Ref::new(user_repo, spec)
                 .with_context(|| format!("resolve shallow basis ref {}", &spec))?,
-        );
+        ));
     }
     Ok(refs)
 }
@@ -719,6 +910,54 @@ mod tests {
         assert_eq!(read, Some(12345));
     }
 
+    #[test]
+    fn keyrings_hold_inline_keys_and_key_files() {
+        let tmp = tempfile::Builder::new()
+            .prefix("config-tests")
+            .tempdir()
+            .expect("tempdir");
+        let inline = eseb::SymmetricKey::gen_key().expect("gen key");
+        let in_file = eseb::SymmetricKey::gen_key().expect("gen key");
+        let key_path = tmp.path().join("nacl.key");
+        std::fs::write(&key_path, in_file.serialize_to_string()).expect("write key");
+        let keyring_path = tmp.path().join("keyring");
+        std::fs::write(
+            &keyring_path,
+            format!(
+                "# Old keys\n{}\n\n  file://{}\n",
+                inline.serialize_to_string(),
+                key_path.display()
+            ),
+        )
+        .expect("write keyring");
+
+        let keys = read_keyring(&keyring_path).expect("read keyring");
+        let keys: Vec<_> = keys.iter().map(|key| key.serialize_to_string()).collect();
+        assert_eq!(
+            keys,
+            vec![inline.serialize_to_string(), in_file.serialize_to_string()]
+        );
+
+        // Missing key files are an error rather than created.
+        std::fs::write(&keyring_path, "file://missing.key\n").expect("write keyring");
+        assert!(read_keyring(&keyring_path).is_err());
+        assert!(!std::path::Path::new("missing.key").exists());
+    }
+
+    #[test]
+    fn expand_home_expands_only_a_leading_tilde() {
+        let home = PathBuf::from(std::env::var("HOME").expect("HOME"));
+        assert_eq!(expand_home("~/keys/a").expect("home"), home.join("keys/a"));
+        assert_eq!(
+            expand_home("keys/~/a").expect("relative"),
+            PathBuf::from("keys/~/a")
+        );
+        assert_eq!(
+            expand_home("/keys/a").expect("absolute"),
+            PathBuf::from("/keys/a")
+        );
+    }
+
     #[test]
     fn configure_nacl_rejects_invalid_inline_key() {
         let (args, _tmp) = test_args("origin");
@@ -1,14 +1,18 @@
+use std::cell::RefCell;
 use std::io::{BufRead, Read, Seek, Write};
 use std::rc::Rc;
 
 use anyhow::{Context, Result};
 use eseb::{EncryptingWriter, SymmetricKey};
-use gix::prelude::Write as GixPreludeWrite;
 use gix_hash::ObjectId;
 use record_reader::{Format, IoRecordReader, IoRecordWriter};
 use sha2::Digest;
 
+use crate::blob_store::{Annex, BlobStore, GitStore};
+use crate::chunker::{ChunkCache, ChunkHasher, Chunker, chunker_seed};
 use crate::config::EncryptionKeys;
+use crate::format::FormatHeader;
+use crate::progress::Progress;
 use crate::serialization::*;
 
 pub fn encode_state(
@@ -16,14 +20,34 @@ pub fn encode_state(
     state: &State,
     encryption: &EncryptionKeys,
     max_object_size: usize,
+    options: EncodeOptions,
 ) -> Result<BlobRef> {
-    let state: SerializedState = state.into();
-    let buf = bincode::serialize(&state).context("encode state")?;
+    let buf = state.to_bytes().context("encode state")?;
     let (blob_ref, _size) = encode(
         repo,
         &mut buf.as_ref(),
         encryption.state_key(),
         max_object_size,
+        options,
+    )?;
+    Ok(blob_ref)
+}
+
+// state.bincode is listed by nothing with a header to say how it is laid out,
+// so a pointer is always written as one stream.
+pub fn encode_state_pointer(
+    repo: &Rc<gix::Repository>,
+    pointer: &StatePointer,
+    encryption: &EncryptionKeys,
+    max_object_size: usize,
+) -> Result<BlobRef> {
+    let buf = pointer.to_bytes().context("encode state pointer")?;
+    let (blob_ref, _size) = encode(
+        repo,
+        &mut buf.as_ref(),
+        encryption.state_key(),
+        max_object_size,
+        EncodeOptions::default().listed_in(None),
     )?;
     Ok(blob_ref)
 }
@@ -33,43 +57,343 @@ pub fn encode_namespace(
     namespace: &Namespace,
     encryption: &EncryptionKeys,
     max_object_size: usize,
+    options: EncodeOptions,
+) -> Result<BlobRef> {
+    let buf = namespace.to_bytes().context("encode namespace")?;
+    let (blob_ref, _size) = encode(
+        repo,
+        &mut buf.as_ref(),
+        encryption.namespace_key(),
+        max_object_size,
+        options,
+    )?;
+    Ok(blob_ref)
+}
+
+// Segments belong to a namespace, so share its key.
+pub fn encode_pack_segment(
+    repo: &Rc<gix::Repository>,
+    segment: &PackSegment,
+    encryption: &EncryptionKeys,
+    max_object_size: usize,
+    options: EncodeOptions,
 ) -> Result<BlobRef> {
-    let namespace: SerializedNamespace = namespace.into();
-    let buf = bincode::serialize(&namespace).context("encode namespace")?;
+    let buf = segment.to_bytes().context("encode pack segment")?;
     let (blob_ref, _size) = encode(
         repo,
         &mut buf.as_ref(),
         encryption.namespace_key(),
         max_object_size,
+        options,
     )?;
     Ok(blob_ref)
 }
 
-struct SplitWriter {
+// Begins each chunk of a framed blob, which is encoded chunk by chunk, followed
+// by a byte naming its compression. No record length, pack or bincode count
+// begins with it, so blobs encoded as one stream split at fixed offsets, as
+// before, can still be told apart.
+const CHUNK_MAGIC: &[u8; 8] = b"\xff\xff\xff\xffrrcc";
+
+// The compression a chunk names after CHUNK_MAGIC. eseb's own compression only
+// applies to encrypted chunks, so cleartext ones so tagged are uncompressed.
+// zstd streams are prefixed with the size of the plaintext as a little-endian
+// u64, which bounds what they may decompress to.
+const CHUNK_ESEB: u8 = 0;
+const CHUNK_UNCOMPRESSED: u8 = 1;
+const CHUNK_ZSTD: u8 = 2;
+
+// Set in the compression byte of chunks whose plaintext, once compressed, is
+// prefixed with its length as a little-endian u64 and padded with zeros.
+const CHUNK_PADDED: u8 = 0x80;
+
+/// How blobs are compressed before they are encrypted. Each chunk records its
+/// compression, so this only affects what is written.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Compression {
+    None,
+
+    // Compression built into eseb's encryption, which leaves cleartext blobs
+    // uncompressed.
+    #[default]
+    Eseb,
+
+    // zstd at the given level.
+    Zstd(i32),
+}
+
+impl Compression {
+    fn tag(&self) -> u8 {
+        match self {
+            Compression::None => CHUNK_UNCOMPRESSED,
+            Compression::Eseb => CHUNK_ESEB,
+            Compression::Zstd(_) => CHUNK_ZSTD,
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    /// Parses "none", "eseb", "zstd" or "zstd:<level>".
+    fn from_str(s: &str) -> Result<Compression> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(Compression::None),
+            None if s == "eseb" => Ok(Compression::Eseb),
+            None if s == "zstd" => Ok(Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)),
+            Some(("zstd", level)) => {
+                let level = level
+                    .parse()
+                    .with_context(|| format!("invalid zstd level {:?}", level))?;
+                if !zstd::compression_level_range().contains(&level) {
+                    anyhow::bail!("zstd level {} is out of range", level);
+                }
+                Ok(Compression::Zstd(level))
+            }
+            _ => anyhow::bail!(
+                "unknown compression {:?}; expected none, eseb, zstd or zstd:<level>",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Eseb => f.write_str("eseb"),
+            Compression::Zstd(level) => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+/// How far encrypted chunks are padded so that their size says less about
+/// their contents. Padding is encrypted along with the chunk and stripped when
+/// it is decoded, and never takes a chunk past the max object size. A blob
+/// split into many chunks still shows roughly how many there are.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Padding {
+    #[default]
+    None,
+
+    // Up to the next power of two.
+    PowerOfTwo,
+
+    // Up to the next multiple of the given number of bytes.
+    Quantum(u64),
+}
+
+impl Padding {
+    fn padded_len(&self, len: u64) -> u64 {
+        match self {
+            Padding::None => len,
+            Padding::PowerOfTwo => len.checked_next_power_of_two().unwrap_or(len),
+            Padding::Quantum(quantum) => len.div_ceil(*quantum).saturating_mul(*quantum),
+        }
+    }
+}
+
+impl std::str::FromStr for Padding {
+    type Err = anyhow::Error;
+
+    /// Parses "none", "pow2" or "quantum:<bytes>".
+    fn from_str(s: &str) -> Result<Padding> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(Padding::None),
+            None if s == "pow2" => Ok(Padding::PowerOfTwo),
+            Some(("quantum", bytes)) => {
+                let bytes = bytes
+                    .parse()
+                    .with_context(|| format!("invalid padding quantum {:?}", bytes))?;
+                if bytes == 0 {
+                    anyhow::bail!("padding quantum must be > 0");
+                }
+                Ok(Padding::Quantum(bytes))
+            }
+            _ => anyhow::bail!(
+                "unknown padding {:?}; expected none, pow2 or quantum:<bytes>",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Padding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Padding::None => f.write_str("none"),
+            Padding::PowerOfTwo => f.write_str("pow2"),
+            Padding::Quantum(bytes) => write!(f, "quantum:{}", bytes),
+        }
+    }
+}
+
+/// How a blob is split into the chunks it is stored as.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Layout {
+    // One stream, compressed and encrypted by eseb as a whole and split at
+    // fixed offsets. The only layout clients from before framing can read.
+    // Compression and padding don't apply.
+    Stream,
+
+    // Framed chunks split at fixed offsets, for blobs such as packs that are
+    // new each time and so have no chunks to reuse.
+    Fixed,
+
+    // Framed chunks split at content-defined boundaries, so that a blob
+    // rewritten with small changes reuses most of its chunks.
+    #[default]
+    ContentDefined,
+}
+
+/// How blobs are written. Each chunk records how it was framed and compressed,
+/// so the decoder needs none of it, but clients that don't know about framing
+/// can only read blobs laid out as one stream.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct EncodeOptions {
+    pub compression: Compression,
+    pub padding: Padding,
+    pub layout: Layout,
+}
+
+impl EncodeOptions {
+    /// The options for `compression`, if configured, and `padding`. eseb
+    /// compresses as it encrypts, after padding would be applied, so padding
+    /// defaults to zstd and refuses eseb.
+    pub fn new(compression: Option<Compression>, padding: Padding) -> Result<EncodeOptions> {
+        let compression = match (compression, padding) {
+            (Some(Compression::Eseb), Padding::PowerOfTwo | Padding::Quantum(..)) => {
+                anyhow::bail!("padding needs compression none or zstd, not eseb")
+            }
+            (Some(compression), _) => compression,
+            (None, Padding::None) => Compression::default(),
+            (None, Padding::PowerOfTwo | Padding::Quantum(..)) => {
+                Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)
+            }
+        };
+        Ok(EncodeOptions {
+            compression,
+            padding,
+            ..Default::default()
+        })
+    }
+
+    /// The options for a blob listed in one in `format`, which is None if
+    /// unversioned. Only a versioned header can tell older clients that the
+    /// blobs it lists are framed, so the others are written as one stream.
+    pub fn listed_in(self, format: Option<&FormatHeader>) -> EncodeOptions {
+        match format {
+            Some(..) => self,
+            None => EncodeOptions {
+                layout: Layout::Stream,
+                ..self
+            },
+        }
+    }
+
+    /// As listed_in, also noting in `format` the chunk features of what is
+    /// written with the options returned, so that the blob knows to announce
+    /// them.
+    pub fn list_in(self, format: &mut Option<FormatHeader>) -> EncodeOptions {
+        let options = self.listed_in(format.as_ref());
+        crate::format::note_chunk_features(format, options.chunk_features());
+        options
+    }
+
+    /// The read features needed by a blob listing those written with these
+    /// options.
+    pub fn chunk_features(&self) -> u64 {
+        if self.layout == Layout::Stream {
+            return 0;
+        }
+        // Uncompressed chunks are tagged as such unless eseb's compression was
+        // asked for, which leaves them as clients that only frame read them.
+        // Padded chunks are never eseb's, as for_chunk has it.
+        let padding = match self.padding {
+            Padding::None => 0,
+            Padding::PowerOfTwo | Padding::Quantum(..) => {
+                crate::format::FEATURE_CHUNK_COMPRESSION | crate::format::FEATURE_CHUNK_PADDING
+            }
+        };
+        let compression = match self.compression {
+            Compression::Eseb => 0,
+            Compression::None | Compression::Zstd(..) => crate::format::FEATURE_CHUNK_COMPRESSION,
+        };
+        crate::format::FEATURE_CHUNK_FRAMING | compression | padding
+    }
+
+    // The compression and padding a chunk is written with. Padding only hides
+    // anything under encryption, and must follow compression, which eseb only
+    // does as it encrypts. new never pairs the two, but should they be, padded
+    // chunks use zstd instead.
+    fn for_chunk(&self, encrypted: bool) -> (Compression, Padding) {
+        match (encrypted, self.compression) {
+            (false, compression) => (compression, Padding::None),
+            (true, Compression::Eseb) if self.padding != Padding::None => (
+                Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL),
+                self.padding,
+            ),
+            (true, compression) => (compression, self.padding),
+        }
+    }
+}
+
+// How the keys of a store are kept in the chunk cache.
+trait ChunkKey: Sized {
+    fn to_cache(&self) -> String;
+    fn from_cache(s: &str) -> Option<Self>;
+}
+
+impl ChunkKey for ObjectId {
+    fn to_cache(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_cache(s: &str) -> Option<ObjectId> {
+        ObjectId::from_hex(s.as_bytes()).ok()
+    }
+}
+
+impl ChunkKey for String {
+    fn to_cache(&self) -> String {
+        self.clone()
+    }
+
+    fn from_cache(s: &str) -> Option<String> {
+        Some(s.to_string())
+    }
+}
+
+// Splits what is written into framed chunks of at most `max_size` bytes,
+// encoding each on its own and putting it in `store`. Encryption is
+// randomized, so an unchanged chunk would still be stored anew were it not
+// found in `cache` by its plaintext.
+struct SplitWriter<'a, K> {
     fd: std::fs::File,
+    encoded: std::fs::File,
     _tmp: tempfile::TempDir,
-    repo: Rc<gix::Repository>,
-    chunk_size: usize,
-    current: Option<usize>,
-    oids: Vec<ObjectId>,
+    store: &'a dyn BlobStore<Key = K>,
+    encryption: Option<&'a SymmetricKey>,
+    compression: Compression,
+    padding: Padding,
+    cache: Option<ChunkCache>,
+    chunker: Chunker,
+    hasher: ChunkHasher,
+    keys: Vec<K>,
     disk_buf_bytes: usize,
+    max_size: usize,
 }
 
-impl Write for SplitWriter {
+impl<K: ChunkKey> Write for SplitWriter<'_, K> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut remaining = match self.current.take() {
-            Some(remaining) => remaining,
-            None => self.chunk_size,
-        };
-
-        let n = std::cmp::min(remaining, buf.len());
-        let n = self.fd.write(&buf[..n])?;
+        let boundary = self.chunker.find_boundary(buf);
+        let n = boundary.unwrap_or(buf.len());
+        self.fd.write_all(&buf[..n])?;
+        self.hasher.update(&buf[..n]);
         self.disk_buf_bytes += n;
-        remaining -= n;
 
-        if remaining > 0 {
-            self.current = Some(remaining);
-        } else {
+        if boundary.is_some() {
             self.write_one()?;
         }
 
@@ -81,84 +405,391 @@ impl Write for SplitWriter {
     }
 }
 
-impl SplitWriter {
-    fn commit(mut self) -> Result<Vec<ObjectId>> {
-        self.write_one()?;
-        Ok(self.oids)
+impl<'a, K: ChunkKey> SplitWriter<'a, K> {
+    fn commit(mut self) -> Result<Vec<K>> {
+        if self.disk_buf_bytes > 0 || self.keys.is_empty() {
+            self.write_one()?;
+        }
+        Ok(self.keys)
     }
 
     fn write_one(&mut self) -> std::io::Result<()> {
-        self.fd.seek(std::io::SeekFrom::Start(0))?;
-        let oid = self
-            .repo
-            .write_stream(
-                gix::object::Kind::Blob,
-                self.disk_buf_bytes.try_into().expect("u64"),
-                &mut self.fd,
-            )
-            .map_err(|_| std::io::Error::other("create blob from file"))?;
-        self.oids.push(oid);
+        let hasher = std::mem::replace(&mut self.hasher, self.new_hasher());
+        let id = hasher.finalize();
+        let key = match self.cached(&id) {
+            Some(key) => key,
+            None => {
+                let key = self
+                    .put_one()
+                    .map_err(|err| std::io::Error::other(format!("store chunk: {:#}", err)))?;
+                if let Some(cache) = self.cache.as_ref() {
+                    cache.insert(&id, &key.to_cache());
+                }
+                key
+            }
+        };
+        self.keys.push(key);
         self.fd.set_len(0)?;
         self.fd.seek(std::io::SeekFrom::Start(0))?;
         self.disk_buf_bytes = 0;
         Ok(())
     }
 
-    fn new(repo: Rc<gix::Repository>, chunk_size: usize) -> Result<SplitWriter> {
+    // Looks for the chunk with plaintext `id` among those already stored.
+    fn cached(&self, id: &[u8; 32]) -> Option<K> {
+        let key = K::from_cache(&self.cache.as_ref()?.get(id)?)?;
+        self.store.contains(&key).then_some(key)
+    }
+
+    fn put_one(&mut self) -> Result<K> {
+        self.fd.seek(std::io::SeekFrom::Start(0))?;
+        self.encoded.set_len(0)?;
+        self.encoded.seek(std::io::SeekFrom::Start(0))?;
+        self.encoded.write_all(CHUNK_MAGIC)?;
+        let padded = if self.padding == Padding::None {
+            0
+        } else {
+            CHUNK_PADDED
+        };
+        self.encoded.write_all(&[self.compression.tag() | padded])?;
+        let len = self.disk_buf_bytes.try_into().expect("u64");
+        encode_chunk(
+            &mut self.encoded,
+            &mut (&self.fd).take(len),
+            len,
+            self.encryption,
+            self.compression,
+            self.padding,
+            self.max_size,
+        )?;
+        let len = self.encoded.stream_position()?;
+        self.encoded.seek(std::io::SeekFrom::Start(0))?;
+        self.store.put(&mut self.encoded, len)
+    }
+
+    // Padded chunks are cached apart from unpadded ones, lest turning padding
+    // on reuse chunks written without it.
+    fn new_hasher(&self) -> ChunkHasher {
+        ChunkHasher::new(self.encryption, self.padding != Padding::None)
+    }
+
+    fn new(
+        store: &'a dyn BlobStore<Key = K>,
+        encryption: Option<&'a SymmetricKey>,
+        options: EncodeOptions,
+        cache: Option<ChunkCache>,
+        max_size: usize,
+    ) -> Result<SplitWriter<'a, K>> {
+        let (compression, padding) = options.for_chunk(encryption.is_some());
+        let chunker = match options.layout {
+            Layout::ContentDefined => Chunker::new(max_size, chunker_seed(encryption)),
+            Layout::Fixed | Layout::Stream => Chunker::fixed(max_size),
+        };
         let _tmp = tempfile::Builder::new()
             .prefix("recursive_remote")
             .tempdir()
             .context("Unable to create temp dir.")?;
-        let path = _tmp.path().join("blob");
-        Ok(SplitWriter {
-            repo,
-            _tmp,
-            chunk_size,
-            current: None,
-            fd: std::fs::OpenOptions::new()
+        let open = |name: &str| {
+            std::fs::OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
                 .truncate(true)
-                .open(&path)?,
-            oids: Vec::default(),
+                .open(_tmp.path().join(name))
+        };
+        Ok(SplitWriter {
+            fd: open("blob")?,
+            encoded: open("chunk")?,
+            store,
+            encryption,
+            compression,
+            padding,
+            cache,
+            chunker,
+            hasher: ChunkHasher::new(encryption, padding != Padding::None),
+            keys: Vec::default(),
             disk_buf_bytes: 0,
+            max_size,
+            _tmp,
         })
     }
 }
 
+// Encodes one chunk's plaintext of `len` bytes from `reader` to `writer`,
+// compressing, padding and encrypting if need be.
+fn encode_chunk<W: Write, R: Read>(
+    writer: W,
+    reader: &mut R,
+    len: u64,
+    encryption: Option<&SymmetricKey>,
+    compression: Compression,
+    padding: Padding,
+    max_size: usize,
+) -> Result<()> {
+    match encryption {
+        Some(key) => {
+            let writer = IoRecordWriter::new(writer, Format::Record);
+            let mut writer =
+                EncryptingWriter::new(writer, key.clone(), compression == Compression::Eseb)
+                    .context("init encrypting writer")?;
+            if padding == Padding::None {
+                writer = compress_into(writer, reader, len, compression)?;
+            } else {
+                let payload = compress_into(Vec::new(), reader, len, compression)?;
+                write_padded(&mut writer, &payload, padding, max_size)?;
+            }
+            writer.into_inner()?.into_inner();
+        }
+        None => {
+            compress_into(writer, reader, len, compression)?;
+        }
+    }
+    Ok(())
+}
+
+// Writes `payload` prefixed with its length and followed by as many zeros as
+// `padding` calls for, short of exceeding `max_size`.
+fn write_padded<W: Write>(
+    writer: &mut W,
+    payload: &[u8],
+    padding: Padding,
+    max_size: usize,
+) -> Result<()> {
+    let payload_len: u64 = payload.len().try_into().context("chunk size")?;
+    let len = payload_len + 8;
+    let padded_len = std::cmp::max(std::cmp::min(padding.padded_len(len), max_size as u64), len);
+    writer
+        .write_all(&payload_len.to_le_bytes())
+        .context("write chunk")?;
+    writer.write_all(payload).context("write chunk")?;
+    std::io::copy(&mut std::io::repeat(0).take(padded_len - len), writer).context("pad chunk")?;
+    Ok(())
+}
+
+fn compress_into<W: Write, R: Read>(
+    mut writer: W,
+    reader: &mut R,
+    len: u64,
+    compression: Compression,
+) -> Result<W> {
+    match compression {
+        Compression::Zstd(level) => {
+            writer
+                .write_all(&len.to_le_bytes())
+                .context("write chunk")?;
+            let mut encoder = zstd::Encoder::new(writer, level).context("init zstd")?;
+            std::io::copy(reader, &mut encoder).context("write chunk")?;
+            encoder.finish().context("finish zstd")
+        }
+        Compression::None | Compression::Eseb => {
+            std::io::copy(reader, &mut writer).context("write chunk")?;
+            Ok(writer)
+        }
+    }
+}
+
+// Holds encoded chunks in a temp file until we know which store they belong
+// in, keyed by their offset and length.
+struct SpoolStore(RefCell<std::fs::File>);
+
+impl BlobStore for SpoolStore {
+    type Key = (u64, u64);
+
+    fn put(&self, chunk: &mut dyn Read, len: u64) -> Result<(u64, u64)> {
+        let mut fd = self.0.borrow_mut();
+        let offset = fd.seek(std::io::SeekFrom::End(0))?;
+        let n = std::io::copy(chunk, &mut *fd).context("spool chunk")?;
+        if n != len {
+            anyhow::bail!("spooled {} bytes of a {} byte chunk", n, len);
+        }
+        Ok((offset, len))
+    }
+
+    // The chunk is read through its own handle, which shares the offset, so
+    // the spool must not be written to meanwhile.
+    fn get<'a>(&'a self, key: &(u64, u64)) -> Result<Box<dyn Read + 'a>> {
+        let mut fd = self.0.borrow().try_clone().context("read spooled chunk")?;
+        fd.seek(std::io::SeekFrom::Start(key.0))?;
+        Ok(Box::new(fd.take(key.1)))
+    }
+
+    fn contains(&self, key: &(u64, u64)) -> bool {
+        self.0
+            .borrow()
+            .metadata()
+            .is_ok_and(|metadata| key.0 + key.1 <= metadata.len())
+    }
+}
+
+// Spooled chunks are never cached, but are written by the same SplitWriter.
+impl ChunkKey for (u64, u64) {
+    fn to_cache(&self) -> String {
+        format!("{}+{}", self.0, self.1)
+    }
+
+    fn from_cache(s: &str) -> Option<(u64, u64)> {
+        let (offset, len) = s.split_once('+')?;
+        Some((offset.parse().ok()?, len.parse().ok()?))
+    }
+}
+
 pub fn encode<R: BufRead>(
     repo: &Rc<gix::Repository>,
     reader: &mut R,
     encryption: Option<&SymmetricKey>,
     max_object_size: usize,
+    options: EncodeOptions,
 ) -> Result<(BlobRef, usize)> {
-    let mut writer = SplitWriter::new(repo.clone(), max_object_size)?;
-    let (sha256, writer, bytes_copied) = match encryption {
+    encode_with_progress(
+        repo,
+        reader,
+        encryption,
+        max_object_size,
+        options,
+        &mut Progress::disabled(),
+    )
+}
+
+// As encode, reporting the bytes read from `reader` as they are written out.
+pub fn encode_with_progress<R: BufRead>(
+    repo: &Rc<gix::Repository>,
+    reader: &mut R,
+    encryption: Option<&SymmetricKey>,
+    max_object_size: usize,
+    options: EncodeOptions,
+    progress: &mut Progress,
+) -> Result<(BlobRef, usize)> {
+    let store = GitStore(repo);
+    let cache = encryption.map(|_| ChunkCache::for_repo(repo));
+    let (sha256, oids, bytes_copied) = encode_into(
+        &store,
+        cache,
+        reader,
+        encryption,
+        max_object_size,
+        options,
+        progress,
+    )
+    .context("commit blobs")?;
+    Ok((
+        BlobRef {
+            resource_key: ResourceKey::Git(oids),
+            sha256,
+        },
+        bytes_copied,
+    ))
+}
+
+// As encode_with_progress, but stores the chunks in `annex` instead of the
+// tracking repo if the blob comes to at least `threshold` bytes once encoded.
+pub fn encode_to_annex_with_progress<R: BufRead>(
+    repo: &Rc<gix::Repository>,
+    annex: &Annex,
+    threshold: u64,
+    reader: &mut R,
+    encryption: Option<&SymmetricKey>,
+    max_object_size: usize,
+    options: EncodeOptions,
+    progress: &mut Progress,
+) -> Result<(BlobRef, usize)> {
+    let spool = SpoolStore(RefCell::new(
+        tempfile::tempfile().context("Unable to create temp file.")?,
+    ));
+    let (sha256, spooled, bytes_copied) = encode_into(
+        &spool,
+        None,
+        reader,
+        encryption,
+        max_object_size,
+        options,
+        progress,
+    )
+    .context("commit spooled chunks")?;
+    let size: u64 = spooled.iter().map(|(_offset, len)| len).sum();
+
+    let resource_key = if size >= threshold {
+        let mut keys = Vec::with_capacity(spooled.len());
+        for key in spooled.iter() {
+            keys.push(annex.put(&mut spool.get(key)?, key.1)?);
+        }
+        ResourceKey::Annex(keys)
+    } else {
+        let store = GitStore(repo);
+        let mut oids = Vec::with_capacity(spooled.len());
+        for key in spooled.iter() {
+            oids.push(store.put(&mut spool.get(key)?, key.1)?);
+        }
+        ResourceKey::Git(oids)
+    };
+    Ok((
+        BlobRef {
+            resource_key,
+            sha256,
+        },
+        bytes_copied,
+    ))
+}
+
+// Encodes what `reader` reads into chunks put in `store` as `options` lay them
+// out, returning the sha256 of what was read, the keys of the chunks and the
+// number of bytes read.
+fn encode_into<K: ChunkKey, R: BufRead>(
+    store: &dyn BlobStore<Key = K>,
+    cache: Option<ChunkCache>,
+    reader: &mut R,
+    encryption: Option<&SymmetricKey>,
+    max_object_size: usize,
+    options: EncodeOptions,
+    progress: &mut Progress,
+) -> Result<([u8; 32], Vec<K>, usize)> {
+    if options.layout == Layout::Stream {
+        return encode_stream(store, reader, encryption, max_object_size, progress);
+    }
+    let mut writer = SplitWriter::new(store, encryption, options, cache, max_object_size)?;
+    let (sha256, bytes_copied) =
+        copy_and_hash(reader, &mut writer, progress).context("write blob")?;
+    Ok((sha256, writer.commit()?, bytes_copied))
+}
+
+// Encodes what `reader` reads as one stream, encrypting and compressing it
+// with eseb if need be, and puts it in `store` split at fixed offsets.
+fn encode_stream<K, R: BufRead>(
+    store: &dyn BlobStore<Key = K>,
+    reader: &mut R,
+    encryption: Option<&SymmetricKey>,
+    max_object_size: usize,
+    progress: &mut Progress,
+) -> Result<([u8; 32], Vec<K>, usize)> {
+    let fd = tempfile::tempfile().context("Unable to create temp file.")?;
+    let (sha256, mut fd, bytes_copied) = match encryption {
         Some(key) => {
-            let writer = IoRecordWriter::new(writer, Format::Record);
+            let writer = IoRecordWriter::new(fd, Format::Record);
             let mut writer = EncryptingWriter::new(writer, key.clone(), /*compress=*/ true)
                 .context("init encrypting writer")?;
             let (sha256, bytes_copied) =
-                copy_and_hash(reader, &mut writer).context("write blob")?;
-            let writer = writer.into_inner()?.into_inner();
-            (sha256, writer, bytes_copied)
+                copy_and_hash(reader, &mut writer, progress).context("write blob")?;
+            (sha256, writer.into_inner()?.into_inner(), bytes_copied)
         }
         None => {
+            let mut fd = fd;
             let (sha256, bytes_copied) =
-                copy_and_hash(reader, &mut writer).context("write blob")?;
-            (sha256, writer, bytes_copied)
+                copy_and_hash(reader, &mut fd, progress).context("write blob")?;
+            (sha256, fd, bytes_copied)
         }
     };
-    writer.commit().context("commit blobs").map(|oids| {
-        (
-            BlobRef {
-                resource_key: ResourceKey::Git(oids),
-                sha256,
-            },
-            bytes_copied,
-        )
-    })
+    let size = fd.stream_position().context("encoded size")?;
+    fd.rewind().context("rewind encoded blob")?;
+
+    let mut keys = Vec::new();
+    let mut offset = 0;
+    loop {
+        let len = std::cmp::min(size - offset, max_object_size as u64);
+        keys.push(store.put(&mut (&mut fd).take(len), len)?);
+        offset += len;
+        if offset == size {
+            return Ok((sha256, keys, bytes_copied));
+        }
+    }
 }
 
 pub fn decode_state(
@@ -189,33 +820,82 @@ pub fn decode_namespace(
         &Some(source_ref.0.sha256),
     )?;
 
-    let namespace = bincode::deserialize::<SerializedNamespace>(&buf)
-        .context("deserialize namespace.bincode")?;
-    let namespace = (&namespace)
-        .try_into()
-        .context("deserialize namespace.bincode")?;
+    Namespace::from_bytes(&buf).context("deserialize namespace.bincode")
+}
+
+pub fn decode_pack_segment(
+    repo: &Rc<gix::Repository>,
+    source_ref: &SegmentRef,
+    encryption: &EncryptionKeys,
+) -> Result<PackSegment> {
+    let mut buf = Vec::default();
+    unverified::decode(
+        repo,
+        &source_ref.blob_ref.resource_key,
+        &mut buf,
+        encryption.namespace_key(),
+        &Some(source_ref.blob_ref.sha256),
+    )?;
 
-    Ok(namespace)
+    PackSegment::from_bytes(&buf).context("deserialize pack segment")
 }
 
 pub fn decode<O: Write>(
-    repo: &Rc<gix::Repository>,
+    repo: &gix::Repository,
+    source_ref: &BlobRef,
+    writer: O,
+    encryption: Option<&SymmetricKey>,
+) -> Result<(BlobRef, usize)> {
+    decode_with_progress(
+        repo,
+        None,
+        source_ref,
+        writer,
+        encryption,
+        &mut Progress::disabled(),
+    )
+}
+
+// As decode, reporting the bytes written to `writer` as they are decoded, and
+// reading blobs stored out of band from `annex`.
+pub fn decode_with_progress<O: Write>(
+    repo: &gix::Repository,
+    annex: Option<&Annex>,
     source_ref: &BlobRef,
     mut writer: O,
     encryption: Option<&SymmetricKey>,
+    progress: &mut Progress,
 ) -> Result<(BlobRef, usize)> {
-    unverified::decode(
+    unverified::decode_with_progress(
         repo,
+        annex,
         &source_ref.resource_key,
         &mut writer,
         encryption,
         &Some(source_ref.sha256),
+        progress,
     )
 }
 
+/// A blob that decoded to something other than the sha256 recorded for it, as
+/// when it was corrupted or tampered with upstream.
+#[derive(thiserror::Error, Debug)]
+#[error("Expected sha256 {}, got {}", hex::encode(.expected), hex::encode(.actual))]
+pub struct Sha256Mismatch {
+    pub expected: [u8; 32],
+    pub actual: [u8; 32],
+}
+
+/// A blob that didn't decrypt, as when it was encrypted with another key than
+/// ours.
+#[derive(thiserror::Error, Debug)]
+#[error("unable to decrypt")]
+pub struct Undecryptable;
+
 fn copy_and_hash<I: BufRead, O: Write>(
     reader: &mut I,
     writer: &mut O,
+    progress: &mut Progress,
 ) -> Result<([u8; 32], usize)> {
     let mut bytes_copied = 0;
     let mut hasher = sha2::Sha256::default();
@@ -229,6 +909,7 @@ fn copy_and_hash<I: BufRead, O: Write>(
         writer.write_all(buf).context("copy write")?;
         let n = buf.len();
         reader.consume(n);
+        progress.add_bytes(n);
     }
 }
 
@@ -241,89 +922,308 @@ pub mod unverified {
 
     use eseb::DecryptingReader;
 
-    struct SplitReader {
-        oids: Vec<ObjectId>,
-        repo: Rc<gix::Repository>,
-        buf: Vec<u8>,
-        offset: usize,
-    }
-
-    impl BufRead for SplitReader {
-        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
-            let buf = &self.buf[self.offset..];
-            if buf.is_empty()
-                && let Some(oid) = self.oids.pop()
-            {
-                let mut blob = self
-                    .repo
-                    .find_blob(oid)
-                    .map_err(|_| std::io::Error::other("find blob"))?;
-                self.buf = blob.take_data();
-                self.offset = 0;
+    // A chunk as read from its store.
+    type Chunk<'a> = Box<dyn Read + 'a>;
+
+    // Reads the chunks stored under `keys` in turn, as one stream.
+    struct SplitReader<'a, K> {
+        keys: std::vec::IntoIter<K>,
+        store: &'a dyn BlobStore<Key = K>,
+
+        // The chunk being read, if any.
+        current: Option<Chunk<'a>>,
+    }
+
+    impl<K> Read for SplitReader<'_, K> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            loop {
+                if self.current.is_none() {
+                    match self.next_chunk()? {
+                        Some((_key, chunk)) => self.current = Some(chunk),
+                        None => return Ok(0),
+                    }
+                }
+                let chunk = self.current.as_mut().expect("chunk started above");
+                let n = chunk.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                self.current = None;
             }
+        }
+    }
 
-            Ok(&self.buf[self.offset..])
+    impl<'a, K> SplitReader<'a, K> {
+        fn new(store: &'a dyn BlobStore<Key = K>, keys: Vec<K>) -> SplitReader<'a, K> {
+            SplitReader {
+                keys: keys.into_iter(),
+                store,
+                current: None,
+            }
         }
 
-        fn consume(&mut self, amt: usize) {
-            self.offset += amt;
+        // Starts reading the chunk after any being read, returning it along
+        // with its key.
+        fn next_chunk(&mut self) -> std::io::Result<Option<(K, Chunk<'a>)>> {
+            let Some(key) = self.keys.next() else {
+                return Ok(None);
+            };
+            let chunk = self
+                .store
+                .get(&key)
+                .map_err(|err| std::io::Error::other(format!("find chunk: {:#}", err)))?;
+            Ok(Some((key, chunk)))
         }
     }
 
-    impl Read for SplitReader {
-        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-            let data = self.fill_buf()?;
-            let n = std::cmp::min(data.len(), buf.len());
-            buf[..n].copy_from_slice(&data[..n]);
-            self.consume(n);
+    // Reads the start of `chunk`, enough to tell whether it is framed and how.
+    // Chunks written as one stream may be shorter.
+    fn read_frame(chunk: &mut dyn Read) -> std::io::Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(CHUNK_MAGIC.len() + 1);
+        chunk
+            .take(frame.capacity() as u64)
+            .read_to_end(&mut frame)?;
+        Ok(frame)
+    }
+
+    // Decodes the chunks `reader` reads, copying the result to `destination`
+    // and returning its sha256 and size. Where each chunk of an encrypted blob
+    // is stored is noted in `cache`, so that writing it again reuses them.
+    fn decode_chunks<K: ChunkKey, O: Write>(
+        mut reader: SplitReader<'_, K>,
+        destination: &mut O,
+        encryption: Option<&SymmetricKey>,
+        cache: Option<&ChunkCache>,
+        progress: &mut Progress,
+    ) -> Result<([u8; 32], usize)> {
+        let Some((key, mut chunk)) = reader.next_chunk()? else {
+            return decode_stream(std::io::empty(), destination, encryption, progress);
+        };
+        let frame = read_frame(&mut chunk)?;
+        if !frame.starts_with(CHUNK_MAGIC) {
+            let stream = std::io::Cursor::new(frame).chain(chunk).chain(reader);
+            return decode_stream(stream, destination, encryption, progress);
+        }
+
+        let mut output = ChunkOutput {
+            destination,
+            hasher: sha2::Sha256::default(),
+            chunk_hasher: None,
+            size: 0,
+            progress,
+        };
+        let mut next = Some((key, frame, chunk));
+        while let Some((key, frame, mut chunk)) = next {
+            let tag = *frame
+                .strip_prefix(CHUNK_MAGIC)
+                .and_then(|rest| rest.first())
+                .with_context(|| format!("chunk {} is not framed", key.to_cache()))?;
+            output.chunk_hasher =
+                cache.map(|_| ChunkHasher::new(encryption, tag & CHUNK_PADDED != 0));
+            decode_chunk(&mut chunk, encryption, tag, &mut output)
+                .with_context(|| format!("decode chunk {}", key.to_cache()))?;
+
+            // Read to the end, so that the store checks the chunk against its
+            // key before it is cached.
+            std::io::copy(&mut chunk, &mut std::io::sink())
+                .with_context(|| format!("read chunk {}", key.to_cache()))?;
+            if let (Some(cache), Some(chunk_hasher)) = (cache, output.chunk_hasher.take()) {
+                cache.insert(&chunk_hasher.finalize(), &key.to_cache());
+            }
+
+            next = match reader.next_chunk()? {
+                Some((key, mut chunk)) => Some((key, read_frame(&mut chunk)?, chunk)),
+                None => None,
+            };
+        }
+        Ok((output.hasher.finalize().into(), output.size))
+    }
+
+    // Passes decoded chunks on to `destination`, hashing them for the blob's
+    // sha256 and, if need be, the chunk cache.
+    struct ChunkOutput<'a, O> {
+        destination: &'a mut O,
+        hasher: sha2::Sha256,
+        chunk_hasher: Option<ChunkHasher>,
+        size: usize,
+        progress: &'a mut Progress,
+    }
+
+    impl<O: Write> Write for ChunkOutput<'_, O> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = self.destination.write(buf)?;
+            self.hasher.update(&buf[..n]);
+            if let Some(chunk_hasher) = self.chunk_hasher.as_mut() {
+                chunk_hasher.update(&buf[..n]);
+            }
+            self.size += n;
+            self.progress.add_bytes(n);
             Ok(n)
         }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.destination.flush()
+        }
     }
 
-    impl SplitReader {
-        fn new(repo: Rc<gix::Repository>, mut oids: Vec<ObjectId>) -> Result<SplitReader> {
-            oids.reverse();
-            Ok(SplitReader {
-                oids,
-                repo,
-                buf: Vec::default(),
-                offset: 0,
-            })
+    fn decode_chunk<R: Read, W: Write>(
+        body: R,
+        encryption: Option<&SymmetricKey>,
+        tag: u8,
+        output: &mut W,
+    ) -> Result<()> {
+        if ![CHUNK_ESEB, CHUNK_UNCOMPRESSED, CHUNK_ZSTD].contains(&(tag & !CHUNK_PADDED)) {
+            anyhow::bail!("unknown compression {:#x}; upgrade recursive_remote", tag);
+        }
+
+        match encryption {
+            Some(key) => {
+                let crypt_reader = DecryptingReader::new(
+                    IoRecordReader::from_read(body, Format::Record, i32::MAX as usize - 1),
+                    key.clone(),
+                    /*compress=*/ tag & !CHUNK_PADDED == CHUNK_ESEB,
+                )
+                .context(Undecryptable)?;
+                unpad_into(crypt_reader, tag, output).context(Undecryptable)
+            }
+            None => unpad_into(body, tag, output),
         }
     }
 
-    pub fn decode<O: Write>(
-        repo: &Rc<gix::Repository>,
-        resource_key: &ResourceKey,
+    // Decompresses the chunk `reader` reads, less any padding.
+    fn unpad_into<R: Read, W: Write>(mut reader: R, tag: u8, output: &mut W) -> Result<()> {
+        if tag & CHUNK_PADDED == 0 {
+            return decompress_into(reader, tag, output);
+        }
+
+        let mut len = [0; 8];
+        reader
+            .read_exact(&mut len)
+            .context("read padded chunk length")?;
+        let mut payload = (&mut reader).take(u64::from_le_bytes(len));
+        decompress_into(&mut payload, tag & !CHUNK_PADDED, output)?;
+        if payload.limit() > 0 {
+            anyhow::bail!("padded chunk is truncated");
+        }
+        // Reads the padding too, so that all of the chunk is authenticated.
+        std::io::copy(&mut reader, &mut std::io::sink()).context("read padding")?;
+        Ok(())
+    }
+
+    fn decompress_into<R: Read, W: Write>(mut reader: R, tag: u8, output: &mut W) -> Result<()> {
+        if tag == CHUNK_ZSTD {
+            let mut len = [0; 8];
+            reader
+                .read_exact(&mut len)
+                .context("read compressed chunk length")?;
+            let len = u64::from_le_bytes(len);
+
+            // Stops at the declared size, so that a stream that would
+            // decompress to more is caught without decompressing it all.
+            let mut decoder = zstd::Decoder::new(reader).context("init zstd")?;
+            let n = std::io::copy(&mut (&mut decoder).take(len), output).context("decompress")?;
+            if n != len {
+                anyhow::bail!(
+                    "chunk decompressed to {} bytes, not the {} declared",
+                    n,
+                    len
+                );
+            }
+            if decoder.read(&mut [0])? != 0 {
+                anyhow::bail!("chunk decompresses past the {} bytes declared", len);
+            }
+        } else {
+            std::io::copy(&mut reader, output).context("copy chunk")?;
+        }
+        Ok(())
+    }
+
+    // Decrypts if need be a blob written as one stream split at fixed offsets,
+    // copying the result to `destination` and returning its sha256 and size.
+    fn decode_stream<R: Read, O: Write>(
+        reader: R,
         destination: &mut O,
         encryption: Option<&SymmetricKey>,
-        want_sha256: &Option<[u8; 32]>,
-    ) -> Result<(BlobRef, usize)> {
-        let (sha256, size) = match (encryption, resource_key) {
-            (_, ResourceKey::Annex(..)) => panic!("Annex not supported"),
-            (Some(key), ResourceKey::Git(oids)) => {
-                let reader = SplitReader::new(repo.clone(), oids.clone())?;
+        progress: &mut Progress,
+    ) -> Result<([u8; 32], usize)> {
+        match encryption {
+            Some(key) => {
                 let mut crypt_reader = DecryptingReader::new(
                     IoRecordReader::from_read(reader, Format::Record, i32::MAX as usize - 1),
                     key.clone(),
                     /*compress=*/ true,
                 )
-                .context("create DecryptingReader")?;
-                copy_and_hash(&mut crypt_reader, destination)?
+                .context(Undecryptable)?;
+                copy_and_hash(&mut crypt_reader, destination, progress).context(Undecryptable)
             }
-            (None, ResourceKey::Git(oids)) => {
-                let mut reader = SplitReader::new(repo.clone(), oids.clone())?;
-                copy_and_hash(&mut reader, destination)?
+            None => copy_and_hash(&mut std::io::BufReader::new(reader), destination, progress),
+        }
+    }
+
+    pub fn decode<O: Write>(
+        repo: &gix::Repository,
+        resource_key: &ResourceKey,
+        destination: &mut O,
+        encryption: Option<&SymmetricKey>,
+        want_sha256: &Option<[u8; 32]>,
+    ) -> Result<(BlobRef, usize)> {
+        decode_with_progress(
+            repo,
+            None,
+            resource_key,
+            destination,
+            encryption,
+            want_sha256,
+            &mut Progress::disabled(),
+        )
+    }
+
+    pub fn decode_with_progress<O: Write>(
+        repo: &gix::Repository,
+        annex: Option<&Annex>,
+        resource_key: &ResourceKey,
+        destination: &mut O,
+        encryption: Option<&SymmetricKey>,
+        want_sha256: &Option<[u8; 32]>,
+        progress: &mut Progress,
+    ) -> Result<(BlobRef, usize)> {
+        let (sha256, size) = match resource_key {
+            ResourceKey::Annex(keys) => {
+                let annex = annex.with_context(|| {
+                    format!(
+                        "blob {} is stored out of band, but no annex is configured",
+                        resource_key
+                    )
+                })?;
+                decode_chunks(
+                    SplitReader::new(annex, keys.clone()),
+                    destination,
+                    encryption,
+                    None,
+                    progress,
+                )?
+            }
+            ResourceKey::Git(oids) => {
+                let store = GitStore(repo);
+                let cache = encryption.map(|_| ChunkCache::for_repo(repo));
+                decode_chunks(
+                    SplitReader::new(&store, oids.clone()),
+                    destination,
+                    encryption,
+                    cache.as_ref(),
+                    progress,
+                )?
             }
         };
 
         if let Some(want_sha256) = want_sha256
             && *want_sha256 != sha256
         {
-            anyhow::bail!(format!(
-                "Expected sha256 {:?}, got {:?}",
-                want_sha256, sha256
-            ));
+            return Err(Sha256Mismatch {
+                expected: *want_sha256,
+                actual: sha256,
+            }
+            .into());
         }
 
         let object_ref = BlobRef {
@@ -334,7 +1234,7 @@ pub mod unverified {
     }
 
     pub fn decode_unverified_state_from_tree_or_blob_oid(
-        repo: &Rc<gix::Repository>,
+        repo: &gix::Repository,
         tree_or_blob_oid: ObjectId,
         encryption: &EncryptionKeys,
         want_sha256: &Option<[u8; 32]>,
@@ -355,11 +1255,38 @@ pub mod unverified {
             }
         };
 
-        decode_unverified_state(repo, &ResourceKey::Git(blobs), encryption, want_sha256)
+        // state.bincode holds either the state or, if it is framed, a pointer
+        // to it.
+        let mut buf = Vec::default();
+        let (blob_ref, _size) = decode(
+            repo,
+            &ResourceKey::Git(blobs),
+            &mut buf,
+            encryption.state_key(),
+            want_sha256,
+        )?;
+        match StatePointer::from_bytes(&buf).context("deserialize state.bincode")? {
+            // States that list this one need to announce how it was chunked,
+            // which only the pointer knows, so the state takes that on.
+            Some(pointer) => {
+                let (state_ref, mut state) = decode_unverified_state(
+                    repo,
+                    &pointer.state.0.resource_key,
+                    encryption,
+                    &Some(pointer.state.0.sha256),
+                )?;
+                crate::format::note_chunk_features(&mut state.format, pointer.chunk_features);
+                Ok((state_ref, state))
+            }
+            None => {
+                let state = State::from_bytes(&buf).context("deserialize state.bincode")?;
+                Ok((StateRef(blob_ref), state))
+            }
+        }
     }
 
     pub fn decode_unverified_state(
-        repo: &Rc<gix::Repository>,
+        repo: &gix::Repository,
         resource_key: &ResourceKey,
         encryption: &EncryptionKeys,
         want_sha256: &Option<[u8; 32]>,
@@ -373,9 +1300,7 @@ pub mod unverified {
             want_sha256,
         )?;
 
-        let state =
-            bincode::deserialize::<SerializedState>(&buf).context("deserialize state.bincode")?;
-        let state = (&state).try_into().context("deserialize state.bincode")?;
+        let state = State::from_bytes(&buf).context("deserialize state.bincode")?;
 
         Ok((StateRef(state_ref), state))
     }
@@ -408,7 +1333,8 @@ mod tests {
         let payload = vec![0xAB; 4096];
 
         let mut reader = Cursor::new(payload.clone());
-        let (source_ref, written) = encode(&repo, &mut reader, None, 128).expect("encode");
+        let (source_ref, written) =
+            encode(&repo, &mut reader, None, 128, EncodeOptions::default()).expect("encode");
         assert_eq!(written, payload.len());
         assert!(source_ref.oids().len() > 1);
 
@@ -426,7 +1352,14 @@ mod tests {
         let key = eseb::SymmetricKey::gen_key().expect("key gen");
 
         let mut reader = Cursor::new(payload.clone());
-        let (source_ref, written) = encode(&repo, &mut reader, Some(&key), 128).expect("encode");
+        let (source_ref, written) = encode(
+            &repo,
+            &mut reader,
+            Some(&key),
+            128,
+            EncodeOptions::default(),
+        )
+        .expect("encode");
         assert_eq!(written, payload.len());
         assert!(source_ref.oids().len() > 1);
 
@@ -437,16 +1370,546 @@ mod tests {
         assert_eq!(payload, out);
     }
 
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut state = 1u64;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reencoding_grown_blob_reuses_chunks() {
+        let (_dir, repo) = init_bare_repo();
+        let key = eseb::SymmetricKey::gen_key().expect("key gen");
+        let payload = pseudo_random(64 * 1024);
+        let mut grown = b"a few more refs".to_vec();
+        grown.extend_from_slice(&payload);
+
+        let (first, _written) = encode(
+            &repo,
+            &mut Cursor::new(payload.clone()),
+            Some(&key),
+            4096,
+            EncodeOptions::default(),
+        )
+        .expect("encode");
+        let (second, _written) = encode(
+            &repo,
+            &mut Cursor::new(grown.clone()),
+            Some(&key),
+            4096,
+            EncodeOptions::default(),
+        )
+        .expect("encode");
+        let shared = second
+            .oids()
+            .iter()
+            .filter(|oid| first.oids().contains(oid))
+            .count();
+        assert!(first.oids().len() > 4);
+        assert!(shared * 2 >= first.oids().len());
+
+        let mut out = Vec::new();
+        decode(&repo, &second, &mut out, Some(&key)).expect("decode");
+        assert_eq!(grown, out);
+    }
+
+    #[test]
+    fn each_compression_roundtrips() {
+        let (_dir, repo) = init_bare_repo();
+        let key = eseb::SymmetricKey::gen_key().expect("key gen");
+        let payload = b"The quick brown fox jumps over the lazy dog".repeat(200);
+
+        for compression in [Compression::None, Compression::Eseb, Compression::Zstd(3)] {
+            for encryption in [None, Some(&key)] {
+                let (source_ref, _written) = encode(
+                    &repo,
+                    &mut Cursor::new(payload.clone()),
+                    encryption,
+                    1 << 20,
+                    EncodeOptions {
+                        compression,
+                        ..Default::default()
+                    },
+                )
+                .expect("encode");
+                let mut out = Vec::new();
+                decode(&repo, &source_ref, &mut out, encryption).expect("decode");
+                assert_eq!(payload, out);
+
+                // Cleartext is only compressed when asked for.
+                if encryption.is_none() {
+                    let store = GitStore(&repo);
+                    let chunk = store.get_all(&source_ref.oids()[0]).expect("chunk");
+                    assert_eq!(
+                        chunk.len() < payload.len(),
+                        compression != Compression::None && compression != Compression::Eseb
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zstd_chunks_decompress_no_further_than_declared() {
+        let (_dir, repo) = init_bare_repo();
+        let store = GitStore(&repo);
+        let payload = vec![0; 1 << 20];
+        let chunk = |declared: u64| {
+            let mut chunk = CHUNK_MAGIC.to_vec();
+            chunk.push(CHUNK_ZSTD);
+            chunk.extend_from_slice(&declared.to_le_bytes());
+            chunk.extend_from_slice(&zstd::encode_all(&payload[..], 3).expect("compress"));
+            let oid = store.put(&mut &chunk[..], chunk.len() as u64).expect("put");
+            BlobRef {
+                resource_key: ResourceKey::Git(vec![oid]),
+                sha256: sha2::Sha256::digest(&payload).into(),
+            }
+        };
+
+        let mut out = Vec::new();
+        decode(&repo, &chunk(1 << 20), &mut out, None).expect("decode");
+        assert_eq!(payload, out);
+
+        let err = decode(&repo, &chunk(1024), Vec::new(), None).expect_err("bomb");
+        assert!(format!("{err:#}").contains("past the 1024 bytes declared"));
+        let err = decode(&repo, &chunk(2 << 20), Vec::new(), None).expect_err("short");
+        assert!(format!("{err:#}").contains("not the 2097152 declared"));
+    }
+
+    #[test]
+    fn compression_parses() {
+        assert_eq!(
+            "none".parse::<Compression>().expect("parse"),
+            Compression::None
+        );
+        assert_eq!(
+            "eseb".parse::<Compression>().expect("parse"),
+            Compression::Eseb
+        );
+        assert_eq!(
+            "zstd".parse::<Compression>().expect("parse"),
+            Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)
+        );
+        assert_eq!(
+            "zstd:19".parse::<Compression>().expect("parse"),
+            Compression::Zstd(19)
+        );
+        assert!("zstd:100".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+
+    // Bytes that no compression will shrink, so that padding decides sizes.
+    fn incompressible(len: usize) -> Vec<u8> {
+        let mut block = [0u8; 32];
+        let mut out = Vec::with_capacity(len + 32);
+        while out.len() < len {
+            block = sha2::Sha256::digest(block).into();
+            out.extend_from_slice(&block);
+        }
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn padding_hides_sizes_and_roundtrips() {
+        let (_dir, repo) = init_bare_repo();
+        let key = eseb::SymmetricKey::gen_key().expect("key gen");
+        let store = GitStore(&repo);
+
+        for padding in [Padding::PowerOfTwo, Padding::Quantum(4096)] {
+            let options = EncodeOptions {
+                padding,
+                ..Default::default()
+            };
+            let mut sizes = Vec::new();
+            for len in [1100, 1500, 2000] {
+                let payload = incompressible(len);
+                let (source_ref, _written) = encode(
+                    &repo,
+                    &mut Cursor::new(payload.clone()),
+                    Some(&key),
+                    1 << 20,
+                    options,
+                )
+                .expect("encode");
+
+                // The ratchet still covers the plaintext alone.
+                assert_eq!(
+                    source_ref.sha256,
+                    <[u8; 32]>::from(sha2::Sha256::digest(&payload))
+                );
+                let mut out = Vec::new();
+                decode(&repo, &source_ref, &mut out, Some(&key)).expect("decode");
+                assert_eq!(payload, out);
+
+                assert_eq!(source_ref.oids().len(), 1);
+                sizes.push(store.get_all(&source_ref.oids()[0]).expect("chunk").len());
+            }
+            assert!(sizes.iter().all(|size| *size == sizes[0]), "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn padding_stops_at_max_object_size() {
+        let (_dir, repo) = init_bare_repo();
+        let key = eseb::SymmetricKey::gen_key().expect("key gen");
+        let payload = incompressible(5000);
+        let options = EncodeOptions {
+            padding: Padding::PowerOfTwo,
+            ..Default::default()
+        };
+        let (source_ref, _written) = encode(
+            &repo,
+            &mut Cursor::new(payload.clone()),
+            Some(&key),
+            3000,
+            options,
+        )
+        .expect("encode");
+        let mut out = Vec::new();
+        decode(&repo, &source_ref, &mut out, Some(&key)).expect("decode");
+        assert_eq!(payload, out);
+
+        let store = GitStore(&repo);
+        for oid in source_ref.oids() {
+            assert!(store.get_all(oid).expect("chunk").len() < 3500);
+        }
+    }
+
+    #[test]
+    fn padding_defaults_to_zstd_and_refuses_eseb() {
+        let options = |compression| EncodeOptions::new(compression, Padding::PowerOfTwo);
+        assert_eq!(
+            options(None).expect("default").compression,
+            Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)
+        );
+        assert_eq!(
+            options(Some(Compression::None)).expect("none").compression,
+            Compression::None
+        );
+        assert!(options(Some(Compression::Eseb)).is_err());
+
+        let unpadded = EncodeOptions::new(None, Padding::None).expect("unpadded");
+        assert_eq!(unpadded, EncodeOptions::default());
+        EncodeOptions::new(Some(Compression::Eseb), Padding::None).expect("eseb");
+    }
+
+    #[test]
+    fn chunk_features_follow_the_options_used() {
+        use crate::format::{
+            FEATURE_CHUNK_COMPRESSION, FEATURE_CHUNK_FRAMING, FEATURE_CHUNK_PADDING,
+        };
+
+        let eseb = EncodeOptions::default();
+        assert_eq!(eseb.listed_in(None).chunk_features(), 0);
+        assert_eq!(eseb.chunk_features(), FEATURE_CHUNK_FRAMING);
+
+        // Padded chunks fall back on zstd even if eseb was asked for.
+        for padding in [Padding::PowerOfTwo, Padding::Quantum(4096)] {
+            let padded = EncodeOptions { padding, ..eseb };
+            assert_eq!(
+                padded.chunk_features(),
+                FEATURE_CHUNK_FRAMING | FEATURE_CHUNK_COMPRESSION | FEATURE_CHUNK_PADDING
+            );
+        }
+        for compression in [Compression::None, Compression::Zstd(3)] {
+            let options = EncodeOptions {
+                compression,
+                ..Default::default()
+            };
+            assert_eq!(
+                options.chunk_features() & FEATURE_CHUNK_COMPRESSION,
+                FEATURE_CHUNK_COMPRESSION
+            );
+        }
+
+        // Only a versioned blob notes what it lists, and keeps what it noted.
+        let mut unversioned = None;
+        eseb.list_in(&mut unversioned);
+        assert_eq!(unversioned, None);
+        let mut format = Some(FormatHeader::current());
+        eseb.list_in(&mut format);
+        assert_eq!(
+            crate::format::chunk_features(format.as_ref()),
+            eseb.chunk_features()
+        );
+        EncodeOptions {
+            compression: Compression::Zstd(3),
+            ..Default::default()
+        }
+        .list_in(&mut format);
+        assert_eq!(
+            crate::format::chunk_features(format.as_ref()) & FEATURE_CHUNK_COMPRESSION,
+            FEATURE_CHUNK_COMPRESSION
+        );
+    }
+
+    #[test]
+    fn padding_parses() {
+        assert_eq!("none".parse::<Padding>().expect("parse"), Padding::None);
+        assert_eq!(
+            "pow2".parse::<Padding>().expect("parse"),
+            Padding::PowerOfTwo
+        );
+        assert_eq!(
+            "quantum:65536".parse::<Padding>().expect("parse"),
+            Padding::Quantum(65536)
+        );
+        assert!("quantum:0".parse::<Padding>().is_err());
+        assert!("random".parse::<Padding>().is_err());
+    }
+
+    #[test]
+    fn decode_reads_blobs_split_at_fixed_offsets() {
+        let (_dir, repo) = init_bare_repo();
+        let key = eseb::SymmetricKey::gen_key().expect("key gen");
+        let payload = b"written before content-defined chunking".repeat(100);
+
+        let writer = IoRecordWriter::new(Vec::new(), Format::Record);
+        let mut writer = EncryptingWriter::new(writer, key.clone(), /*compress=*/ true)
+            .expect("encrypting writer");
+        writer.write_all(&payload).expect("encrypt");
+        let ciphertext = writer.into_inner().expect("flush").into_inner();
+
+        let store = GitStore(&repo);
+        let oids = ciphertext
+            .chunks(128)
+            .map(|chunk| store.put(&mut &chunk[..], chunk.len() as u64).expect("put"))
+            .collect();
+        let source_ref = BlobRef {
+            resource_key: ResourceKey::Git(oids),
+            sha256: sha2::Sha256::digest(&payload).into(),
+        };
+
+        let mut out = Vec::new();
+        decode(&repo, &source_ref, &mut out, Some(&key)).expect("decode");
+        assert_eq!(payload, out);
+    }
+
+    #[test]
+    fn blobs_listed_in_unversioned_ones_are_written_as_one_stream() {
+        let (_dir, repo) = init_bare_repo();
+        let key = eseb::SymmetricKey::gen_key().expect("key gen");
+        let payload = b"read by clients from before framing".repeat(100);
+        let options = EncodeOptions {
+            compression: Compression::Zstd(3),
+            padding: Padding::PowerOfTwo,
+            ..Default::default()
+        };
+        let is_framed = |source_ref: &BlobRef| {
+            let chunk = GitStore(&repo)
+                .get_all(&source_ref.oids()[0])
+                .expect("chunk");
+            chunk.starts_with(CHUNK_MAGIC)
+        };
+
+        let (source_ref, _written) = encode(
+            &repo,
+            &mut Cursor::new(payload.clone()),
+            Some(&key),
+            128,
+            options.listed_in(None),
+        )
+        .expect("encode");
+        assert!(source_ref.oids().len() > 1);
+        assert!(!is_framed(&source_ref));
+        let mut out = Vec::new();
+        decode(&repo, &source_ref, &mut out, Some(&key)).expect("decode");
+        assert_eq!(payload, out);
+
+        let (source_ref, _written) = encode(
+            &repo,
+            &mut Cursor::new(payload.clone()),
+            Some(&key),
+            128,
+            options.listed_in(Some(&FormatHeader::current())),
+        )
+        .expect("encode");
+        assert!(is_framed(&source_ref));
+
+        // Nothing lists state.bincode with a header, so a pointer is never
+        // framed.
+        let keys = EncryptionKeys {
+            inner: Some(EncryptionKeysInner {
+                state_key: key.clone(),
+                namespace_key: key.clone(),
+            }),
+        };
+        let pointer = StatePointer {
+            state: StateRef(source_ref),
+            chunk_features: crate::format::CHUNK_FEATURES,
+        };
+        let pointer_ref =
+            encode_state_pointer(&repo, &pointer, &keys, 128).expect("encode pointer");
+        assert!(!is_framed(&pointer_ref));
+    }
+
+    #[test]
+    fn state_bincode_may_point_at_a_framed_state() {
+        let (_dir, repo) = init_bare_repo();
+        let key = eseb::SymmetricKey::gen_key().expect("key gen");
+        let keys = EncryptionKeys {
+            inner: Some(EncryptionKeysInner {
+                state_key: key.clone(),
+                namespace_key: key,
+            }),
+        };
+        let state = State {
+            namespaces: HashMap::from([(
+                "ns".to_string(),
+                NamespaceRef(BlobRef {
+                    resource_key: ResourceKey::Git(vec![oid(
+                        "1111111111111111111111111111111111111111",
+                    )]),
+                    sha256: [1; 32],
+                }),
+            )]),
+            ..State::default()
+        };
+        let state_ref = StateRef(
+            encode_state(&repo, &state, &keys, 64, EncodeOptions::default()).expect("encode state"),
+        );
+        let pointer = StatePointer {
+            state: state_ref.clone(),
+            chunk_features: crate::format::CHUNK_FEATURES,
+        };
+        let pointer_ref =
+            encode_state_pointer(&repo, &pointer, &keys, 1 << 20).expect("encode pointer");
+
+        // The state read through the pointer is known by its own ref, as
+        // its children list it, and passes on how it was chunked to them.
+        let (decoded_ref, decoded) = unverified::decode_unverified_state_from_tree_or_blob_oid(
+            &repo,
+            pointer_ref.oids()[0],
+            &keys,
+            &None,
+        )
+        .expect("decode through pointer");
+        assert_eq!(decoded_ref, state_ref);
+        assert!(decoded.namespaces == state.namespaces);
+        assert_eq!(
+            crate::format::chunk_features(decoded.format.as_ref()),
+            crate::format::CHUNK_FEATURES
+        );
+
+        // A state written as state.bincode itself is read as before.
+        let stream_ref = encode_state(
+            &repo,
+            &state,
+            &keys,
+            1 << 20,
+            EncodeOptions::default().listed_in(None),
+        )
+        .expect("encode state");
+        let (decoded_ref, decoded) = unverified::decode_unverified_state_from_tree_or_blob_oid(
+            &repo,
+            stream_ref.oids()[0],
+            &keys,
+            &None,
+        )
+        .expect("decode state");
+        assert_eq!(decoded_ref, StateRef(stream_ref));
+        assert!(decoded == state);
+    }
+
+    #[test]
+    fn fixed_layout_ignores_content() {
+        let (_dir, repo) = init_bare_repo();
+        let payload = pseudo_random(10_000);
+        let options = EncodeOptions {
+            compression: Compression::None,
+            layout: Layout::Fixed,
+            ..Default::default()
+        };
+        let (source_ref, _written) = encode(
+            &repo,
+            &mut Cursor::new(payload.clone()),
+            None,
+            4096,
+            options,
+        )
+        .expect("encode");
+        let store = GitStore(&repo);
+        let sizes: Vec<_> = source_ref
+            .oids()
+            .iter()
+            .map(|oid| store.get_all(oid).expect("chunk").len() - CHUNK_MAGIC.len() - 1)
+            .collect();
+        assert_eq!(sizes, vec![4096, 4096, 1808]);
+    }
+
+    #[test]
+    fn encode_to_annex_keeps_large_blobs_out_of_band() {
+        let (dir, repo) = init_bare_repo();
+        let annex = crate::blob_store::AnnexDir::new(dir.path().join("annex"));
+        let key = eseb::SymmetricKey::gen_key().expect("key gen");
+        let payload = b"The quick brown fox jumps over the lazy dog".repeat(200);
+
+        let (large_ref, _written) = encode_to_annex_with_progress(
+            &repo,
+            &annex,
+            128,
+            &mut Cursor::new(payload.clone()),
+            Some(&key),
+            128,
+            EncodeOptions::default(),
+            &mut Progress::disabled(),
+        )
+        .expect("encode large");
+        match &large_ref.resource_key {
+            ResourceKey::Annex(keys) => assert!(keys.len() > 1),
+            key => panic!("expected an annex key, got {}", key),
+        }
+
+        let (small_ref, _written) = encode_to_annex_with_progress(
+            &repo,
+            &annex,
+            1 << 20,
+            &mut Cursor::new(payload.clone()),
+            Some(&key),
+            128,
+            EncodeOptions::default(),
+            &mut Progress::disabled(),
+        )
+        .expect("encode small");
+        assert!(small_ref.oids().len() > 1);
+
+        for source_ref in [&large_ref, &small_ref] {
+            let mut out = Vec::new();
+            decode_with_progress(
+                &repo,
+                Some(&annex as &Annex),
+                source_ref,
+                &mut out,
+                Some(&key),
+                &mut Progress::disabled(),
+            )
+            .expect("decode");
+            assert_eq!(payload, out);
+        }
+
+        let err = decode(&repo, &large_ref, Vec::new(), Some(&key)).expect_err("no annex");
+        assert!(format!("{err}").contains("stored out of band"));
+    }
+
     #[test]
     fn decode_rejects_sha256_mismatch() {
         let (_dir, repo) = init_bare_repo();
         let payload = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
         let mut reader = Cursor::new(payload);
-        let (mut source_ref, _written) = encode(&repo, &mut reader, None, 64).expect("encode");
+        let (mut source_ref, _written) =
+            encode(&repo, &mut reader, None, 64, EncodeOptions::default()).expect("encode");
         source_ref.sha256 = [0; 32];
 
         let err = decode(&repo, &source_ref, Vec::new(), None).expect_err("must fail");
         assert!(format!("{err}").contains("Expected sha256"));
+        assert!(err.downcast_ref::<Sha256Mismatch>().is_some());
     }
 
     #[test]
@@ -461,9 +1924,16 @@ mod tests {
             )]),
             pack: None,
             random_name: [2; 20],
+            push_options: Vec::new(),
+            shallow_basis: HashMap::new(),
+            manifest: None,
+            audit: None,
+            format: None,
         };
-        let namespace_ref =
-            NamespaceRef(encode_namespace(&repo, &namespace, &keys, 64).expect("encode namespace"));
+        let namespace_ref = NamespaceRef(
+            encode_namespace(&repo, &namespace, &keys, 64, EncodeOptions::default())
+                .expect("encode namespace"),
+        );
         let namespace_roundtrip =
             decode_namespace(&repo, &namespace_ref, &keys).expect("decode namespace");
         assert!(namespace_roundtrip == namespace);
@@ -471,8 +1941,19 @@ mod tests {
         let state = State {
             namespaces: HashMap::from([("ns".to_string(), namespace_ref)]),
             parents: Vec::new(),
+            supersedes: None,
+            format: None,
         };
-        let state_ref = StateRef(encode_state(&repo, &state, &keys, 64).expect("encode state"));
+        let state_ref = StateRef(
+            encode_state(
+                &repo,
+                &state,
+                &keys,
+                64,
+                EncodeOptions::default().listed_in(None),
+            )
+            .expect("encode state"),
+        );
         let state_roundtrip = decode_state(&repo, &state_ref, &keys).expect("decode state");
         assert!(state_roundtrip == state);
     }
@@ -486,6 +1967,10 @@ mod tests {
                 namespace_key: eseb::SymmetricKey::gen_key().expect("namespace key"),
             }),
         };
+        let framed = FormatHeader {
+            read_features: crate::format::CHUNK_FEATURES,
+            ..FormatHeader::current()
+        };
 
         let namespace = Namespace {
             refs: HashMap::from([(
@@ -494,9 +1979,16 @@ mod tests {
             )]),
             pack: None,
             random_name: [4; 20],
+            push_options: vec!["ticket ABC-1".to_string()],
+            shallow_basis: HashMap::new(),
+            manifest: None,
+            audit: None,
+            format: Some(framed),
         };
-        let namespace_ref =
-            NamespaceRef(encode_namespace(&repo, &namespace, &keys, 64).expect("encode namespace"));
+        let namespace_ref = NamespaceRef(
+            encode_namespace(&repo, &namespace, &keys, 64, EncodeOptions::default())
+                .expect("encode namespace"),
+        );
         let namespace_roundtrip =
             decode_namespace(&repo, &namespace_ref, &keys).expect("decode namespace");
         assert!(namespace_roundtrip == namespace);
@@ -504,8 +1996,12 @@ mod tests {
         let state = State {
             namespaces: HashMap::from([("encrypted".to_string(), namespace_ref)]),
             parents: Vec::new(),
+            supersedes: None,
+            format: Some(framed),
         };
-        let state_ref = StateRef(encode_state(&repo, &state, &keys, 64).expect("encode state"));
+        let state_ref = StateRef(
+            encode_state(&repo, &state, &keys, 64, EncodeOptions::default()).expect("encode state"),
+        );
         let state_roundtrip = decode_state(&repo, &state_ref, &keys).expect("decode state");
         assert!(state_roundtrip == state);
     }
@@ -517,7 +2013,14 @@ mod tests {
         let payload = b"encrypted payload".repeat(64);
 
         let mut reader = Cursor::new(payload);
-        let (source_ref, _written) = encode(&repo, &mut reader, Some(&key), 128).expect("encode");
+        let (source_ref, _written) = encode(
+            &repo,
+            &mut reader,
+            Some(&key),
+            128,
+            EncodeOptions::default(),
+        )
+        .expect("encode");
 
         let err = decode(&repo, &source_ref, Vec::new(), None).expect_err("must fail");
         let msg = format!("{err}");
@@ -537,10 +2040,16 @@ mod tests {
         let payload = b"encrypted payload".repeat(64);
 
         let mut reader = Cursor::new(payload);
-        let (source_ref, _written) = encode(&repo, &mut reader, Some(&key), 128).expect("encode");
+        let (source_ref, _written) = encode(
+            &repo,
+            &mut reader,
+            Some(&key),
+            128,
+            EncodeOptions::default(),
+        )
+        .expect("encode");
 
         let err = decode(&repo, &source_ref, Vec::new(), Some(&wrong_key)).expect_err("must fail");
-        let msg = format!("{err}");
-        assert!(!msg.is_empty());
+        assert!(err.downcast_ref::<Undecryptable>().is_some(), "{err:#}");
     }
 }
@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+
+// Marks a blob that starts with a format header. Unversioned blobs start with a
+// little endian u64 count of namespaces or refs, which is never this large.
+const MAGIC: &[u8; 8] = b"rrformat";
+
+/// The newest layout of state.bincode and namespace.bincode this client reads
+/// and writes.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The namespace keeps a manifest of all its packs. It is both a read and a
+/// write feature: packs may be listed only there, as after a rename, so a
+/// client that doesn't know about it would miss them when reading and drop the
+/// manifest when writing.
+pub const FEATURE_PACK_MANIFEST: u64 = 1 << 0;
+
+/// The state begins a new epoch, superseding the chain of states before it
+/// rather than descending from it. A client that doesn't know about epochs
+/// would take it for an unrelated history.
+pub const FEATURE_STATE_EPOCH: u64 = 1 << 1;
+
+/// Some of the packs listed are kept in an annex rather than the tracking
+/// repo. A client that doesn't know about annexes couldn't fetch them.
+pub const FEATURE_ANNEX: u64 = 1 << 2;
+
+/// The blobs listed may be split into framed chunks, each encoded on its own,
+/// rather than being one stream split at fixed offsets. A client that doesn't
+/// know about framing couldn't decode them.
+pub const FEATURE_CHUNK_FRAMING: u64 = 1 << 3;
+
+/// Framed chunks may be compressed otherwise than by eseb, as each names in
+/// its frame. A client that only knows about framing couldn't decompress them.
+pub const FEATURE_CHUNK_COMPRESSION: u64 = 1 << 4;
+
+/// Framed chunks may be padded, as each says in its frame. A client that
+/// doesn't know about padding couldn't strip it.
+pub const FEATURE_CHUNK_PADDING: u64 = 1 << 5;
+
+/// The blob is state.bincode, and only points at the state, which is kept in
+/// framed chunks as the blobs it lists are. A client that doesn't know about
+/// this would take the pointer for the state.
+pub const FEATURE_STATE_POINTER: u64 = 1 << 6;
+
+/// The features that say how the blobs listed are chunked. Blobs written with
+/// them stay listed, so a blob keeps those of the one it replaces.
+pub const CHUNK_FEATURES: u64 =
+    FEATURE_CHUNK_FRAMING | FEATURE_CHUNK_COMPRESSION | FEATURE_CHUNK_PADDING;
+
+/// Feature flags this client understands.
+pub const KNOWN_READ_FEATURES: u64 = FEATURE_PACK_MANIFEST
+    | FEATURE_STATE_EPOCH
+    | FEATURE_ANNEX
+    | CHUNK_FEATURES
+    | FEATURE_STATE_POINTER;
+pub const KNOWN_WRITE_FEATURES: u64 = FEATURE_PACK_MANIFEST;
+
+/// Precedes the serialized state or namespace in a versioned blob. The layout
+/// of the header itself never changes, so that any client can read it and
+/// explain why it can't read the rest. Within a version, features may append
+/// fields to the layout.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct FormatHeader {
+    // The layout of what follows.
+    pub version: u32,
+
+    // Features a client must understand to read the blob at all.
+    pub read_features: u64,
+
+    // Features a client must understand to write a blob derived from this one,
+    // since it would otherwise silently drop them.
+    pub write_features: u64,
+}
+
+impl FormatHeader {
+    pub fn current() -> FormatHeader {
+        FormatHeader {
+            version: FORMAT_VERSION,
+            read_features: 0,
+            write_features: 0,
+        }
+    }
+
+    pub fn check_readable(&self) -> Result<()> {
+        if self.version > FORMAT_VERSION {
+            anyhow::bail!(
+                "format version {} is newer than the {} this client understands; upgrade recursive_remote",
+                self.version,
+                FORMAT_VERSION
+            );
+        }
+        let unknown = self.read_features & !KNOWN_READ_FEATURES;
+        if unknown != 0 {
+            anyhow::bail!(
+                "format uses unknown features {:#x}; upgrade recursive_remote",
+                unknown
+            );
+        }
+        Ok(())
+    }
+
+    /// Fails unless this client may replace a blob with this header by one of
+    /// its own.
+    pub fn check_writable(&self) -> Result<()> {
+        self.check_readable()?;
+        let unknown = self.write_features & !KNOWN_WRITE_FEATURES;
+        if unknown != 0 {
+            anyhow::bail!(
+                "refusing to write over format features {:#x} this client doesn't understand; upgrade recursive_remote",
+                unknown
+            );
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for FormatHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "version {} (read features {:#x}, write features {:#x})",
+            self.version, self.read_features, self.write_features
+        )
+    }
+}
+
+/// Describes the format of a blob, which is None if unversioned.
+pub fn describe(format: Option<&FormatHeader>) -> String {
+    match format {
+        Some(format) => format.to_string(),
+        None => "unversioned".to_string(),
+    }
+}
+
+/// The chunk features of the blobs listed in one in `format`.
+pub fn chunk_features(format: Option<&FormatHeader>) -> u64 {
+    format.map_or(0, |format| format.read_features & CHUNK_FEATURES)
+}
+
+/// Notes in `format` that a blob listed in it was written with the chunk
+/// features `features`. An unversioned blob lists no such blobs.
+pub fn note_chunk_features(format: &mut Option<FormatHeader>, features: u64) {
+    if let Some(format) = format {
+        format.read_features |= features & CHUNK_FEATURES;
+    }
+}
+
+/// Serializes `value` in the current format with `read_features` and
+/// `write_features`, or unversioned if `format` is None. `format` is that of
+/// the blob being replaced, which must be writable. Unversioned blobs can't
+/// carry read features.
+pub fn serialize<T: serde::Serialize>(
+    format: Option<&FormatHeader>,
+    read_features: u64,
+    write_features: u64,
+    value: &T,
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(format) = format {
+        format.check_writable()?;
+        let header = FormatHeader {
+            read_features,
+            write_features,
+            ..FormatHeader::current()
+        };
+        buf.extend_from_slice(MAGIC);
+        bincode::serialize_into(&mut buf, &header).context("format header")?;
+    } else if read_features != 0 {
+        anyhow::bail!("features {:#x} need the versioned format", read_features);
+    }
+    bincode::serialize_into(&mut buf, value)?;
+    Ok(buf)
+}
+
+/// Splits off the format header, if any, having checked that this client can
+/// read what follows it.
+pub fn split_header(buf: &[u8]) -> Result<(Option<FormatHeader>, &[u8])> {
+    let Some(rest) = buf.strip_prefix(MAGIC) else {
+        return Ok((None, buf));
+    };
+    let header: FormatHeader = bincode::deserialize(rest).context("format header")?;
+    header.check_readable()?;
+    let len = bincode::serialized_size(&header).context("format header")? as usize;
+    Ok((Some(header), &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unversioned_blobs_pass_through() {
+        let buf = serialize(None, 0, 0, &(3u64, 4u8)).expect("serialize");
+        assert_eq!(buf, bincode::serialize(&(3u64, 4u8)).expect("bincode"));
+        let (header, rest) = split_header(&buf).expect("split");
+        assert_eq!(header, None);
+        assert_eq!(rest, &buf[..]);
+    }
+
+    #[test]
+    fn versioned_blobs_roundtrip() {
+        let buf = serialize(
+            Some(&FormatHeader::current()),
+            0,
+            FEATURE_PACK_MANIFEST,
+            &(3u64, 4u8),
+        )
+        .expect("serialize");
+        let (header, rest) = split_header(&buf).expect("split");
+        assert_eq!(
+            header,
+            Some(FormatHeader {
+                write_features: FEATURE_PACK_MANIFEST,
+                ..FormatHeader::current()
+            })
+        );
+        assert_eq!(
+            bincode::deserialize::<(u64, u8)>(rest).expect("payload"),
+            (3, 4)
+        );
+    }
+
+    #[test]
+    fn newer_formats_are_refused() {
+        let newer = FormatHeader {
+            version: FORMAT_VERSION + 1,
+            ..FormatHeader::current()
+        };
+        let mut buf = MAGIC.to_vec();
+        bincode::serialize_into(&mut buf, &newer).expect("header");
+        let err = split_header(&buf).expect_err("newer version");
+        assert!(format!("{err}").contains("upgrade recursive_remote"));
+
+        let unknown_read = FormatHeader {
+            read_features: 1 << 63,
+            ..FormatHeader::current()
+        };
+        assert!(unknown_read.check_readable().is_err());
+
+        // Unknown write features may be read, but never written over.
+        let unknown_write = FormatHeader {
+            write_features: 1 << 63,
+            ..FormatHeader::current()
+        };
+        unknown_write.check_readable().expect("readable");
+        let err = serialize(Some(&unknown_write), 0, 0, &0u8).expect_err("not writable");
+        assert!(format!("{err}").contains("refusing to write"));
+
+        // Nor may read features be hidden from older clients.
+        assert!(serialize(None, FEATURE_STATE_EPOCH, 0, &0u8).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+
+use crate::config::Config;
+use crate::encoding::{Undecryptable, decode_state};
+use crate::persistence::ref_changes;
+use crate::serialization::*;
+use crate::update::update_branches;
+
+/// The refs in the namespace that one state changed from its parent.
+pub struct RefUpdate {
+    pub state: StateRef,
+    pub audit: Option<AuditRecord>,
+    pub changes: Vec<RefChange>,
+}
+
+/// Lists the updates to refs in the configured namespace, newest first, by
+/// walking back from the current state through its ancestors, and through any
+/// renames of the namespace. Only refs selected by `filter` are listed, if it
+/// is given; see `ref_matches`.
+pub fn ref_history(config: &Config, filter: Option<&str>) -> Result<Vec<RefUpdate>> {
+    let (state_identifier, state, _basis_state, _root_id, _commit_id) =
+        update_branches(config).context("ref history")?;
+    let Some(state_identifier) = state_identifier else {
+        return Ok(Vec::default());
+    };
+
+    let tracking_repo = Rc::new(config.tracking_repo()?);
+    let namespace = state
+        .namespace(&config.namespace, &config.nacl_keys, &tracking_repo)?
+        .map(|namespace| (config.namespace.clone(), namespace));
+
+    let tip = state_identifier.clone();
+    let mut updates = HashMap::new();
+    let mut graph = HashMap::new();
+    let mut seen = HashSet::new();
+    seen.insert(state_identifier.clone());
+    let mut stack = vec![(state_identifier, state, namespace)];
+    while let Some((state_identifier, state, namespace)) = stack.pop() {
+        // The namespace didn't exist yet in this state, nor in its history.
+        let Some((name, namespace)) = namespace else {
+            graph.insert(state_identifier, Vec::default());
+            continue;
+        };
+
+        // The refs carry over into an epoch unchanged, so the history goes on
+        // through the chain it supersedes.
+        let mut parents = Vec::default();
+        for parent in state.parents.iter().chain(state.supersedes.as_ref()) {
+            let parent_state = decode_state(&tracking_repo, parent, &config.nacl_keys)
+                .with_context(|| format!("decode state {}", parent))?;
+
+            // Most states update some other namespace, and share ours with
+            // their parent.
+            let parent_namespace =
+                if parent_state.namespaces.get(&name) == state.namespaces.get(&name) {
+                    Some((name.clone(), namespace.clone()))
+                } else {
+                    match parent_state.namespace(&name, &config.nacl_keys, &tracking_repo)? {
+                        Some(parent_namespace) => Some((name.clone(), parent_namespace)),
+                        None => find_renamed(config, &tracking_repo, &parent_state, &namespace)?,
+                    }
+                };
+            parents.push((parent.clone(), parent_state, parent_namespace));
+        }
+
+        // As with `git log --first-parent`, a merged state is compared with
+        // the state it was pushed on top of.
+        let previous = parents
+            .first()
+            .and_then(|(_, _, namespace)| namespace.as_ref())
+            .map(|(_, namespace)| namespace);
+        let changes: Vec<_> = ref_changes(previous, Some(&namespace))
+            .into_iter()
+            .filter(|change| filter.is_none_or(|filter| ref_matches(&change.name, filter)))
+            .collect();
+        if !changes.is_empty() {
+            updates.insert(
+                state_identifier.clone(),
+                RefUpdate {
+                    state: state_identifier.clone(),
+                    audit: namespace.audit,
+                    changes,
+                },
+            );
+        }
+
+        graph.insert(
+            state_identifier,
+            parents
+                .iter()
+                .map(|(parent, _, _)| parent.clone())
+                .collect(),
+        );
+        for parent in parents.into_iter().rev() {
+            if seen.insert(parent.0.clone()) {
+                stack.push(parent);
+            }
+        }
+    }
+
+    Ok(newest_first(tip, &graph)
+        .into_iter()
+        .filter_map(|state| updates.remove(&state))
+        .collect())
+}
+
+// Orders the states reachable from `tip` in `graph`, which gives the parents of
+// each, so that every state comes before its ancestors. States are taken
+// breadth first once all their children have been, so that across a merge
+// neither side's updates are numbered as older than the other's base.
+fn newest_first<T: Clone + Eq + Hash>(tip: T, graph: &HashMap<T, Vec<T>>) -> Vec<T> {
+    let mut children = HashMap::<&T, usize>::new();
+    for parents in graph.values() {
+        for parent in parents.iter() {
+            *children.entry(parent).or_default() += 1;
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(graph.len());
+    let mut ready = VecDeque::from([tip]);
+    while let Some(state) = ready.pop_front() {
+        for parent in graph.get(&state).into_iter().flatten() {
+            let remaining = children.get_mut(parent).expect("counted above");
+            *remaining -= 1;
+            if *remaining == 0 {
+                ready.push_back(parent.clone());
+            }
+        }
+        ordered.push(state);
+    }
+    ordered
+}
+
+// A renamed namespace keeps its random name, for which its tree is named, so
+// the states from before the rename hold it under another name with the same
+// random name. Namespaces we can't decrypt are someone else's.
+fn find_renamed(
+    config: &Config,
+    tracking_repo: &Rc<gix::Repository>,
+    state: &State,
+    namespace: &Namespace,
+) -> Result<Option<(String, Namespace)>> {
+    for name in state.namespaces.keys() {
+        let candidate = match state.namespace(name, &config.nacl_keys, tracking_repo) {
+            Ok(candidate) => candidate,
+            Err(err) if err.downcast_ref::<Undecryptable>().is_some() => continue,
+            Err(err) => return Err(err),
+        };
+        if let Some(candidate) =
+            candidate.filter(|candidate| candidate.random_name == namespace.random_name)
+        {
+            return Ok(Some((name.clone(), candidate)));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether `filter` selects the ref `name`. Filters are full ref names, prefixes
+/// ending in a slash such as `refs/tags/`, or short branch and tag names.
+pub fn ref_matches(name: &str, filter: &str) -> bool {
+    name == filter
+        || (filter.ends_with('/') && name.starts_with(filter))
+        || ["refs/heads/", "refs/tags/"]
+            .iter()
+            .any(|prefix| name.strip_prefix(prefix) == Some(filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_states_come_before_their_common_base() {
+        // tip merges a and b, which both descend from base. Walking depth first
+        // would list base before b.
+        let graph = HashMap::from([
+            ("tip", vec!["a", "b"]),
+            ("a", vec!["base"]),
+            ("b", vec!["c"]),
+            ("c", vec!["base"]),
+            ("base", vec![]),
+        ]);
+        let ordered = newest_first("tip", &graph);
+        assert_eq!(ordered.len(), 5);
+        let position = |state| ordered.iter().position(|s| *s == state).expect("listed");
+        for (state, parents) in graph.iter() {
+            for parent in parents {
+                assert!(position(*state) < position(*parent), "{ordered:?}");
+            }
+        }
+        assert_eq!(ordered.last(), Some(&"base"));
+    }
+
+    #[test]
+    fn filters_select_refs_by_name_prefix_or_short_name() {
+        assert!(ref_matches("refs/heads/main", "refs/heads/main"));
+        assert!(ref_matches("refs/heads/main", "main"));
+        assert!(ref_matches("refs/tags/v1", "v1"));
+        assert!(ref_matches("refs/tags/v1", "refs/tags/"));
+        assert!(ref_matches("HEAD", "HEAD"));
+
+        assert!(!ref_matches("refs/heads/main", "mai"));
+        assert!(!ref_matches("refs/heads/mainline", "refs/heads/main"));
+        assert!(!ref_matches("refs/heads/main", "refs/tags/"));
+        assert!(!ref_matches("refs/remotes/origin/main", "main"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+
+use crate::blob_store::parse_content_key;
+use crate::cmd_fetch::ordered_pack_list;
+use crate::config::{Config, EncryptionKeys, EncryptionKeysInner};
+use crate::encoding::Undecryptable;
+use crate::progress::Progress;
+use crate::serialization::*;
+use crate::update::update_branches;
+
+/// Which of our keys, if any, decrypts a namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// The keys configured for the remote, or none if it is in cleartext.
+    Configured,
+    /// The key at this position in the keyring.
+    Keyring(usize),
+    Unreadable,
+    /// Reading it failed other than by our keys not decrypting it, as when it
+    /// is corrupt or missing.
+    Broken,
+}
+
+/// What we can tell of a namespace on the branch. Of one that none of our keys
+/// decrypt, only the name is known.
+pub struct NamespaceSummary {
+    pub name: String,
+    pub access: Access,
+    pub refs: Option<usize>,
+    pub packs: Option<usize>,
+    // The bytes its packs take up upstream, as stored.
+    pub size: Option<u64>,
+}
+
+/// Summarizes each namespace on the branch, in order by name, trying the
+/// configured keys and then each of `keyring` as the namespace key. Namespaces
+/// none of them decrypt are listed as unreadable, and those that fail to read
+/// otherwise as broken, rather than failing.
+pub fn list_namespaces(
+    config: &Config,
+    keyring: &[eseb::SymmetricKey],
+) -> Result<Vec<NamespaceSummary>> {
+    let (state_identifier, state, _basis_state, _root_id, _commit_id) =
+        update_branches(config).context("list namespaces")?;
+    let tracking_repo = Rc::new(config.tracking_repo()?);
+
+    // Keys go in pairs, but only the state key reads the history of states, so
+    // it is the configured one for each. A branch without one is in cleartext
+    // throughout.
+    let keyring: Vec<_> = match config.nacl_keys.state_key() {
+        Some(state_key) => keyring
+            .iter()
+            .map(|namespace_key| EncryptionKeys {
+                inner: Some(EncryptionKeysInner {
+                    state_key: state_key.clone(),
+                    namespace_key: namespace_key.clone(),
+                }),
+            })
+            .collect(),
+        None => Vec::default(),
+    };
+
+    let mut names: Vec<_> = state.namespaces.keys().collect();
+    names.sort();
+    let mut summaries = Vec::default();
+    for name in names {
+        let keys_to_try = std::iter::once((Access::Configured, &config.nacl_keys)).chain(
+            keyring
+                .iter()
+                .enumerate()
+                .map(|(i, keys)| (Access::Keyring(i), keys)),
+        );
+        // Only a key that doesn't decrypt the namespace is reason to try the
+        // next. Any other failure would be the same under every key.
+        let mut readable = Err(Access::Unreadable);
+        for (access, keys) in keys_to_try {
+            match state.namespace(name, keys, &tracking_repo) {
+                Ok(namespace) => {
+                    readable = namespace
+                        .map(|namespace| (access, keys, namespace))
+                        .ok_or(Access::Unreadable);
+                    break;
+                }
+                Err(err) if err.downcast_ref::<Undecryptable>().is_some() => {
+                    log::debug!("{:?} can't decrypt namespace {}: {:#}", access, name, err);
+                }
+                Err(err) => {
+                    log::warn!("Unable to read namespace {}: {:#}", name, err);
+                    readable = Err(Access::Broken);
+                    break;
+                }
+            }
+        }
+        let (access, keys, namespace) = match readable {
+            Ok(readable) => readable,
+            Err(access) => {
+                summaries.push(NamespaceSummary {
+                    name: name.clone(),
+                    access,
+                    refs: None,
+                    packs: None,
+                    size: None,
+                });
+                continue;
+            }
+        };
+
+        // Older states may hold the namespace under a key we no longer have,
+        // which leaves its packs uncounted but the namespace still listed.
+        let packs = ordered_pack_list(
+            keys,
+            &tracking_repo,
+            name,
+            state_identifier.as_ref(),
+            &state,
+            None,
+            &mut Progress::disabled(),
+        )
+        .and_then(|packs| {
+            let mut size = 0;
+            for pack in packs.iter() {
+                size += stored_size(&tracking_repo, &pack.blob_ref)?;
+            }
+            Ok((packs.len(), size))
+        });
+        let (packs, size) = match packs {
+            Ok((packs, size)) => (Some(packs), Some(size)),
+            Err(err) => {
+                log::warn!("Unable to list the packs of namespace {}: {:#}", name, err);
+                (None, None)
+            }
+        };
+        summaries.push(NamespaceSummary {
+            name: name.clone(),
+            access,
+            refs: Some(namespace.refs.len()),
+            packs,
+            size,
+        });
+    }
+    Ok(summaries)
+}
+
+// The size of a blob as stored, over all its chunks. Annexed chunks are named
+// by their size, so needn't be fetched.
+fn stored_size(tracking_repo: &gix::Repository, blob_ref: &BlobRef) -> Result<u64> {
+    let mut size = 0;
+    match &blob_ref.resource_key {
+        ResourceKey::Git(oids) => {
+            for oid in oids.iter() {
+                size += tracking_repo
+                    .find_header(*oid)
+                    .with_context(|| format!("find blob {}", oid))?
+                    .size();
+            }
+        }
+        ResourceKey::Annex(keys) => {
+            for key in keys {
+                size += parse_content_key(key)?.0;
+            }
+        }
+    }
+    Ok(size)
+}
@@ -1,9 +1,19 @@
+pub mod blob_store;
+pub mod chunker;
 pub mod cmd_fetch;
 pub mod cmd_push;
 pub mod config;
 pub mod embedded_config;
 pub mod encoding;
+pub mod format;
+pub mod history;
+pub mod inventory;
+pub mod manifest;
+pub mod options;
 pub mod persistence;
+pub mod progress;
+pub mod s3;
 pub mod serialization;
 pub mod update;
 pub mod util;
+pub mod verify;
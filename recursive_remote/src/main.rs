@@ -7,6 +7,7 @@ use clap::App;
 use log::{error, info, trace};
 
 use recursive_remote::config::*;
+use recursive_remote::options::Options;
 use recursive_remote::serialization::{Namespace, Ref};
 use recursive_remote::update::*;
 use recursive_remote::util::*;
@@ -19,6 +20,7 @@ enum ProtocolCommand {
     List,
     Push,
     Fetch,
+    Option,
     Ignore,
 }
 
@@ -33,6 +35,8 @@ fn parse_protocol_command(line: &str) -> ProtocolCommand {
         ProtocolCommand::Push
     } else if command.starts_with("fetch") {
         ProtocolCommand::Fetch
+    } else if command == "option" {
+        ProtocolCommand::Option
     } else {
         ProtocolCommand::Ignore
     }
@@ -65,10 +69,29 @@ where
 }
 
 fn handle_capabilities() {
-    println!("push\nfetch\n");
+    println!("option\npush\nfetch\nobject-format\ncheck-connectivity\n");
 }
 
-fn handle_list(config: &Config) -> Result<()> {
+fn handle_option(options: &mut Options, line: &str) {
+    let mut tok = line.splitn(3, ' ').skip(1);
+    let name = tok.next().unwrap_or_default();
+    let value = tok.next().unwrap_or_default();
+    let response = options.set(name, value);
+    trace!("option {} {:?}: {}", name, value, &response);
+    if name == "verbosity" {
+        apply_log_level(options);
+    }
+    println!("{}", response);
+}
+
+// An explicit RUST_LOG always wins over what git asks for.
+fn apply_log_level(options: &Options) {
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(options.log_level_filter());
+    }
+}
+
+fn handle_list(config: &Config, options: &Options) -> Result<()> {
     let (_, state, _basis_state, _root_id, _commit_id) = update_branches(config)?;
     let namespace = state
         .namespace(
@@ -77,23 +100,67 @@ fn handle_list(config: &Config) -> Result<()> {
             &Rc::new(config.tracking_repo()?),
         )?
         .unwrap_or_else(Namespace::new);
+    if options.object_format {
+        // An empty namespace can take whatever the user repo uses.
+        let object_hash = match namespace.object_hash() {
+            Some(object_hash) => object_hash,
+            None => config.user_repo()?.object_hash(),
+        };
+        println!(":object-format {}", object_format_name(object_hash));
+    }
+
+    // Don't create the all objects repo here: while cloning, the user repo
+    // does not yet have the object format we are about to advertise.
+    let all_objects_ever_repo = if config.all_objects_ever_repo_path.exists() {
+        Some(config.all_objects_ever_repo()?)
+    } else {
+        None
+    };
     let mut advertised = std::collections::BTreeMap::new();
     for (name, target) in namespace.refs.iter() {
         let advertised_name = to_advertised_ref_name(name, &config.remote_name);
         let advertised_target = to_advertised_target(target, &config.remote_name);
+        let peeled = match (target, all_objects_ever_repo.as_ref()) {
+            (Ref::Direct(oid), Some(repo)) => {
+                peel_tag(repo, *oid).with_context(|| format!("peel {}", &name))?
+            }
+            _ => None,
+        };
         trace!(
-            "\t{} -> {} (advertise as {})",
-            &name, &advertised_target, &advertised_name
+            "\t{} -> {} (advertise as {}, peeled {:?})",
+            &name, &advertised_target, &advertised_name, &peeled
         );
-        advertised.insert(advertised_name, advertised_target);
+        advertised.insert(advertised_name, (advertised_target, peeled));
     }
-    for (name, target) in advertised {
+    for (name, (target, peeled)) in advertised {
         println!("{target} {name}");
+        // Git expects the peeled line to directly follow the tag itself.
+        if let Some(peeled) = peeled {
+            println!("{peeled} {name}^{{}}");
+        }
     }
     println!();
     Ok(())
 }
 
+// Peels an annotated tag to the object it ultimately refers to, as git servers
+// advertise. Objects we have never fetched can't be peeled; on a fresh clone
+// that is all of them, and git copes without the peeled values.
+fn peel_tag(repo: &gix::Repository, oid: gix_hash::ObjectId) -> Result<Option<gix_hash::ObjectId>> {
+    match repo
+        .try_find_object(oid)
+        .with_context(|| format!("find object {}", oid))?
+    {
+        Some(object) if object.kind == gix_object::Kind::Tag => {
+            let peeled = object
+                .peel_tags_to_end()
+                .with_context(|| format!("peel tag {}", oid))?;
+            Ok(Some(peeled.id))
+        }
+        _ => Ok(None),
+    }
+}
+
 fn to_advertised_ref_name(name: &str, remote_name: &str) -> String {
     match remotes_to_heads_ref(name, remote_name) {
         Some(short) => format!("refs/heads/{short}"),
@@ -104,9 +171,10 @@ fn to_advertised_ref_name(name: &str, remote_name: &str) -> String {
 fn to_advertised_target(target: &Ref, remote_name: &str) -> String {
     match target {
         Ref::Direct(oid) => oid.to_string(),
+        // Git expects symrefs such as HEAD in the form `@<target> <name>`.
         Ref::Symbolic(symbolic, _) => match remotes_to_heads_ref(symbolic, remote_name) {
-            Some(short) => format!("refs/heads/{short}"),
-            None => symbolic.to_string(),
+            Some(short) => format!("@refs/heads/{short}"),
+            None => format!("@{symbolic}"),
         },
     }
 }
@@ -116,23 +184,28 @@ fn remotes_to_heads_ref<'a>(name: &'a str, remote_name: &str) -> Option<&'a str>
     name.strip_prefix(&prefix)
 }
 
-fn handle_push<I>(config: &Config, lines: &mut I, line: String) -> Result<()>
+fn handle_push<I>(config: &Config, options: &Options, lines: &mut I, line: String) -> Result<()>
 where
     I: Iterator<Item = Result<String, std::io::Error>>,
 {
     let pushes = collect_lines(lines, "push", Some(line)).context("push collect")?;
-    recursive_remote::cmd_push::push(config, &pushes).context("Failed to push.")
+    recursive_remote::cmd_push::push(config, options, &pushes).context("Failed to push.")
 }
 
-fn handle_fetch<I>(config: &Config, lines: &mut I, line: String) -> Result<()>
+fn handle_fetch<I>(config: &Config, options: &Options, lines: &mut I, line: String) -> Result<()>
 where
     I: Iterator<Item = Result<String, std::io::Error>>,
 {
     let fetches = collect_lines(lines, "fetch", Some(line)).context("fetch collect")?;
-    recursive_remote::cmd_fetch::fetch(config, &fetches).context("Failed to fetch.")
+    recursive_remote::cmd_fetch::fetch(config, options, &fetches).context("Failed to fetch.")
 }
 
-fn dispatch_protocol_command<I>(config: &Config, lines: &mut I, line: String) -> Result<()>
+fn dispatch_protocol_command<I>(
+    config: &Config,
+    options: &mut Options,
+    lines: &mut I,
+    line: String,
+) -> Result<()>
 where
     I: Iterator<Item = Result<String, std::io::Error>>,
 {
@@ -141,9 +214,13 @@ where
             handle_capabilities();
             Ok(())
         }
-        ProtocolCommand::List => handle_list(config),
-        ProtocolCommand::Push => handle_push(config, lines, line),
-        ProtocolCommand::Fetch => handle_fetch(config, lines, line),
+        ProtocolCommand::List => handle_list(config, options),
+        ProtocolCommand::Push => handle_push(config, options, lines, line),
+        ProtocolCommand::Fetch => handle_fetch(config, options, lines, line),
+        ProtocolCommand::Option => {
+            handle_option(options, &line);
+            Ok(())
+        }
         ProtocolCommand::Ignore => Ok(()),
     }
 }
@@ -226,6 +303,13 @@ fn initialize_state_repo(args: Args) -> Result<Config> {
 
         let t2 = scope.spawn(|| {
             report_error((|| {
+                // The repo is created on first use rather than here, since
+                // while cloning git only settles on the user repo's object
+                // format after listing refs.
+                if !args.all_objects_ever_repo_path.exists() {
+                    return Ok(());
+                }
+
                 git_gc_auto_with_config(&mut args.all_objects_ever_repo()?)?;
 
                 // Clean up .keep files. In theory we no longer need to create
@@ -244,6 +328,7 @@ fn initialize_state_repo(args: Args) -> Result<Config> {
 
 fn main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+    apply_log_level(&Options::default());
 
     let pkg_name = env!("CARGO_PKG_NAME");
     let about = "A git special remote that permits using a git branch as an upstream for one or more repositories.";
@@ -262,6 +347,22 @@ fn main() -> Result<()> {
         .arg_from_usage("-c, --configuration 'Prints configuration information/examples.'")
         .arg_from_usage("-g, --generate-configuration 'Prints an example config for embedding.'")
         .arg_from_usage("-d, --debug 'Dumps tracking repository state.'")
+        .arg_from_usage("-H, --set-head=[ref] 'Points the namespace HEAD at [ref], such as refs/heads/main, so that clones check it out.'")
+        .arg(
+            clap::Arg::from_usage("-L, --ref-history=[ref] 'Prints the history of each ref in the namespace, newest first, as of each state that changed it. With =[ref], only of that ref, such as main, refs/heads/main or refs/tags/.'")
+                .min_values(0)
+                .require_equals(true),
+        )
+        .arg_from_usage("--list-namespaces 'Lists each namespace on the branch with its ref count, pack count and the size of its packs, and whether our keys decrypt it. Given only an upstream URL in place of the remote, lists its branch without a configured remote.'")
+        .arg_from_usage("--keyring=[file] 'With --list-namespaces, also tries the namespace keys in [file], one per line, given inline or as file://path.'")
+        .arg_from_usage("--branch=[branch] 'With --list-namespaces and an upstream URL, the branch to list, main by default.'")
+        .arg_from_usage("--state-key=[file] 'With --list-namespaces and an upstream URL, the state key file of an encrypted branch.'")
+        .arg_from_usage("--namespace-key=[file] 'With --state-key, the namespace key file to try first.'")
+        .arg_from_usage("--verify 'Checks every state, namespace and pack in the history of the branch against its sha256, indexes and verifies the packs, and checks that every ref is reachable from them, printing each broken link.'")
+        .arg_from_usage("--delete-namespace 'Removes the namespace from the branch. Its packs are left only in the history of the branch.'")
+        .arg_from_usage("--rename-namespace=[name] 'Moves the namespace to [name], keeping its refs and packs. Clients must then be configured with the new name.'")
+        .arg_from_usage("--gc 'Begins a new epoch holding a single pack for each namespace our keys can read, superseding the chain of states before it. Clients then need nothing from before the epoch.'")
+        .arg_from_usage("-M, --migrate 'Rewrites the branch state and namespace in the current format. Clients older than the format can no longer read them.'")
         .arg_from_usage("-e, --embed-configuration=[config] 'Encodes the recursive remote options under the [remote] section in git config file [config] into a format that can be used in place of the remote spec for git clone, etc. Use -g for an example.'")
         .arg_from_usage("-p, --parse-configuration=[config] 'Parses the encoded configuration [config] and prints the corresponding git config.'")
         .arg_from_usage("[remote_name_passed_from_git]")
@@ -321,11 +422,62 @@ fn main() -> Result<()> {
             matches.get_one::<String>("remote_name_passed_from_git"),
             matches.get_one::<String>("remote_spec_passed_from_git"),
         ) {
-            (Some(remote_name), Some(remote_spec)) => git_special_remote_main(
-                remote_name.as_ref(),
-                remote_spec.as_ref(),
-                matches.contains_id("debug"),
-            ),
+            (Some(remote_name), Some(remote_spec)) => {
+                let admin = if matches.contains_id("debug") {
+                    Some(AdminCommand::DebugDump)
+                } else if matches.contains_id("migrate") {
+                    Some(AdminCommand::Migrate)
+                } else if matches.contains_id("list-namespaces") {
+                    Some(AdminCommand::ListNamespaces(
+                        matches.get_one::<String>("keyring").map(PathBuf::from),
+                    ))
+                } else if matches.contains_id("verify") {
+                    Some(AdminCommand::Verify)
+                } else if matches.contains_id("delete-namespace") {
+                    Some(AdminCommand::DeleteNamespace)
+                } else if let Some(name) = matches.get_one::<String>("rename-namespace") {
+                    Some(AdminCommand::RenameNamespace(name.to_string()))
+                } else if matches.contains_id("gc") {
+                    Some(AdminCommand::Gc)
+                } else if matches.contains_id("ref-history") {
+                    Some(AdminCommand::RefHistory(
+                        matches.get_one::<String>("ref-history").cloned(),
+                    ))
+                } else {
+                    matches
+                        .get_one::<String>("set-head")
+                        .map(|target| AdminCommand::SetHead(target.to_string()))
+                };
+                git_special_remote_main(
+                    Args::new(remote_name, remote_spec).context("parse Args")?,
+                    admin,
+                )
+            }
+            (Some(url), None) if matches.contains_id("list-namespaces") => {
+                let keys = match (
+                    matches.get_one::<String>("state-key"),
+                    matches.get_one::<String>("namespace-key"),
+                ) {
+                    (Some(state_key), Some(namespace_key)) => {
+                        Some((state_key.as_str(), namespace_key.as_str()))
+                    }
+                    (None, None) => None,
+                    _ => anyhow::bail!(
+                        "Both or neither of --state-key and --namespace-key must be given."
+                    ),
+                };
+                let url = url.strip_prefix("recursive::").unwrap_or(url);
+                let branch = matches
+                    .get_one::<String>("branch")
+                    .map_or("main", String::as_str);
+                let (args, _scratch) = Args::scratch(url, branch, keys)?;
+                git_special_remote_main(
+                    args,
+                    Some(AdminCommand::ListNamespaces(
+                        matches.get_one::<String>("keyring").map(PathBuf::from),
+                    )),
+                )
+            }
             _ => {
                 app.print_help().ok();
                 std::process::exit(1);
@@ -336,6 +488,10 @@ fn main() -> Result<()> {
 
 fn do_debug_dump(config: &Config) -> Result<()> {
     let tracking_repo = Rc::new(config.tracking_repo().context("open tracking repo")?);
+    // Packs are indexed into the all objects repo below, so it must exist.
+    config
+        .all_objects_ever_repo()
+        .context("open all objects repo")?;
     let (_commit_oid, (state_identifier, state), _root_oid) =
         match resolve_state_ref(&tracking_repo, &config.nacl_keys, &config.tracking_ref)
             .context("get state oid for tracking ref")?
@@ -346,10 +502,17 @@ fn do_debug_dump(config: &Config) -> Result<()> {
             }
         };
 
-    eprint!("State: {}\n\tParents:", &state_identifier);
+    eprintln!("State: {}", &state_identifier);
+    eprint!(
+        "\tFormat: {}\n\tParents:",
+        recursive_remote::format::describe(state.format.as_ref())
+    );
     for parent in state.parents.iter() {
         eprint!(" {}", &parent);
     }
+    if let Some(supersedes) = state.supersedes.as_ref() {
+        eprint!("\n\tSupersedes: {}", &supersedes);
+    }
     eprintln!("\n\tNamespaces:");
     for (name, namespace) in state.namespaces.iter() {
         eprintln!("\t\t{} -> {}", &name, &namespace);
@@ -363,10 +526,39 @@ fn do_debug_dump(config: &Config) -> Result<()> {
             .namespace(name, &config.nacl_keys, &tracking_repo)
             .with_context(|| format!("decode namespace {}", name))?
             .expect("");
+        eprintln!(
+            "\tFormat: {}",
+            recursive_remote::format::describe(ns.format.as_ref())
+        );
         match ns.pack.as_ref() {
             None => eprintln!("\t<no pack>"),
             Some(pack) => eprintln!("\tPack: {}", &pack),
         }
+        match ns.manifest.as_ref() {
+            None => eprintln!("\t<no pack manifest>"),
+            Some(manifest) => {
+                eprintln!(
+                    "\tPack manifest: {} packs, {} since the last segment",
+                    manifest.count,
+                    manifest.recent.len()
+                );
+                if let Some(history) = manifest.history.as_ref() {
+                    eprintln!("\tPacks before the manifest from: {}", history);
+                }
+            }
+        }
+        if let Some(audit) = ns.audit.as_ref() {
+            eprintln!("\tAudit: {}", audit);
+            for change in audit.ref_changes.iter() {
+                eprintln!("\t\t{}", change);
+            }
+        }
+        for push_option in ns.push_options.iter() {
+            eprintln!("\tPush option: {}", push_option);
+        }
+        for (name, basis) in ns.shallow_basis.iter() {
+            eprintln!("\tShallow basis: {} -> {}", name, basis);
+        }
         eprintln!("\tRefs:");
         let mut ref_targets = std::collections::HashSet::new();
         for (name, target) in ns.refs.iter() {
@@ -382,11 +574,13 @@ fn do_debug_dump(config: &Config) -> Result<()> {
     for name in state.namespaces.keys() {
         eprintln!("History for Namespace {}", name);
         let ordered_packs = recursive_remote::cmd_fetch::materialize_ordered_pack_list(
-            config,
+            &config.nacl_keys,
             &tracking_repo,
+            name,
             Some(&state_identifier),
             &state,
             None,
+            &mut recursive_remote::progress::Progress::disabled(),
         )?;
         let mut commits_in_pack = std::collections::HashMap::new();
         let mut all_commits = std::collections::HashSet::new();
@@ -396,6 +590,7 @@ fn do_debug_dump(config: &Config) -> Result<()> {
                 config,
                 &tracking_repo,
                 pack_name.clone(),
+                &mut recursive_remote::progress::Progress::disabled(),
             )? {
                 Some(git_pack_name) => {
                     let git_pack_name = hex::encode(git_pack_name);
@@ -488,9 +683,122 @@ fn do_debug_dump(config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn git_special_remote_main(remote_name: &str, remote_spec: &str, debug_dump: bool) -> Result<()> {
-    let args = Args::new(remote_name, remote_spec).context("parse Args")?;
+// Prints each ref's values in the manner of `git reflog`, numbering them per ref
+// from the newest.
+fn do_ref_history(config: &Config, filter: Option<&str>) -> Result<()> {
+    let updates = recursive_remote::history::ref_history(config, filter)
+        .context("Failed to read the ref history.")?;
+    let mut counts = std::collections::HashMap::new();
+    for update in updates.iter() {
+        for change in update.changes.iter() {
+            let count = counts.entry(&change.name).or_insert(0);
+            let new = match change.new.as_ref() {
+                Some(new) => new.to_string(),
+                None => "(deleted)".to_string(),
+            };
+            let old = match change.old.as_ref() {
+                Some(old) => old.to_string(),
+                None => "(none)".to_string(),
+            };
+            print!(
+                "{}@{{{}}}: {} (was {}) in state {}",
+                &change.name, count, new, old, &update.state
+            );
+            match update.audit.as_ref() {
+                Some(audit) => println!(" by {}", audit),
+                None => println!(),
+            }
+            *count += 1;
+        }
+    }
+    Ok(())
+}
+
+// Prints a line for each namespace on the branch. Those we can't decrypt are
+// listed by name alone.
+fn do_list_namespaces(config: &Config, keyring: Option<&std::path::Path>) -> Result<()> {
+    let keyring = match keyring {
+        Some(path) => recursive_remote::config::read_keyring(path)?,
+        None => Vec::default(),
+    };
+    let summaries = recursive_remote::inventory::list_namespaces(config, &keyring)
+        .context("Failed to list namespaces.")?;
+    for summary in summaries.iter() {
+        let access = match summary.access {
+            recursive_remote::inventory::Access::Configured
+                if config.nacl_keys.namespace_key().is_none() =>
+            {
+                "in cleartext".to_string()
+            }
+            recursive_remote::inventory::Access::Configured => {
+                "decryptable with the configured key".to_string()
+            }
+            recursive_remote::inventory::Access::Keyring(i) => {
+                format!("decryptable with keyring key {}", i + 1)
+            }
+            recursive_remote::inventory::Access::Unreadable => {
+                println!("{}: not decryptable", &summary.name);
+                continue;
+            }
+            recursive_remote::inventory::Access::Broken => {
+                println!("{}: broken", &summary.name);
+                continue;
+            }
+        };
+        println!(
+            "{}: {} refs, {} packs, {} bytes, {}",
+            &summary.name,
+            or_unknown(summary.refs),
+            or_unknown(summary.packs),
+            or_unknown(summary.size),
+            access
+        );
+    }
+    Ok(())
+}
+
+// Prints each broken link to stdout, and fails if there are any.
+fn do_verify(config: &Config) -> Result<()> {
+    let report = recursive_remote::verify::verify(config).context("Failed to verify.")?;
+    for link in report.broken.iter() {
+        println!("broken: {}: {:#}", &link.what, &link.error);
+    }
+    for name in report.unreadable.iter() {
+        eprintln!(
+            "Namespace {} is not decryptable, so was not verified.",
+            name
+        );
+    }
+    eprintln!(
+        "Verified {} states, {} namespace versions and {} packs.",
+        report.states, report.namespaces, report.packs
+    );
+    if !report.broken.is_empty() {
+        anyhow::bail!("Found {} broken link(s).", report.broken.len());
+    }
+    Ok(())
+}
+
+fn or_unknown<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "?".to_string(), |value| value.to_string())
+}
+
+// Commands run against a remote from the command line instead of the remote
+// helper protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AdminCommand {
+    DebugDump,
+    SetHead(String),
+    Migrate,
+    ListNamespaces(Option<PathBuf>),
+    Verify,
+    DeleteNamespace,
+    RenameNamespace(String),
+    Gc,
+    RefHistory(Option<String>),
+}
 
+fn git_special_remote_main(args: Args, admin: Option<AdminCommand>) -> Result<()> {
     std::fs::create_dir_all(&args.state_path).context("create state repo dir")?;
     std::fs::create_dir_all(&args.lock_path).context("create locks dir")?;
 
@@ -512,10 +820,37 @@ fn git_special_remote_main(remote_name: &str, remote_spec: &str, debug_dump: boo
         (config, (state_repo_lock, lock))
     };
 
-    if debug_dump {
-        return do_debug_dump(&config);
+    match admin {
+        Some(AdminCommand::DebugDump) => return do_debug_dump(&config),
+        Some(AdminCommand::SetHead(target)) => {
+            return recursive_remote::cmd_push::set_head(&config, &target)
+                .with_context(|| format!("Failed to set HEAD to {}.", &target));
+        }
+        Some(AdminCommand::Migrate) => {
+            return recursive_remote::cmd_push::migrate(&config).context("Failed to migrate.");
+        }
+        Some(AdminCommand::ListNamespaces(keyring)) => {
+            return do_list_namespaces(&config, keyring.as_deref());
+        }
+        Some(AdminCommand::Verify) => return do_verify(&config),
+        Some(AdminCommand::DeleteNamespace) => {
+            return recursive_remote::cmd_push::delete_namespace(&config)
+                .with_context(|| format!("Failed to delete namespace {}.", &config.namespace));
+        }
+        Some(AdminCommand::RenameNamespace(name)) => {
+            return recursive_remote::cmd_push::rename_namespace(&config, &name)
+                .with_context(|| format!("Failed to rename namespace to {}.", &name));
+        }
+        Some(AdminCommand::Gc) => {
+            return recursive_remote::cmd_push::gc(&config).context("Failed to collect garbage.");
+        }
+        Some(AdminCommand::RefHistory(filter)) => {
+            return do_ref_history(&config, filter.as_deref());
+        }
+        None => {}
     }
 
+    let mut options = Options::default();
     let lines = std::io::stdin();
     let mut lines = lines.lock().lines();
 
@@ -523,7 +858,7 @@ fn git_special_remote_main(remote_name: &str, remote_spec: &str, debug_dump: boo
         match lines.next() {
             Some(Ok(line)) => {
                 trace!("Received command: {:?}", &line);
-                dispatch_protocol_command(&config, &mut lines, line)?;
+                dispatch_protocol_command(&config, &mut options, &mut lines, line)?;
             }
             None => break,
             Some(Err(e)) => panic!("Error: {:?}", &e),
@@ -612,6 +947,10 @@ mod tests {
             parse_protocol_command("fetch deadbeef refs/heads/main"),
             ProtocolCommand::Fetch
         );
+        assert_eq!(
+            parse_protocol_command("option verbosity 1"),
+            ProtocolCommand::Option
+        );
         assert_eq!(
             parse_protocol_command("unknown whatever"),
             ProtocolCommand::Ignore
@@ -644,7 +983,14 @@ mod tests {
                 &Ref::Symbolic("refs/remotes/origin/main".to_string(), None),
                 "origin"
             ),
-            "refs/heads/main"
+            "@refs/heads/main"
+        );
+        assert_eq!(
+            to_advertised_target(
+                &Ref::Symbolic("refs/heads/trunk".to_string(), None),
+                "origin"
+            ),
+            "@refs/heads/trunk"
         );
         assert_eq!(
             to_advertised_target(&Ref::Direct(oid), "origin"),
@@ -0,0 +1,179 @@
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+use rand::Rng;
+
+use crate::config::EncryptionKeys;
+use crate::encoding::{EncodeOptions, decode_pack_segment, encode_pack_segment};
+use crate::format::FormatHeader;
+use crate::serialization::*;
+
+/// How many packs are gathered into each segment of a manifest. A fresh clone
+/// reads one segment per this many pushes, rather than one state per push.
+pub const SEGMENT_LEN: usize = 64;
+
+/// Records a newly pushed pack in the manifest of a namespace in `format`,
+/// writing out a segment once enough have built up.
+pub fn append(
+    repo: &Rc<gix::Repository>,
+    manifest: &mut PackManifest,
+    format: &mut Option<FormatHeader>,
+    pack: PackRef,
+    encryption: &EncryptionKeys,
+    max_object_size: usize,
+    encode_options: EncodeOptions,
+) -> Result<()> {
+    manifest.recent.push(pack);
+    manifest.count += 1;
+    if manifest.recent.len() < SEGMENT_LEN {
+        return Ok(());
+    }
+
+    let segment = PackSegment {
+        start: manifest.count - manifest.recent.len() as u64,
+        packs: std::mem::take(&mut manifest.recent),
+        previous: manifest.segment.take(),
+        // The namespace lists whatever the segment does, so knows how it was
+        // chunked.
+        chunk_features: crate::format::chunk_features(format.as_ref()),
+    };
+    let encode_options = encode_options.list_in(format);
+    let blob_ref = encode_pack_segment(repo, &segment, encryption, max_object_size, encode_options)
+        .context("encode pack segment")?;
+    manifest.segment = Some(SegmentRef {
+        blob_ref,
+        random_name: rand::thread_rng().r#gen(),
+    });
+    Ok(())
+}
+
+/// The packs at position `since` in the manifest and later, newest first.
+pub fn packs_since(
+    repo: &Rc<gix::Repository>,
+    manifest: &PackManifest,
+    since: u64,
+    encryption: &EncryptionKeys,
+) -> Result<Vec<PackRef>> {
+    let mut packs = Vec::new();
+    let recent_start = manifest
+        .count
+        .checked_sub(manifest.recent.len() as u64)
+        .context("manifest has more recent packs than its count")?;
+    let skip = since.saturating_sub(recent_start) as usize;
+    packs.extend(manifest.recent.iter().skip(skip).rev().cloned());
+
+    let mut next = manifest.segment.clone();
+    let mut end = recent_start;
+    while end > since {
+        let segment_ref = next.context("manifest ends before its first pack")?;
+        let segment = decode_pack_segment(repo, &segment_ref, encryption)
+            .with_context(|| format!("decode pack segment {}", &segment_ref.blob_ref))?;
+        if segment.start + segment.packs.len() as u64 != end {
+            anyhow::bail!("pack segment {} is out of place", &segment_ref.blob_ref);
+        }
+        let skip = since.saturating_sub(segment.start) as usize;
+        packs.extend(segment.packs.iter().skip(skip).rev().cloned());
+        end = segment.start;
+        next = segment.previous;
+    }
+    Ok(packs)
+}
+
+/// The newest pack in the manifest, if any, which reads at most one segment.
+pub fn newest_pack(
+    repo: &Rc<gix::Repository>,
+    manifest: &PackManifest,
+    encryption: &EncryptionKeys,
+) -> Result<Option<PackRef>> {
+    if let Some(pack) = manifest.recent.last() {
+        return Ok(Some(pack.clone()));
+    }
+    let Some(segment_ref) = manifest.segment.as_ref() else {
+        return Ok(None);
+    };
+    let segment = decode_pack_segment(repo, segment_ref, encryption)
+        .with_context(|| format!("decode pack segment {}", &segment_ref.blob_ref))?;
+    Ok(segment.packs.last().cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack(i: u8) -> PackRef {
+        PackRef {
+            blob_ref: BlobRef {
+                resource_key: ResourceKey::Annex(vec![format!("pack-{i}")]),
+                sha256: [i; 32],
+            },
+            random_name: [i; 20],
+        }
+    }
+
+    #[test]
+    fn packs_roll_into_segments_and_read_back_in_order() {
+        let tmp = tempfile::Builder::new()
+            .prefix("manifest-tests")
+            .tempdir()
+            .expect("tempdir");
+        let repo = Rc::new(gix::init_bare(tmp.path().join("repo")).expect("init bare repo"));
+        let keys = EncryptionKeys { inner: None };
+
+        let total = 2 * SEGMENT_LEN + 5;
+        let mut manifest = PackManifest::default();
+        let mut format = Some(FormatHeader::current());
+        for i in 0..total {
+            append(
+                &repo,
+                &mut manifest,
+                &mut format,
+                pack(i as u8),
+                &keys,
+                1 << 20,
+                EncodeOptions::default(),
+            )
+            .expect("append");
+        }
+        assert_eq!(manifest.count, total as u64);
+        assert_eq!(manifest.recent.len(), 5);
+        assert!(manifest.segment.is_some());
+
+        // The namespace must announce how its segments were chunked.
+        assert_eq!(
+            crate::format::chunk_features(format.as_ref()),
+            EncodeOptions::default().chunk_features()
+        );
+
+        let all = packs_since(&repo, &manifest, 0, &keys).expect("all packs");
+        let expected: Vec<_> = (0..total).rev().map(|i| pack(i as u8)).collect();
+        assert_eq!(all, expected);
+
+        let since = SEGMENT_LEN as u64 + 3;
+        let some = packs_since(&repo, &manifest, since, &keys).expect("some packs");
+        assert_eq!(some, expected[..total - since as usize]);
+
+        assert!(
+            packs_since(&repo, &manifest, total as u64, &keys)
+                .expect("no packs")
+                .is_empty()
+        );
+
+        assert_eq!(
+            newest_pack(&repo, &manifest, &keys).expect("newest"),
+            Some(pack(total as u8 - 1))
+        );
+        let rolled = PackManifest {
+            recent: Vec::new(),
+            count: 2 * SEGMENT_LEN as u64,
+            ..manifest
+        };
+        assert_eq!(
+            newest_pack(&repo, &rolled, &keys).expect("newest in a segment"),
+            Some(pack(2 * SEGMENT_LEN as u8 - 1))
+        );
+        assert_eq!(
+            newest_pack(&repo, &PackManifest::default(), &keys).expect("empty"),
+            None
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use gix_hash::ObjectId;
+
+/// Options set by git using the remote helper `option` command. These persist
+/// for the lifetime of the helper process, which git runs once per operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    // 0 is `-q`, 1 is git's default, and each `-v` adds one.
+    pub verbosity: usize,
+
+    // Whether git would like progress output on stderr.
+    pub progress: bool,
+
+    // Run the whole push pipeline and report per-ref status, but neither commit
+    // to the tracking repo nor push upstream.
+    pub dry_run: bool,
+
+    // Either every ref in a push is applied, or none are.
+    pub atomic: bool,
+
+    // Expected current values for `git push --force-with-lease`, keyed by ref
+    // name. None means the ref is expected not to exist.
+    pub cas: HashMap<String, Option<ObjectId>>,
+
+    // Strings from `git push -o`, recorded in the namespace with the push.
+    pub push_options: Vec<String>,
+
+    // Whether git wants the object format reported when listing refs.
+    pub object_format: bool,
+
+    // Whether git wants fetched refs proven to be fully connected.
+    pub check_connectivity: bool,
+}
+
+/// The response to an `option` command, as sent back to git.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionResponse {
+    Ok,
+    Unsupported,
+    Error(String),
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            verbosity: 1,
+            progress: false,
+            dry_run: false,
+            atomic: false,
+            cas: HashMap::new(),
+            push_options: Vec::new(),
+            object_format: false,
+            check_connectivity: false,
+        }
+    }
+}
+
+impl std::fmt::Display for OptionResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OptionResponse::Ok => f.write_str("ok"),
+            OptionResponse::Unsupported => f.write_str("unsupported"),
+            OptionResponse::Error(msg) => write!(f, "error {}", msg),
+        }
+    }
+}
+
+impl Options {
+    /// Applies a single `option <name> <value>` line received from git.
+    pub fn set(&mut self, name: &str, value: &str) -> OptionResponse {
+        let result = match name {
+            "verbosity" => parse_verbosity(value).map(|v| self.verbosity = v),
+            "progress" => parse_bool(value).map(|v| self.progress = v),
+            "dry-run" => parse_bool(value).map(|v| self.dry_run = v),
+            "atomic" => parse_bool(value).map(|v| self.atomic = v),
+            "cas" => parse_cas(value).map(|(name, expected)| {
+                self.cas.insert(name, expected);
+            }),
+            "push-option" => unquote(value).map(|v| self.push_options.push(v)),
+            "object-format" => parse_bool(value).map(|v| self.object_format = v),
+            "check-connectivity" => parse_bool(value).map(|v| self.check_connectivity = v),
+            _ => return OptionResponse::Unsupported,
+        };
+
+        match result {
+            Ok(()) => OptionResponse::Ok,
+            Err(e) => OptionResponse::Error(format!("{:#}", e)),
+        }
+    }
+
+    // Maps git's verbosity onto a log level, so that a quiet `git pull` is
+    // actually quiet. Warnings are shown by default since they typically
+    // indicate something the user should act on.
+    pub fn log_level_filter(&self) -> log::LevelFilter {
+        match self.verbosity {
+            0 => log::LevelFilter::Error,
+            1 => log::LevelFilter::Warn,
+            2 => log::LevelFilter::Info,
+            3 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        }
+    }
+}
+
+fn parse_verbosity(value: &str) -> Result<usize> {
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid verbosity {:?}", value))
+}
+
+// Git C-quotes option values that contain unusual characters.
+fn unquote(value: &str) -> Result<String> {
+    let Some(inner) = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    else {
+        return Ok(value.to_string());
+    };
+
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c @ ('"' | '\\')) => out.push(c),
+            other => {
+                return None
+                    .with_context(|| format!("unsupported escape {:?} in {}", other, value));
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn parse_cas(value: &str) -> Result<(String, Option<ObjectId>)> {
+    let value = unquote(value)?;
+    let (name, expected) = value
+        .rsplit_once(':')
+        .with_context(|| format!("expected <ref>:<expected oid>, got {:?}", &value))?;
+    let expected = if expected.is_empty() {
+        None
+    } else {
+        let oid = ObjectId::from_hex(expected.as_bytes())
+            .with_context(|| format!("invalid expected oid {:?}", expected))?;
+        Some(oid).filter(|oid| !oid.is_null())
+    };
+    Ok((name.to_string(), expected))
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.trim() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => None.with_context(|| format!("expected true or false, got {:?}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_verbosity_and_progress() {
+        let mut options = Options::default();
+        assert_eq!(options.set("verbosity", "0"), OptionResponse::Ok);
+        assert_eq!(options.set("progress", "true"), OptionResponse::Ok);
+        assert_eq!(options.verbosity, 0);
+        assert!(options.progress);
+        assert_eq!(options.log_level_filter(), log::LevelFilter::Error);
+
+        assert_eq!(options.set("verbosity", "5"), OptionResponse::Ok);
+        assert_eq!(options.log_level_filter(), log::LevelFilter::Trace);
+    }
+
+    #[test]
+    fn set_push_flags() {
+        let mut options = Options::default();
+        assert!(!options.dry_run);
+        assert!(!options.atomic);
+        assert_eq!(options.set("dry-run", "true"), OptionResponse::Ok);
+        assert_eq!(options.set("atomic", "true"), OptionResponse::Ok);
+        assert_eq!(options.set("object-format", "true"), OptionResponse::Ok);
+        assert_eq!(
+            options.set("check-connectivity", "true"),
+            OptionResponse::Ok
+        );
+        assert!(options.dry_run);
+        assert!(options.atomic);
+        assert!(options.object_format);
+        assert!(options.check_connectivity);
+    }
+
+    #[test]
+    fn set_cas_records_expected_values() {
+        let mut options = Options::default();
+        let oid = "1111111111111111111111111111111111111111";
+        assert_eq!(
+            options.set("cas", &format!("refs/heads/main:{oid}")),
+            OptionResponse::Ok
+        );
+        assert_eq!(
+            options.set("cas", "\"refs/heads/new:\""),
+            OptionResponse::Ok
+        );
+        assert_eq!(
+            options.set(
+                "cas",
+                "refs/heads/gone:0000000000000000000000000000000000000000"
+            ),
+            OptionResponse::Ok
+        );
+        assert_eq!(
+            options.cas.get("refs/heads/main"),
+            Some(&Some(ObjectId::from_hex(oid.as_bytes()).expect("oid")))
+        );
+        assert_eq!(options.cas.get("refs/heads/new"), Some(&None));
+        assert_eq!(options.cas.get("refs/heads/gone"), Some(&None));
+
+        match options.set("cas", "refs/heads/main") {
+            OptionResponse::Error(msg) => assert!(msg.contains("expected <ref>:<expected oid>")),
+            other => panic!("expected error, got {other:?}"),
+        }
+        match options.set("cas", "refs/heads/main:xyz") {
+            OptionResponse::Error(msg) => assert!(msg.contains("invalid expected oid")),
+            other => panic!("expected error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn set_push_option_accumulates_in_order() {
+        let mut options = Options::default();
+        assert_eq!(
+            options.set("push-option", "release 4.2"),
+            OptionResponse::Ok
+        );
+        assert_eq!(
+            options.set("push-option", "\"ticket \\\"ABC-1\\\"\""),
+            OptionResponse::Ok
+        );
+        assert_eq!(
+            options.push_options,
+            vec!["release 4.2".to_string(), "ticket \"ABC-1\"".to_string()]
+        );
+    }
+
+    #[test]
+    fn set_rejects_invalid_values() {
+        let mut options = Options::default();
+        match options.set("verbosity", "loud") {
+            OptionResponse::Error(msg) => assert!(msg.contains("invalid verbosity")),
+            other => panic!("expected error, got {other:?}"),
+        }
+        match options.set("progress", "maybe") {
+            OptionResponse::Error(msg) => assert!(msg.contains("expected true or false")),
+            other => panic!("expected error, got {other:?}"),
+        }
+        assert_eq!(options, Options::default());
+    }
+
+    #[test]
+    fn set_reports_unknown_options_as_unsupported() {
+        let mut options = Options::default();
+        assert_eq!(
+            options.set("followtags", "true"),
+            OptionResponse::Unsupported
+        );
+        assert_eq!(format!("{}", OptionResponse::Unsupported), "unsupported");
+        assert_eq!(
+            format!("{}", OptionResponse::Error("bad".to_string())),
+            "error bad"
+        );
+    }
+}
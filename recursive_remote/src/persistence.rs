@@ -8,11 +8,24 @@ use gix::object::tree::EntryKind;
 use gix_hash::ObjectId;
 use rand::Rng;
 
-use crate::config::{Config, EncryptionKeys};
+use crate::config::Config;
 use crate::encoding::*;
+use crate::options::Options;
+use crate::progress::Progress;
 use crate::serialization::*;
 use crate::util::*;
 
+/// The namespace ref that tells clones which branch to check out. It is stored
+/// as a `Ref::Symbolic`.
+pub const HEAD: &str = "HEAD";
+
+/// The outcome of pushing a single ref, as reported back to git.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefStatus {
+    Ok,
+    Rejected(String),
+}
+
 pub fn ref_to_state_oid(
     repo: &gix::Repository,
     ref_name: &str,
@@ -40,6 +53,7 @@ pub fn update_state_with_push(
 ) -> Result<State> {
     let mut future = state.clone();
     future.parents = parent_state_identifier.iter().cloned().collect();
+    future.supersedes = None;
 
     let namespace_ref = NamespaceRef(
         encode_namespace(
@@ -47,6 +61,7 @@ pub fn update_state_with_push(
             namespace,
             &config.nacl_keys,
             config.max_object_size,
+            config.encode_options.list_in(&mut future.format),
         )
         .context("encode pack file")?,
     );
@@ -56,16 +71,26 @@ pub fn update_state_with_push(
     Ok(future)
 }
 
-// Updates the namespace with the specified refs changes and added packs.
+/// The ref changes a push asks for. `refs` are only applied if they fast
+/// forward, while `force_refs` are applied regardless, None deleting the ref.
+pub struct RefUpdates<'a> {
+    pub refs: &'a HashMap<String, Ref>,
+    pub force_refs: &'a HashMap<String, Option<Ref>>,
+}
+
+// Updates the namespace with the specified refs changes and added packs. Returns
+// no namespace if nothing should be committed, which is the case when an atomic
+// push has any rejected ref.
 pub fn update_namespace_with_push(
     config: &Config,
     tracking_repo: &Rc<gix::Repository>,
     all_objects_ever_repo: &gix::Repository,
     namespace: &Namespace,
     mut pack_process: std::process::Child,
-    refs: &HashMap<String, Ref>,
-    force_refs: &HashMap<String, Option<Ref>>,
-) -> Result<(Namespace, HashMap<String, bool>)> {
+    updates: RefUpdates<'_>,
+    options: &Options,
+) -> Result<(Option<Namespace>, HashMap<String, RefStatus>)> {
+    let RefUpdates { refs, force_refs } = updates;
     let mut push_status = HashMap::new();
 
     let mut future = namespace.clone();
@@ -74,6 +99,14 @@ pub fn update_namespace_with_push(
     let mut revision_graph = all_objects_ever_repo.revision_graph(commit_cache.as_ref());
 
     for (name, future_target) in refs.iter() {
+        if lease_is_stale(options, namespace, name) {
+            push_status.insert(
+                name.to_string(),
+                RefStatus::Rejected("stale info".to_string()),
+            );
+            continue;
+        }
+
         let ff = match namespace.refs.get(name) {
             Some(current_target) => can_fast_forward(
                 all_objects_ever_repo,
@@ -88,13 +121,25 @@ pub fn update_namespace_with_push(
 
         if ff {
             future.refs.insert(name.clone(), future_target.clone());
+            push_status.insert(name.to_string(), RefStatus::Ok);
+        } else {
+            push_status.insert(
+                name.to_string(),
+                RefStatus::Rejected("rejected".to_string()),
+            );
         }
-
-        push_status.insert(name.to_string(), ff);
     }
 
     for (name, future_target) in force_refs.iter() {
-        push_status.insert(name.to_string(), true);
+        if lease_is_stale(options, namespace, name) {
+            push_status.insert(
+                name.to_string(),
+                RefStatus::Rejected("stale info".to_string()),
+            );
+            continue;
+        }
+
+        push_status.insert(name.to_string(), RefStatus::Ok);
         match future_target {
             Some(future_target) => {
                 future.refs.insert(name.clone(), future_target.clone());
@@ -105,14 +150,45 @@ pub fn update_namespace_with_push(
         }
     }
 
+    if options.atomic && push_status.values().any(|s| *s != RefStatus::Ok) {
+        for status in push_status.values_mut() {
+            if *status == RefStatus::Ok {
+                *status = RefStatus::Rejected("atomic push failed".to_string());
+            }
+        }
+
+        // Nothing will be committed, so don't bother reading the pack.
+        pack_process.kill().ok();
+        pack_process.wait().context("reap git pack-objects")?;
+        return Ok((None, push_status));
+    }
+
+    future.push_options = options.push_options.clone();
+
+    // Remember what this push left out of the pack, so that a fetch missing
+    // those objects can say where to get them.
+    update_shallow_basis(&mut revision_graph, &mut future, &config.shallow_basis);
+
     let mut reader = std::io::BufReader::new(pack_process.stdout.take().context("No stdout.")?);
-    let (blob_ref, size) = encode(
+
+    if options.dry_run {
+        // Estimate the upload without writing any blobs to the tracking repo.
+        let size = std::io::copy(&mut reader, &mut std::io::sink()).context("read pack file")?;
+        wait_subprocess(&mut pack_process).context("git pack-objects")?;
+        log::info!("Dry run: would upload a pack of {} bytes.", size);
+        return Ok((Some(future), push_status));
+    }
+
+    let mut progress = Progress::bytes("Uploading pack", options.progress);
+    let (blob_ref, size) = encode_pack(
+        config,
         tracking_repo,
+        &mut future.format,
         &mut reader,
-        config.nacl_keys.namespace_key(),
-        config.max_object_size,
+        &mut progress,
     )
     .context("encode pack file")?;
+    progress.done();
 
     wait_subprocess(&mut pack_process).context("git pack-objects")?;
 
@@ -122,12 +198,199 @@ pub fn update_namespace_with_push(
             blob_ref,
             random_name,
         };
+        if let Some(manifest) = future.manifest.as_mut() {
+            crate::manifest::append(
+                tracking_repo,
+                manifest,
+                &mut future.format,
+                pack_ref.clone(),
+                &config.nacl_keys,
+                config.max_object_size,
+                config.encode_options,
+            )
+            .context("update pack manifest")?;
+        }
         future.pack = Some(pack_ref);
     } else {
         future.pack = None;
     }
 
-    Ok((future, push_status))
+    Ok((Some(future), push_status))
+}
+
+/// Encodes a pack with the namespace key, into the annex if one is configured
+/// and the pack is large enough.
+pub fn encode_pack<R: std::io::BufRead>(
+    config: &Config,
+    tracking_repo: &Rc<gix::Repository>,
+    namespace_format: &mut Option<crate::format::FormatHeader>,
+    reader: &mut R,
+    progress: &mut Progress,
+) -> Result<(BlobRef, usize)> {
+    // Packs are new each time, so gain nothing from content-defined chunks.
+    let options = EncodeOptions {
+        layout: Layout::Fixed,
+        ..config.encode_options
+    }
+    .list_in(namespace_format);
+
+    // An unversioned namespace couldn't tell older clients that they need the
+    // annex, so its packs stay in the tracking repo.
+    match config
+        .annex
+        .as_deref()
+        .filter(|_| namespace_format.is_some())
+    {
+        Some(annex) => encode_to_annex_with_progress(
+            tracking_repo,
+            annex,
+            config.annex_threshold,
+            reader,
+            config.nacl_keys.namespace_key(),
+            config.max_object_size,
+            options,
+            progress,
+        ),
+        None => encode_with_progress(
+            tracking_repo,
+            reader,
+            config.nacl_keys.namespace_key(),
+            config.max_object_size,
+            options,
+            progress,
+        ),
+    }
+}
+
+// Points HEAD at a branch if the namespace has none yet, as happens on the first
+// push, or if the branch it pointed at has been deleted. `preferred` is used if
+// it names a branch, otherwise the first branch by name is chosen. Without any
+// branch to point at, a dangling HEAD is dropped.
+pub fn set_default_head(namespace: &mut Namespace, preferred: Option<&str>) {
+    match namespace.refs.get(HEAD) {
+        Some(Ref::Symbolic(target, _)) if !namespace.refs.contains_key(target) => {
+            log::info!("HEAD pointed at deleted {}.", target);
+            namespace.refs.remove(HEAD);
+        }
+        Some(..) => return,
+        None => {}
+    }
+
+    let is_branch =
+        |name: &str| name.starts_with("refs/heads/") && namespace.refs.contains_key(name);
+    let target = match preferred {
+        Some(name) if is_branch(name) => Some(name.to_string()),
+        _ => namespace
+            .refs
+            .keys()
+            .filter(|name| is_branch(name))
+            .min()
+            .cloned(),
+    };
+
+    if let Some(target) = target {
+        log::info!("Setting HEAD to {}.", &target);
+        namespace
+            .refs
+            .insert(HEAD.to_string(), Ref::Symbolic(target, None));
+    }
+}
+
+/// Describes an update of a namespace from `previous` to `future` by `pusher`.
+pub fn audit_record(pusher: &str, previous: Option<&Namespace>, future: &Namespace) -> AuditRecord {
+    AuditRecord {
+        pusher: pusher.to_string(),
+        host: hostname(),
+        time: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_secs() as i64)
+            .unwrap_or_default(),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        ref_changes: ref_changes(previous, Some(future)),
+    }
+}
+
+/// Lists the refs that differ between `previous` and `future`, in order by name.
+/// A namespace that doesn't exist has no refs.
+pub fn ref_changes(previous: Option<&Namespace>, future: Option<&Namespace>) -> Vec<RefChange> {
+    let mut names: Vec<&String> = future
+        .iter()
+        .chain(previous.iter())
+        .flat_map(|namespace| namespace.refs.keys())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = previous.and_then(|previous| previous.refs.get(name));
+            let new = future.and_then(|future| future.refs.get(name));
+            (old != new).then(|| RefChange {
+                name: name.clone(),
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+        })
+        .collect()
+}
+
+// Best effort, since the standard library has no portable way to ask.
+fn hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+// Checks the expected value from `git push --force-with-lease` against the
+// namespace being updated. Since that is re-read from upstream on every push
+// attempt, a ref that moved since git last saw it is caught even when we lose a
+// race and retry.
+fn lease_is_stale(options: &Options, namespace: &Namespace, name: &str) -> bool {
+    match options.cas.get(name) {
+        Some(expected) => namespace.refs.get(name).and_then(Ref::oid_at_time) != *expected,
+        None => false,
+    }
+}
+
+// Records the shallow basis a push left out of its pack. Those earlier pushes
+// left out are kept only while some ref may still depend on them, so that the
+// list doesn't grow with every basis ever configured.
+fn update_shallow_basis(
+    graph: &mut gix_revision::Graph<()>,
+    future: &mut Namespace,
+    configured: &[(String, Ref)],
+) {
+    let refs = &future.refs;
+    future.shallow_basis.retain(|name, basis| {
+        configured.iter().any(|(configured, _)| configured == name)
+            || basis_still_needed(graph, refs, basis)
+    });
+    for (name, basis) in configured.iter() {
+        future.shallow_basis.insert(name.clone(), basis.clone());
+    }
+}
+
+// Whether any of `refs` may need the objects left out for `basis`. That is
+// assumed unless walking each ref's history, which must then be wholly present,
+// shows that none reaches it.
+fn basis_still_needed(
+    graph: &mut gix_revision::Graph<()>,
+    refs: &HashMap<String, Ref>,
+    basis: &Ref,
+) -> bool {
+    let Some(basis) = basis.oid_at_time() else {
+        return false;
+    };
+    refs.values().filter_map(Ref::oid_at_time).any(|tip| {
+        graph_descendant_of(graph, tip, basis).unwrap_or_else(|err| {
+            log::debug!("Keeping shallow basis {}: {:#}", basis, err);
+            true
+        })
+    })
 }
 
 fn graph_descendant_of(
@@ -225,44 +488,81 @@ fn can_fast_forward(
     Ok(true)
 }
 
+/// The namespaces a new state changed. Each of `written` must have been written
+/// to the state, while `deleted` are the `random_name`s of those removed from
+/// it.
+#[derive(Clone, Copy)]
+pub struct NamespaceChanges<'a> {
+    pub written: &'a [&'a str],
+    pub deleted: &'a [[u8; 20]],
+}
+
 /// Given the parent's commit root tree as a tree builder, create the new commit
-/// tree.
+/// tree. The trees of written namespaces are updated and those of deleted ones
+/// dropped.
 pub fn create_commit_tree<'a>(
+    config: &Config,
     repo: &gix::Repository,
-    namespace_name: &str,
+    changes: NamespaceChanges<'_>,
     mut root: gix::object::tree::Editor<'a>,
     tracking_repo: &Rc<gix::Repository>,
     state: &State,
-    encrypt: &EncryptionKeys,
-    max_object_size: usize,
 ) -> Result<ObjectId> {
-    let namespace_ref = state
-        .namespaces
-        .get(namespace_name)
-        .context("namespace should have been written already")?;
-    let namespace = state
-        .namespace(namespace_name, encrypt, tracking_repo)?
-        .expect("namespace should have been written already");
-
-    let name = format!("ns_{}", hex::encode(namespace.random_name));
-
-    let namespace_tree = create_treebuilder_at(tracking_repo, &root, &name)?;
-    let namespace_tree =
-        create_namespace_tree(tracking_repo, namespace_tree, &namespace, namespace_ref)?;
+    let encrypt = &config.nacl_keys;
+    let max_object_size = config.max_object_size;
+
+    for namespace_name in changes.written {
+        let namespace_ref = state
+            .namespaces
+            .get(*namespace_name)
+            .context("namespace should have been written already")?;
+        let namespace = state
+            .namespace(namespace_name, encrypt, tracking_repo)?
+            .expect("namespace should have been written already");
+
+        let name = format!("ns_{}", hex::encode(namespace.random_name));
+
+        let namespace_tree = create_treebuilder_at(tracking_repo, &root, &name)?;
+        let namespace_tree =
+            create_namespace_tree(tracking_repo, namespace_tree, &namespace, namespace_ref)?;
+
+        root.upsert(&name, EntryKind::Tree, namespace_tree)
+            .with_context(|| format!("insert namespace tree for namespace {}", namespace_name))?;
+    }
 
-    root.upsert(&name, EntryKind::Tree, namespace_tree)
-        .with_context(|| format!("insert namespace tree for namespace {}", namespace_name))?;
+    for random_name in changes.deleted {
+        root.remove(format!("ns_{}", hex::encode(random_name)))
+            .context("remove namespace tree")?;
+    }
 
-    let oids = match encode_state(tracking_repo, state, encrypt, max_object_size)
-        .context("encode state.bincode")?
-        .resource_key
-    {
+    let options = config.encode_options.listed_in(state.format.as_ref());
+    let state_ref = encode_state(tracking_repo, state, encrypt, max_object_size, options)
+        .context("encode state.bincode")?;
+    let oids = match &state_ref.resource_key {
         ResourceKey::Git(oids) => oids,
         _ => unreachable!(),
     };
 
     // This is the "root" state for the current commit.
-    insert_metadata_chunk_tree(repo, &mut root, "state", &oids).context("insert state.bincode")?;
+    insert_metadata_chunk_tree(repo, &mut root, "state", oids).context("insert state.bincode")?;
+
+    // Clients from before framing find the state by state.bincode, so a framed
+    // state is kept only in the tree above, and state.bincode points at it with
+    // a header they can refuse.
+    if options.layout != Layout::Stream {
+        let pointer = StatePointer {
+            state: StateRef(state_ref),
+            chunk_features: options.chunk_features(),
+        };
+        let pointer_ref = encode_state_pointer(tracking_repo, &pointer, encrypt, max_object_size)
+            .context("encode state pointer")?;
+        let (oid, mode) = match &pointer_ref.resource_key {
+            ResourceKey::Git(oids) => create_chunk_tree_or_blob(repo, oids)?,
+            _ => unreachable!(),
+        }
+        .context("empty state pointer")?;
+        root.upsert("state.bincode", mode, oid)?;
+    }
 
     Ok(root.write()?.into())
 }
@@ -341,8 +641,31 @@ pub fn create_namespace_tree<'a>(
                     root.upsert("pack", EntryKind::Tree, pack_tree.write()?)?;
                 }
             }
+            // Kept out of band, so there is nothing to keep reachable here.
+            ResourceKey::Annex(..) => {}
+        }
+    }
+
+    // Older segments are already in the tree from the pushes that wrote them.
+    if let Some(segment) = namespace
+        .manifest
+        .as_ref()
+        .and_then(|manifest| manifest.segment.as_ref())
+    {
+        match &segment.blob_ref.resource_key {
+            ResourceKey::Git(oids) => {
+                if let Some((oid, mode)) = create_chunk_tree_or_blob(tracking_repo, oids)? {
+                    let mut manifest_tree =
+                        create_treebuilder_at(tracking_repo, &root, "manifest")?;
+                    insert_into_name_tree(&mut manifest_tree, segment.random_name, oid, mode)?;
+                    root.upsert("manifest", EntryKind::Tree, manifest_tree.write()?)?;
+                }
+            }
             ResourceKey::Annex(..) => {
-                unreachable!();
+                anyhow::bail!(
+                    "pack segment {} is not stored in the tracking repo",
+                    &segment.blob_ref
+                );
             }
         }
     }
@@ -418,6 +741,62 @@ mod tests {
         (tmp, repo, c1, c2)
     }
 
+    #[test]
+    fn lease_is_stale_compares_against_namespace_refs() {
+        let (_tmp, _repo, c1, c2) = setup_repo_with_linear_history();
+        let mut namespace = Namespace::new();
+        namespace
+            .refs
+            .insert("refs/heads/main".to_string(), Ref::Direct(c1));
+
+        let mut options = Options::default();
+        assert!(!lease_is_stale(&options, &namespace, "refs/heads/main"));
+
+        options.cas.insert("refs/heads/main".to_string(), Some(c1));
+        assert!(!lease_is_stale(&options, &namespace, "refs/heads/main"));
+
+        options.cas.insert("refs/heads/main".to_string(), Some(c2));
+        assert!(lease_is_stale(&options, &namespace, "refs/heads/main"));
+
+        options.cas.insert("refs/heads/main".to_string(), None);
+        assert!(lease_is_stale(&options, &namespace, "refs/heads/main"));
+
+        options.cas.insert("refs/heads/new".to_string(), None);
+        assert!(!lease_is_stale(&options, &namespace, "refs/heads/new"));
+    }
+
+    #[test]
+    fn update_shallow_basis_drops_bases_no_ref_reaches() {
+        let (_tmp, repo, c1, c2) = setup_repo_with_linear_history();
+        let commit_cache = repo.commit_graph_if_enabled().expect("commit graph");
+        let mut graph = repo.revision_graph(commit_cache.as_ref());
+
+        let mut namespace = Namespace::new();
+        namespace
+            .refs
+            .insert("refs/heads/main".to_string(), Ref::Direct(c1));
+        namespace
+            .shallow_basis
+            .insert("refs/tags/reached".to_string(), Ref::Direct(c1));
+        namespace
+            .shallow_basis
+            .insert("refs/tags/unreached".to_string(), Ref::Direct(c2));
+        namespace
+            .shallow_basis
+            .insert("refs/tags/configured".to_string(), Ref::Direct(c2));
+
+        let configured = vec![("refs/tags/configured".to_string(), Ref::Direct(c1))];
+        update_shallow_basis(&mut graph, &mut namespace, &configured);
+
+        let mut names: Vec<_> = namespace.shallow_basis.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["refs/tags/configured", "refs/tags/reached"]);
+        assert_eq!(
+            namespace.shallow_basis.get("refs/tags/configured"),
+            Some(&Ref::Direct(c1))
+        );
+    }
+
     #[test]
     fn can_fast_forward_accepts_commit_fast_forward() {
         let (_tmp, repo, c1, c2) = setup_repo_with_linear_history();
@@ -503,4 +882,133 @@ mod tests {
         .expect("can_fast_forward");
         assert!(!ok);
     }
+
+    #[test]
+    fn audit_record_lists_changed_refs() {
+        let (_tmp, _repo, c1, c2) = setup_repo_with_linear_history();
+        let mut previous = Namespace::new();
+        previous
+            .refs
+            .insert("refs/heads/main".to_string(), Ref::Direct(c1));
+        previous
+            .refs
+            .insert("refs/heads/gone".to_string(), Ref::Direct(c1));
+        previous
+            .refs
+            .insert("refs/tags/v1".to_string(), Ref::Direct(c1));
+
+        let mut future = previous.clone();
+        future
+            .refs
+            .insert("refs/heads/main".to_string(), Ref::Direct(c2));
+        future.refs.remove("refs/heads/gone");
+        future
+            .refs
+            .insert("refs/heads/new".to_string(), Ref::Direct(c2));
+
+        let record = audit_record("Test User <you@example.com>", Some(&previous), &future);
+        assert_eq!(record.pusher, "Test User <you@example.com>");
+        assert_eq!(record.client_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            record.ref_changes,
+            vec![
+                RefChange {
+                    name: "refs/heads/gone".to_string(),
+                    old: Some(Ref::Direct(c1)),
+                    new: None,
+                },
+                RefChange {
+                    name: "refs/heads/main".to_string(),
+                    old: Some(Ref::Direct(c1)),
+                    new: Some(Ref::Direct(c2)),
+                },
+                RefChange {
+                    name: "refs/heads/new".to_string(),
+                    old: None,
+                    new: Some(Ref::Direct(c2)),
+                },
+            ]
+        );
+
+        // Every ref of a new namespace is a change.
+        let record = audit_record("Test User <you@example.com>", None, &future);
+        assert_eq!(record.ref_changes.len(), future.refs.len());
+    }
+
+    #[test]
+    fn set_default_head_prefers_pushed_branch() {
+        let oid = ObjectId::from_hex(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").expect("oid");
+        let mut namespace = Namespace::new();
+        namespace
+            .refs
+            .insert("refs/tags/a".to_string(), Ref::Direct(oid));
+        namespace
+            .refs
+            .insert("refs/heads/a".to_string(), Ref::Direct(oid));
+        namespace
+            .refs
+            .insert("refs/heads/b".to_string(), Ref::Direct(oid));
+
+        let mut preferred = namespace.clone();
+        set_default_head(&mut preferred, Some("refs/heads/b"));
+        assert_eq!(
+            preferred.refs.get(HEAD),
+            Some(&Ref::Symbolic("refs/heads/b".to_string(), None))
+        );
+
+        // Tags and missing refs are not valid defaults.
+        for bad in [None, Some("refs/tags/a"), Some("refs/heads/missing")] {
+            let mut fallback = namespace.clone();
+            set_default_head(&mut fallback, bad);
+            assert_eq!(
+                fallback.refs.get(HEAD),
+                Some(&Ref::Symbolic("refs/heads/a".to_string(), None))
+            );
+        }
+
+        // An existing HEAD is left alone.
+        set_default_head(&mut preferred, Some("refs/heads/a"));
+        assert_eq!(
+            preferred.refs.get(HEAD),
+            Some(&Ref::Symbolic("refs/heads/b".to_string(), None))
+        );
+
+        let mut empty = Namespace::new();
+        set_default_head(&mut empty, None);
+        assert!(empty.refs.is_empty());
+    }
+
+    #[test]
+    fn set_default_head_repoints_deleted_head() {
+        let oid = ObjectId::from_hex(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").expect("oid");
+        let mut namespace = Namespace::new();
+        namespace
+            .refs
+            .insert("refs/heads/a".to_string(), Ref::Direct(oid));
+        namespace
+            .refs
+            .insert("refs/heads/b".to_string(), Ref::Direct(oid));
+        namespace.refs.insert(
+            HEAD.to_string(),
+            Ref::Symbolic("refs/heads/b".to_string(), Some(oid)),
+        );
+
+        // Deleting the branch HEAD points at moves HEAD to another.
+        namespace.refs.remove("refs/heads/b");
+        set_default_head(&mut namespace, None);
+        assert_eq!(
+            namespace.refs.get(HEAD),
+            Some(&Ref::Symbolic("refs/heads/a".to_string(), None))
+        );
+
+        // Deleting the last branch drops HEAD.
+        namespace.refs.remove("refs/heads/a");
+        set_default_head(&mut namespace, Some("refs/heads/a"));
+        assert!(namespace.refs.is_empty());
+
+        // A detached HEAD is left alone.
+        namespace.refs.insert(HEAD.to_string(), Ref::Direct(oid));
+        set_default_head(&mut namespace, None);
+        assert_eq!(namespace.refs.get(HEAD), Some(&Ref::Direct(oid)));
+    }
 }
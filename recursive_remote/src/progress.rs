@@ -0,0 +1,212 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+// Redrawing more often than this just makes the terminal flicker.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Progress output on stderr, formatted like git's own so that it blends in
+/// with the rest of a push or fetch. Disabled progress does nothing, so callers
+/// don't need to check whether git asked for it.
+pub struct Progress {
+    title: String,
+    enabled: bool,
+
+    // Items done out of an optional total, shown as a percentage if known.
+    count: u64,
+    total: Option<u64>,
+
+    // Bytes transferred, shown with throughput. None if not tracked.
+    bytes: Option<u64>,
+
+    start: Instant,
+    last_drawn: Option<(Instant, Option<u64>)>,
+    last_len: usize,
+}
+
+impl Progress {
+    /// Counts items, such as states or packs, out of an optional total.
+    pub fn new(title: &str, total: Option<u64>, enabled: bool) -> Progress {
+        Progress {
+            title: title.to_string(),
+            enabled,
+            count: 0,
+            total,
+            bytes: None,
+            start: Instant::now(),
+            last_drawn: None,
+            last_len: 0,
+        }
+    }
+
+    /// Counts bytes, shown with their throughput.
+    pub fn bytes(title: &str, enabled: bool) -> Progress {
+        Progress::new(title, None, enabled).with_throughput()
+    }
+
+    pub fn disabled() -> Progress {
+        Progress::new("", None, false)
+    }
+
+    /// Also tracks bytes and throughput alongside the item count.
+    pub fn with_throughput(mut self) -> Progress {
+        self.bytes = Some(0);
+        self
+    }
+
+    pub fn set(&mut self, count: u64) {
+        self.count = count;
+        self.draw(false);
+    }
+
+    pub fn inc(&mut self) {
+        self.set(self.count + 1);
+    }
+
+    pub fn add_bytes(&mut self, n: usize) {
+        if let Some(bytes) = self.bytes.as_mut() {
+            *bytes += n as u64;
+        }
+        self.draw(false);
+    }
+
+    /// Draws the final state followed by git's ", done.".
+    pub fn done(&mut self) {
+        self.draw(true);
+        self.enabled = false;
+    }
+
+    fn draw(&mut self, done: bool) {
+        if !self.enabled {
+            return;
+        }
+
+        let now = Instant::now();
+        let percent = self.percent();
+        if !done
+            && let Some((at, last_percent)) = self.last_drawn
+            && now.duration_since(at) < REDRAW_INTERVAL
+            && last_percent == percent
+        {
+            return;
+        }
+        self.last_drawn = Some((now, percent));
+
+        let mut line = self.line(now.duration_since(self.start));
+        let len = line.len();
+        if done {
+            line.push_str(", done.");
+        }
+        // Blank out whatever is left over from a longer previous line.
+        let padding = self.last_len.saturating_sub(line.len());
+        self.last_len = len;
+        let end = if done { "\n" } else { "\r" };
+
+        let mut stderr = std::io::stderr().lock();
+        write!(stderr, "{}{:padding$}{}", line, "", end).ok();
+        stderr.flush().ok();
+    }
+
+    fn percent(&self) -> Option<u64> {
+        self.total
+            .filter(|total| *total > 0)
+            .map(|total| self.count.min(total) * 100 / total)
+    }
+
+    fn line(&self, elapsed: Duration) -> String {
+        let mut line = match (self.total, self.percent()) {
+            (Some(total), Some(percent)) => {
+                format!("{}: {:3}% ({}/{})", self.title, percent, self.count, total)
+            }
+            _ if self.bytes.is_some() && self.count == 0 => format!("{}:", self.title),
+            _ => format!("{}: {}", self.title, self.count),
+        };
+
+        if let Some(bytes) = self.bytes {
+            if !line.ends_with(':') {
+                line.push(',');
+            }
+            let seconds = elapsed.as_secs_f64();
+            let rate = if seconds > 0.0 {
+                (bytes as f64 / seconds) as u64
+            } else {
+                0
+            };
+            line.push_str(&format!(
+                " {} | {}/s",
+                humanise_bytes(bytes),
+                humanise_bytes(rate)
+            ));
+        }
+        line
+    }
+}
+
+// Matches git's strbuf_humanise_bytes.
+fn humanise_bytes(bytes: u64) -> String {
+    const KIB: u64 = 1 << 10;
+    const MIB: u64 = 1 << 20;
+    const GIB: u64 = 1 << 30;
+    let scaled = |unit: u64| {
+        let x = bytes / unit;
+        let fraction = (bytes % unit) * 100 / unit;
+        format!("{}.{:02}", x, fraction)
+    };
+    if bytes > GIB {
+        format!("{} GiB", scaled(GIB))
+    } else if bytes > MIB {
+        format!("{} MiB", scaled(MIB))
+    } else if bytes > KIB {
+        format!("{} KiB", scaled(KIB))
+    } else if bytes == 1 {
+        "1 byte".to_string()
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn humanise_bytes_matches_git() {
+        assert_eq!(humanise_bytes(0), "0 bytes");
+        assert_eq!(humanise_bytes(1), "1 byte");
+        assert_eq!(humanise_bytes(1024), "1024 bytes");
+        assert_eq!(humanise_bytes(1536), "1.50 KiB");
+        assert_eq!(humanise_bytes(5 * (1 << 20) + (1 << 19)), "5.50 MiB");
+        assert_eq!(humanise_bytes(3 << 30), "3.00 GiB");
+    }
+
+    #[test]
+    fn line_formats_like_git() {
+        let mut progress = Progress::new("Receiving packs", Some(20), false);
+        progress.set(9);
+        assert_eq!(
+            progress.line(Duration::from_secs(1)),
+            "Receiving packs:  45% (9/20)"
+        );
+
+        let mut progress = progress.with_throughput();
+        progress.add_bytes(3 << 20);
+        assert_eq!(
+            progress.line(Duration::from_secs(2)),
+            "Receiving packs:  45% (9/20), 3.00 MiB | 1.50 MiB/s"
+        );
+
+        let mut progress = Progress::new("Reading state history", None, false);
+        progress.inc();
+        progress.inc();
+        assert_eq!(
+            progress.line(Duration::from_secs(1)),
+            "Reading state history: 2"
+        );
+
+        let mut progress = Progress::bytes("Uploading pack", false);
+        progress.add_bytes(100);
+        assert_eq!(
+            progress.line(Duration::from_secs(1)),
+            "Uploading pack: 100 bytes | 100 bytes/s"
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use std::io::{Read, Seek};
+
+use anyhow::{Context, Result};
+use hmac::Mac;
+use sha2::Digest;
+
+use crate::blob_store::{BlobStore, ContentKeyReader, copy_with_content_key, parse_content_key};
+
+/// Keeps annexed chunks in a bucket of an S3-compatible object store, such as
+/// AWS S3 or MinIO, addressed path style.
+pub struct S3Store {
+    // The scheme and authority, as in "https://s3.amazonaws.com".
+    base: String,
+
+    // The Host header the request will be sent with, which is signed.
+    host: String,
+
+    // The bucket and key prefix, as in "/bucket/prefix", already URI encoded.
+    path: String,
+
+    region: String,
+    credentials: Option<S3Credentials>,
+    agent: ureq::Agent,
+}
+
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl S3Store {
+    /// `url` names the bucket and an optional key prefix, as in
+    /// `https://host[:port]/bucket[/prefix]`. Requests are sent unsigned
+    /// without credentials.
+    pub fn new(url: &str, region: &str, credentials: Option<S3Credentials>) -> Result<S3Store> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .with_context(|| format!("S3 url {:?} has no scheme", url))?;
+        let default_port = match scheme {
+            "http" => "80",
+            "https" => "443",
+            _ => anyhow::bail!("S3 url {:?} must be http or https", url),
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let path = path.trim_matches('/');
+        if authority.is_empty() || path.is_empty() {
+            anyhow::bail!("S3 url {:?} must name a host and bucket", url);
+        }
+
+        // Sent without the port if it is the default for the scheme.
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port == default_port => host,
+            _ => authority,
+        };
+
+        Ok(S3Store {
+            base: format!("{}://{}", scheme, authority),
+            host: host.to_string(),
+            path: path
+                .split('/')
+                .map(|segment| format!("/{}", uri_encode(segment)))
+                .collect(),
+            region: region.to_string(),
+            credentials,
+            agent: ureq::AgentBuilder::new().build(),
+        })
+    }
+
+    /// As new, taking the region and credentials from the usual AWS
+    /// environment variables.
+    pub fn from_env(url: &str) -> Result<S3Store> {
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+        let credentials = match (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key_id), Ok(secret_access_key)) => Some(S3Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            }),
+            _ => None,
+        };
+        S3Store::new(url, &region, credentials)
+    }
+
+    // Sends `body`, if any, as the payload, along with its length. The payload
+    // hash is signed, so must be known before the body is sent.
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        body: Option<(std::fs::File, u64, [u8; 32])>,
+    ) -> Result<ureq::Response> {
+        let uri = format!("{}/{}", &self.path, uri_encode(key));
+        let payload_hash = match body.as_ref() {
+            Some((_, _, sha256)) => hex::encode(sha256),
+            None => hex::encode(sha2::Sha256::digest(b"")),
+        };
+        let mut request = self
+            .agent
+            .request(method, &format!("{}{}", &self.base, &uri))
+            .set("x-amz-content-sha256", &payload_hash);
+
+        if let Some(credentials) = self.credentials.as_ref() {
+            let amz_date = amz_date(std::time::SystemTime::now())?;
+            let mut headers = vec![
+                ("host", self.host.clone()),
+                ("x-amz-content-sha256", payload_hash.clone()),
+                ("x-amz-date", amz_date.clone()),
+            ];
+            if let Some(token) = credentials.session_token.as_ref() {
+                headers.push(("x-amz-security-token", token.clone()));
+                request = request.set("x-amz-security-token", token);
+            }
+            let authorization = authorization(
+                credentials,
+                &self.region,
+                method,
+                &uri,
+                &headers,
+                &payload_hash,
+                &amz_date,
+            );
+            request = request
+                .set("x-amz-date", &amz_date)
+                .set("authorization", &authorization);
+        }
+
+        let response = match body {
+            Some((file, len, _)) => request.set("content-length", &len.to_string()).send(file),
+            None => request.call(),
+        };
+        match response {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => anyhow::bail!(
+                "S3 {} {} failed with status {}: {}",
+                method,
+                key,
+                status,
+                response.into_string().unwrap_or_default()
+            ),
+            Err(err) => Err(err).with_context(|| format!("S3 {} {}", method, key)),
+        }
+    }
+}
+
+impl BlobStore for S3Store {
+    type Key = String;
+
+    fn put(&self, chunk: &mut dyn Read, _len: u64) -> Result<String> {
+        // Spooled first, since the key is the chunk's hash.
+        let mut spool = tempfile::tempfile().context("Unable to create temp file.")?;
+        let key = copy_with_content_key(chunk, &mut spool).context("spool chunk")?;
+        spool.rewind().context("rewind spooled chunk")?;
+        let (len, sha256) = parse_content_key(&key)?;
+        self.request("PUT", &key, Some((spool, len, sha256)))?;
+        Ok(key)
+    }
+
+    fn get<'a>(&'a self, key: &String) -> Result<Box<dyn Read + 'a>> {
+        let response = self.request("GET", key, None)?;
+        Ok(Box::new(ContentKeyReader::new(
+            response.into_reader(),
+            key,
+        )?))
+    }
+
+    // Any failure counts as absent, which at worst stores the chunk again.
+    fn contains(&self, key: &String) -> bool {
+        self.request("HEAD", key, None).is_ok()
+    }
+}
+
+// AWS Signature Version 4, with every header in `headers` signed. Headers must
+// be in order by name, and the URI already encoded.
+fn authorization(
+    credentials: &S3Credentials,
+    region: &str,
+    method: &str,
+    uri: &str,
+    headers: &[(&str, String)],
+    payload_hash: &str,
+    amz_date: &str,
+) -> String {
+    let date = &amz_date[..8];
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request =
+        format!("{method}\n{uri}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}");
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex::encode(sha2::Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let mut key = hmac_sha256(
+        format!("AWS4{}", &credentials.secret_access_key).as_bytes(),
+        date.as_bytes(),
+    );
+    for part in [region, "s3", "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+        &credentials.access_key_id
+    )
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        hmac::Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+// Percent encodes all but the unreserved characters, as S3 expects of each
+// path segment.
+fn uri_encode(segment: &str) -> String {
+    let mut encoded = String::new();
+    for b in segment.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+// The request time in ISO 8601 basic format, as in "20150830T123600Z".
+fn amz_date(time: std::time::SystemTime) -> Result<String> {
+    let time = jiff::Timestamp::try_from(time).context("request time")?;
+    Ok(time.strftime("%Y%m%dT%H%M%SZ").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::blob_store::content_key;
+
+    // A stand-in for an S3-compatible server, keeping objects in memory and
+    // recording the Authorization header of each request.
+    struct FakeS3 {
+        url: String,
+        objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        authorizations: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeS3 {
+        fn start() -> FakeS3 {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+            let url = format!(
+                "http://{}/bucket/prefix",
+                listener.local_addr().expect("addr")
+            );
+            let objects = Arc::new(Mutex::new(HashMap::new()));
+            let authorizations = Arc::new(Mutex::new(Vec::new()));
+            let (thread_objects, thread_authorizations) = (objects.clone(), authorizations.clone());
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.expect("accept");
+                    FakeS3::serve(stream, &thread_objects, &thread_authorizations);
+                }
+            });
+            FakeS3 {
+                url,
+                objects,
+                authorizations,
+            }
+        }
+
+        fn serve(
+            mut stream: std::net::TcpStream,
+            objects: &Mutex<HashMap<String, Vec<u8>>>,
+            authorizations: &Mutex<Vec<String>>,
+        ) {
+            let mut reader = std::io::BufReader::new(stream.try_clone().expect("clone"));
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).expect("request line");
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().expect("method").to_string();
+            let path = parts.next().expect("path").to_string();
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).expect("header");
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').expect("header line");
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.trim().parse().expect("length"),
+                    "authorization" => authorizations
+                        .lock()
+                        .expect("lock")
+                        .push(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).expect("body");
+
+            let (status, body) = match method.as_str() {
+                "PUT" => {
+                    objects.lock().expect("lock").insert(path, body);
+                    ("200 OK", Vec::new())
+                }
+                "GET" => match objects.lock().expect("lock").get(&path) {
+                    Some(body) => ("200 OK", body.clone()),
+                    None => ("404 Not Found", b"NoSuchKey".to_vec()),
+                },
+                "HEAD" => match objects.lock().expect("lock").get(&path) {
+                    Some(..) => ("200 OK", Vec::new()),
+                    None => ("404 Not Found", Vec::new()),
+                },
+                _ => ("405 Method Not Allowed", Vec::new()),
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            )
+            .expect("write response");
+            stream.write_all(&body).expect("write body");
+        }
+    }
+
+    #[test]
+    fn chunks_roundtrip_through_an_s3_stand_in() {
+        let server = FakeS3::start();
+        let store = S3Store::new(
+            &server.url,
+            "us-east-1",
+            Some(S3Credentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+            }),
+        )
+        .expect("store");
+
+        let key = store.put(&mut &b"big pack"[..], 8).expect("put");
+        assert_eq!(key, content_key(b"big pack"));
+        assert!(
+            server
+                .objects
+                .lock()
+                .expect("lock")
+                .contains_key(&format!("/bucket/prefix/{}", &key))
+        );
+        assert_eq!(store.get_all(&key).expect("get"), b"big pack");
+
+        let authorizations = server.authorizations.lock().expect("lock").clone();
+        assert_eq!(authorizations.len(), 2);
+        for authorization in authorizations {
+            assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+            assert!(authorization.contains("/us-east-1/s3/aws4_request, SignedHeaders="));
+        }
+
+        // The store is not trusted to return what was put.
+        server
+            .objects
+            .lock()
+            .expect("lock")
+            .insert(format!("/bucket/prefix/{}", &key), b"bad pack".to_vec());
+        let err = store.get_all(&key).expect_err("corrupt chunk");
+        assert!(format!("{err}").contains("does not match its key"));
+
+        let missing = content_key(b"missing");
+        let err = store.get(&missing).err().expect("missing chunk");
+        assert!(format!("{err}").contains("status 404"));
+
+        assert!(store.contains(&key));
+        assert!(!store.contains(&missing));
+    }
+
+    #[test]
+    fn urls_are_parsed_path_style() {
+        let store = S3Store::new("https://s3.example.com:443/my bucket/a/", "eu-west-1", None)
+            .expect("store");
+        assert_eq!(store.base, "https://s3.example.com:443");
+        assert_eq!(store.host, "s3.example.com");
+        assert_eq!(store.path, "/my%20bucket/a");
+
+        assert!(S3Store::new("s3://bucket", "us-east-1", None).is_err());
+        assert!(S3Store::new("http://host", "us-east-1", None).is_err());
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn amz_dates_are_utc() {
+        let at = |secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        assert_eq!(amz_date(at(0)).expect("epoch"), "19700101T000000Z");
+        assert_eq!(
+            amz_date(at(1_700_000_000)).expect("date"),
+            "20231114T221320Z"
+        );
+        assert_eq!(
+            amz_date(at(951_782_400)).expect("leap day"),
+            "20000229T000000Z"
+        );
+    }
+}
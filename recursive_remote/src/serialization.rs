@@ -7,19 +7,33 @@ use gix_hash::ObjectId;
 use rand::Rng;
 
 use crate::config::EncryptionKeys;
+use crate::format::FormatHeader;
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub enum ResourceKey {
     Git(Vec<ObjectId>),
-    Annex(String),
+    // The content keys of the chunks, in order.
+    Annex(Vec<String>),
 }
 
+// SHA-1 oids keep their original encodings so that older clients can still read
+// them. Longer hashes get variants of their own, which older clients reject
+// rather than misread.
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Ord, PartialOrd, Eq)]
 pub enum SerializedResourceKey {
+    // Concatenated 20 byte SHA-1 oids.
     Git(Vec<u8>),
+    // A single annexed chunk, as written before blobs were split in the annex.
     Annex(String),
+    // Concatenated 32 byte SHA-256 oids.
+    GitSha256(Vec<u8>),
+    // The content keys of annexed chunks, in order.
+    AnnexChunks(Vec<String>),
 }
 
+const SHA1_LEN: usize = 20;
+const SHA256_LEN: usize = 32;
+
 /// A reference to a blob, such as a pack or state.bincode.
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub struct BlobRef {
@@ -51,12 +65,83 @@ struct SerializedPackRef {
     random_name: [u8; 20],
 }
 
+/// A reference to a segment of a pack manifest. Like packs, segments use a
+/// randomly generated name in the tree.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct SegmentRef {
+    pub blob_ref: BlobRef,
+    pub random_name: [u8; 20],
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedSegmentRef {
+    blob_ref: SerializedBlobRef,
+    random_name: [u8; 20],
+}
+
+/// Every pack pushed to a namespace since it started keeping a manifest, so
+/// that a fetch needn't walk the state history to find them. Older packs are
+/// kept in a chain of segment blobs.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct PackManifest {
+    // The number of packs in the manifest, including those in segments.
+    pub count: u64,
+
+    // Packs since the newest segment, oldest first.
+    pub recent: Vec<PackRef>,
+
+    // The newest segment, which links to the older ones.
+    pub segment: Option<SegmentRef>,
+
+    // If the namespace had packs before it kept a manifest, they are found by
+    // walking the state history back from this state, which is the last one
+    // without a manifest.
+    pub history: Option<StateRef>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedPackManifest {
+    count: u64,
+    recent: Vec<SerializedPackRef>,
+    segment: Option<SerializedSegmentRef>,
+    history: Option<SerializedStateRef>,
+}
+
+/// A full run of packs from a manifest, oldest first.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct PackSegment {
+    // The position in the manifest of the first pack.
+    pub start: u64,
+    pub packs: Vec<PackRef>,
+    pub previous: Option<SegmentRef>,
+
+    // The chunk features needed to read the packs.
+    pub chunk_features: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedPackSegment {
+    start: u64,
+    packs: Vec<SerializedPackRef>,
+    previous: Option<SerializedSegmentRef>,
+}
+
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub struct StateRef(pub BlobRef);
 
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Ord, PartialOrd, Eq)]
 struct SerializedStateRef(SerializedBlobRef);
 
+/// What state.bincode holds in place of a framed state, which clients from
+/// before framing would otherwise misread.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct StatePointer {
+    pub state: StateRef,
+
+    // The chunk features needed to read the state.
+    pub chunk_features: u64,
+}
+
 #[derive(Clone, Eq, PartialEq, Hash)]
 pub struct NamespaceRef(pub BlobRef);
 
@@ -76,6 +161,8 @@ pub enum Ref {
 enum SerializedRef {
     Direct([u8; 20]),
     Symbolic(String, Option<[u8; 20]>),
+    DirectSha256([u8; 32]),
+    SymbolicSha256(String, [u8; 32]),
 }
 
 #[derive(Clone, Eq, PartialEq)]
@@ -83,6 +170,19 @@ pub struct Namespace {
     pub refs: HashMap<String, Ref>,
     pub pack: Option<PackRef>,
     pub random_name: [u8; 20],
+    pub push_options: Vec<String>,
+    pub shallow_basis: HashMap<String, Ref>,
+
+    // None if no client has kept a manifest for the namespace yet.
+    pub manifest: Option<PackManifest>,
+
+    // Describes the update that wrote this namespace, if the pusher keeps an
+    // audit trail.
+    pub audit: Option<AuditRecord>,
+
+    // The format this was read in, and will be written in. None for the
+    // unversioned format.
+    pub format: Option<FormatHeader>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -94,12 +194,89 @@ pub struct SerializedNamespace {
     pack: Option<SerializedPackRef>,
 
     pub random_name: [u8; 20],
+
+    // The `git push -o` options given for the push that wrote this namespace.
+    push_options: Vec<String>,
+
+    // The shallow basis refs that pushes have left out of their packs, as last
+    // seen by a pusher. Objects reachable from these may not be upstream.
+    shallow_basis: BTreeMap<String, SerializedRef>,
+
+    // Every pack in the namespace, if a pusher has kept track.
+    manifest: Option<SerializedPackManifest>,
+
+    // Who wrote this namespace, when and how. Being in the namespace, it is
+    // encrypted with the namespace key and never in cleartext upstream.
+    audit: Option<SerializedAuditRecord>,
 }
 
-#[derive(Default, Clone, Eq, PartialEq)]
+/// Describes the update that wrote a namespace. Earlier updates are described
+/// by the namespace in earlier states.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct AuditRecord {
+    // The configured identity of the pusher, such as "Name <email>".
+    pub pusher: String,
+
+    // The host the update came from, if known.
+    pub host: Option<String>,
+
+    // Seconds since the Unix epoch, by the pusher's clock.
+    pub time: i64,
+
+    // The version of recursive_remote that made the update.
+    pub client_version: String,
+
+    // Each ref the update changed, by name.
+    pub ref_changes: Vec<RefChange>,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RefChange {
+    pub name: String,
+
+    // None if the ref was created or deleted, respectively.
+    pub old: Option<Ref>,
+    pub new: Option<Ref>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedAuditRecord {
+    pusher: String,
+    host: Option<String>,
+    time: i64,
+    client_version: String,
+    ref_changes: Vec<SerializedRefChange>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedRefChange {
+    name: String,
+    old: Option<SerializedRef>,
+    new: Option<SerializedRef>,
+}
+
+// The first layout of SerializedNamespace. Every later layout appends fields to
+// it, which SerializedNamespace::deserialize reads in turn.
+#[derive(serde::Deserialize)]
+struct LegacySerializedNamespace {
+    refs: BTreeMap<String, SerializedRef>,
+    pack: Option<SerializedPackRef>,
+    random_name: [u8; 20],
+}
+
+#[derive(Clone, Eq, PartialEq)]
 pub struct State {
     pub namespaces: HashMap<String, NamespaceRef>,
     pub parents: Vec<StateRef>,
+
+    // The last state of the chain this one replaces, if it begins a new epoch.
+    // An epoch has no parents, and needs nothing from before it to be read.
+    pub supersedes: Option<StateRef>,
+
+    // The format this was read in, and will be written in. Branches in the
+    // unversioned format (None) keep it until migrated, so as not to lock out
+    // older clients.
+    pub format: Option<FormatHeader>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -109,6 +286,17 @@ pub struct SerializedState {
     // The parent SerializedState blobs. This mirrors Git's own history using
     // sha256.
     parents: Vec<SerializedStateRef>,
+
+    // The SerializedState blob this epoch supersedes.
+    supersedes: Option<SerializedStateRef>,
+}
+
+// The layout of SerializedState before epochs, which is a prefix of the
+// current one.
+#[derive(serde::Deserialize)]
+struct LegacySerializedState {
+    namespaces: BTreeMap<String, SerializedNamespaceRef>,
+    parents: Vec<SerializedStateRef>,
 }
 
 impl Namespace {
@@ -118,6 +306,62 @@ impl Namespace {
             refs: HashMap::new(),
             pack: None,
             random_name,
+            push_options: Vec::new(),
+            shallow_basis: HashMap::new(),
+            manifest: None,
+            audit: None,
+            format: Some(FormatHeader::current()),
+        }
+    }
+
+    /// Reads namespace.bincode in either the current or the unversioned format.
+    pub fn from_bytes(buf: &[u8]) -> Result<Namespace> {
+        // Versioned blobs may also lack trailing fields, such as the manifest
+        // when its feature is not set.
+        let (format, buf) = crate::format::split_header(buf)?;
+        let namespace = SerializedNamespace::deserialize(buf)?;
+        let mut namespace: Namespace = (&namespace).try_into()?;
+        namespace.format = format;
+        Ok(namespace)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        // Clients that don't know about manifests could neither find the packs
+        // only it lists nor keep it, so only the versioned format can hold one.
+        let manifest_features = match self.manifest {
+            Some(..) => crate::format::FEATURE_PACK_MANIFEST,
+            None => 0,
+        };
+        let recent = self.manifest.iter().flat_map(|manifest| &manifest.recent);
+        let annex_features = annex_features(self.pack.iter().chain(recent));
+        crate::format::serialize(
+            self.format.as_ref(),
+            manifest_features
+                | annex_features
+                | crate::format::chunk_features(self.format.as_ref()),
+            manifest_features,
+            &SerializedNamespace::try_from(self)?,
+        )
+    }
+
+    /// The kind of object ids held by the namespace's refs, if any.
+    pub fn object_hash(&self) -> Option<gix_hash::Kind> {
+        self.refs
+            .values()
+            .find_map(Ref::oid_at_time)
+            .map(|oid| oid.kind())
+    }
+
+    /// Fails if the namespace holds object ids of another kind, since objects
+    /// can't be exchanged between repos with different object formats.
+    pub fn check_object_hash(&self, object_hash: gix_hash::Kind) -> Result<()> {
+        match self.object_hash() {
+            Some(namespace_hash) if namespace_hash != object_hash => anyhow::bail!(
+                "the namespace holds {} objects but this repository uses {}",
+                crate::util::object_format_name(namespace_hash),
+                crate::util::object_format_name(object_hash)
+            ),
+            _ => Ok(()),
         }
     }
 }
@@ -128,6 +372,18 @@ impl Default for Namespace {
     }
 }
 
+// A new branch starts out in the current format.
+impl Default for State {
+    fn default() -> Self {
+        State {
+            namespaces: HashMap::new(),
+            parents: Vec::new(),
+            supersedes: None,
+            format: Some(FormatHeader::current()),
+        }
+    }
+}
+
 impl std::fmt::Display for StateRef {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
@@ -155,7 +411,8 @@ impl std::fmt::Display for BlobRef {
 impl std::fmt::Display for ResourceKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ResourceKey::Annex(key) => write!(f, "annex:{}", &key),
+            ResourceKey::Annex(keys) if keys.len() == 1 => write!(f, "annex:{}", &keys[0]),
+            ResourceKey::Annex(keys) => write!(f, "annex:({})", keys.join(", ")),
             ResourceKey::Git(oids) if oids.is_empty() => f.write_str("git:()"),
             ResourceKey::Git(oids) if oids.len() == 1 => write!(f, "git:{}", &oids[0]),
             ResourceKey::Git(oids) => {
@@ -182,8 +439,39 @@ impl std::fmt::Display for Ref {
     }
 }
 
+impl std::fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at {} +0000", &self.pusher, self.time)?;
+        if let Some(host) = self.host.as_ref() {
+            write!(f, " from {}", host)?;
+        }
+        write!(f, " with recursive_remote {}", &self.client_version)
+    }
+}
+
+impl std::fmt::Display for RefChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |r: &Option<Ref>| match r {
+            Some(r) => r.to_string(),
+            None => "(none)".to_string(),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            &self.name,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
 impl std::fmt::Display for State {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Format {}",
+            crate::format::describe(self.format.as_ref())
+        )?;
         for (name, namespace) in self.namespaces.iter() {
             writeln!(f, "Namespace {}: {}\n---\n", name, namespace)?;
         }
@@ -196,6 +484,9 @@ impl std::fmt::Display for State {
         if self.parents.is_empty() {
             writeln!(f, "<no parents>")?;
         }
+        if let Some(supersedes) = self.supersedes.as_ref() {
+            writeln!(f, "Supersedes {}", &supersedes)?;
+        }
         Ok(())
     }
 }
@@ -206,18 +497,13 @@ impl std::convert::TryFrom<&SerializedResourceKey> for ResourceKey {
     fn try_from(r: &SerializedResourceKey) -> Result<ResourceKey> {
         Ok(match r {
             SerializedResourceKey::Git(s_oids) => {
-                if s_oids.len() % 20 != 0 {
-                    anyhow::bail!("oids are 20 bytes each");
-                }
-                let mut oids = Vec::with_capacity(s_oids.len() / 20);
-                if !s_oids.is_empty() {
-                    for oid in s_oids.chunks_exact(20) {
-                        oids.push(ObjectId::from_bytes_or_panic(oid));
-                    }
-                }
-                ResourceKey::Git(oids)
+                ResourceKey::Git(oids_from_bytes(s_oids, SHA1_LEN)?)
+            }
+            SerializedResourceKey::GitSha256(s_oids) => {
+                ResourceKey::Git(oids_from_bytes(s_oids, SHA256_LEN)?)
             }
-            SerializedResourceKey::Annex(key) => ResourceKey::Annex(key.clone()),
+            SerializedResourceKey::Annex(key) => ResourceKey::Annex(vec![key.clone()]),
+            SerializedResourceKey::AnnexChunks(keys) => ResourceKey::Annex(keys.clone()),
         })
     }
 }
@@ -226,17 +512,37 @@ impl std::convert::From<&ResourceKey> for SerializedResourceKey {
     fn from(r: &ResourceKey) -> SerializedResourceKey {
         match r {
             ResourceKey::Git(oids) => {
-                let mut s_oids = Vec::with_capacity(oids.len() * 20);
+                let mut s_oids =
+                    Vec::with_capacity(oids.iter().map(|oid| oid.as_bytes().len()).sum());
                 for oid in oids {
                     s_oids.extend_from_slice(oid.as_bytes());
                 }
-                SerializedResourceKey::Git(s_oids)
+                match oids.first() {
+                    Some(oid) if oid.as_bytes().len() == SHA256_LEN => {
+                        SerializedResourceKey::GitSha256(s_oids)
+                    }
+                    _ => SerializedResourceKey::Git(s_oids),
+                }
             }
-            ResourceKey::Annex(key) => SerializedResourceKey::Annex(key.clone()),
+            ResourceKey::Annex(keys) => SerializedResourceKey::AnnexChunks(keys.clone()),
         }
     }
 }
 
+// Splits concatenated oids of `len` bytes each. Fails cleanly for a hash this
+// build of gix does not support.
+fn oids_from_bytes(s_oids: &[u8], len: usize) -> Result<Vec<ObjectId>> {
+    if s_oids.len() % len != 0 {
+        anyhow::bail!("oids are {} bytes each", len);
+    }
+    s_oids
+        .chunks_exact(len)
+        .map(|oid| {
+            ObjectId::try_from(oid).with_context(|| format!("unsupported {} byte object id", len))
+        })
+        .collect()
+}
+
 impl BlobRef {
     pub fn oids(&self) -> &[ObjectId] {
         match &self.resource_key {
@@ -316,14 +622,28 @@ impl std::convert::From<&NamespaceRef> for SerializedNamespaceRef {
     }
 }
 
-impl std::convert::From<Ref> for SerializedRef {
-    fn from(r: Ref) -> SerializedRef {
-        match r {
-            Ref::Direct(s) => SerializedRef::Direct(s.as_bytes().try_into().expect("")),
-            Ref::Symbolic(s, d) => {
-                SerializedRef::Symbolic(s, d.map(|d| d.as_bytes().try_into().expect("")))
-            }
-        }
+impl std::convert::TryFrom<Ref> for SerializedRef {
+    type Error = anyhow::Error;
+
+    fn try_from(r: Ref) -> Result<SerializedRef> {
+        // Oids are either 20 or 32 bytes, and a symbolic ref whose target
+        // didn't resolve has no oid and so no particular hash.
+        let sha256 = |oid: ObjectId| -> Result<[u8; 32]> {
+            oid.as_bytes()
+                .try_into()
+                .with_context(|| format!("unsupported {} byte object id", oid.as_bytes().len()))
+        };
+        Ok(match r {
+            Ref::Direct(oid) => match oid.as_bytes().try_into() {
+                Ok(sha1) => SerializedRef::Direct(sha1),
+                Err(..) => SerializedRef::DirectSha256(sha256(oid)?),
+            },
+            Ref::Symbolic(s, None) => SerializedRef::Symbolic(s, None),
+            Ref::Symbolic(s, Some(oid)) => match oid.as_bytes().try_into() {
+                Ok(sha1) => SerializedRef::Symbolic(s, Some(sha1)),
+                Err(..) => SerializedRef::SymbolicSha256(s, sha256(oid)?),
+            },
+        })
     }
 }
 
@@ -331,11 +651,13 @@ impl std::convert::TryFrom<SerializedRef> for Ref {
     type Error = anyhow::Error;
 
     fn try_from(r: SerializedRef) -> Result<Ref> {
+        let sha256 =
+            |d: [u8; 32]| ObjectId::try_from(&d[..]).context("unsupported 32 byte object id");
         Ok(match r {
-            SerializedRef::Direct(s) => Ref::Direct(ObjectId::from_bytes_or_panic(&s)),
-            SerializedRef::Symbolic(s, d) => {
-                Ref::Symbolic(s, d.map(|d| ObjectId::from_bytes_or_panic(&d)))
-            }
+            SerializedRef::Direct(s) => Ref::Direct(ObjectId::from(s)),
+            SerializedRef::Symbolic(s, d) => Ref::Symbolic(s, d.map(ObjectId::from)),
+            SerializedRef::DirectSha256(d) => Ref::Direct(sha256(d)?),
+            SerializedRef::SymbolicSha256(s, d) => Ref::Symbolic(s, Some(sha256(d)?)),
         })
     }
 }
@@ -410,11 +732,40 @@ impl std::convert::TryFrom<&SerializedState> for State {
         Ok(State {
             namespaces,
             parents,
+            supersedes: r
+                .supersedes
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()
+                .context("supersedes conversion")?,
+            format: None,
         })
     }
 }
 
 impl State {
+    /// Reads state.bincode in either the current or the unversioned format.
+    pub fn from_bytes(buf: &[u8]) -> Result<State> {
+        let (format, buf) = crate::format::split_header(buf)?;
+        let state = SerializedState::deserialize(buf)?;
+        let mut state: State = (&state).try_into()?;
+        state.format = format;
+        Ok(state)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let read_features = match self.supersedes {
+            Some(..) => crate::format::FEATURE_STATE_EPOCH,
+            None => 0,
+        };
+        crate::format::serialize(
+            self.format.as_ref(),
+            read_features | crate::format::chunk_features(self.format.as_ref()),
+            0,
+            &SerializedState::from(self),
+        )
+    }
+
     pub fn namespace(
         &self,
         namespace: &str,
@@ -431,6 +782,32 @@ impl State {
     }
 }
 
+impl StatePointer {
+    /// Reads state.bincode as a pointer, or None if it holds the state itself.
+    pub fn from_bytes(buf: &[u8]) -> Result<Option<StatePointer>> {
+        let (format, buf) = crate::format::split_header(buf)?;
+        let Some(format) = format
+            .filter(|format| format.read_features & crate::format::FEATURE_STATE_POINTER != 0)
+        else {
+            return Ok(None);
+        };
+        let state: SerializedStateRef = bincode::deserialize(buf).context("state pointer")?;
+        Ok(Some(StatePointer {
+            state: (&state).try_into()?,
+            chunk_features: crate::format::chunk_features(Some(&format)),
+        }))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        crate::format::serialize(
+            Some(&FormatHeader::current()),
+            crate::format::FEATURE_STATE_POINTER | self.chunk_features,
+            0,
+            &SerializedStateRef::from(&self.state),
+        )
+    }
+}
+
 impl std::convert::From<&State> for SerializedState {
     fn from(r: &State) -> SerializedState {
         let mut parents: Vec<_> = r.parents.iter().map(Into::into).collect();
@@ -442,10 +819,67 @@ impl std::convert::From<&State> for SerializedState {
                 .map(|(k, v)| (k.clone(), v.into()))
                 .collect(),
             parents,
+            supersedes: r.supersedes.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl SerializedState {
+    pub fn deserialize(buf: &[u8]) -> Result<SerializedState> {
+        // As with namespaces, a state ends before the fields its writer didn't
+        // know about.
+        let mut reader = buf;
+        let state: LegacySerializedState =
+            bincode::deserialize_from(&mut reader).context("state")?;
+        Ok(SerializedState {
+            namespaces: state.namespaces,
+            parents: state.parents,
+            supersedes: read_appended(&mut reader).context("supersedes")?,
+        })
+    }
+}
+
+impl From<LegacySerializedNamespace> for SerializedNamespace {
+    fn from(r: LegacySerializedNamespace) -> SerializedNamespace {
+        SerializedNamespace {
+            refs: r.refs,
+            pack: r.pack,
+            random_name: r.random_name,
+            push_options: Vec::new(),
+            shallow_basis: BTreeMap::new(),
+            manifest: None,
+            audit: None,
         }
     }
 }
 
+impl SerializedNamespace {
+    /// Reads any layout of SerializedNamespace. Each appended a field to the
+    /// one before, so a blob's layout is given by where its bytes end: every
+    /// field it has must parse, and those after its end take their defaults.
+    pub fn deserialize(buf: &[u8]) -> Result<SerializedNamespace> {
+        let mut reader = buf;
+        let legacy: LegacySerializedNamespace =
+            bincode::deserialize_from(&mut reader).context("namespace")?;
+        Ok(SerializedNamespace {
+            push_options: read_appended(&mut reader).context("push options")?,
+            shallow_basis: read_appended(&mut reader).context("shallow basis")?,
+            manifest: read_appended(&mut reader).context("pack manifest")?,
+            audit: read_appended(&mut reader).context("audit record")?,
+            ..SerializedNamespace::from(legacy)
+        })
+    }
+}
+
+// Reads a field appended to a layout, which blobs written before it was added
+// end without.
+fn read_appended<T: serde::de::DeserializeOwned + Default>(reader: &mut &[u8]) -> Result<T> {
+    if reader.is_empty() {
+        return Ok(T::default());
+    }
+    Ok(bincode::deserialize_from(reader)?)
+}
+
 impl std::convert::TryFrom<&SerializedNamespace> for Namespace {
     type Error = anyhow::Error;
 
@@ -455,6 +889,11 @@ impl std::convert::TryFrom<&SerializedNamespace> for Namespace {
             refs.insert(k.clone(), (*v).clone().try_into().context("ref")?);
         }
 
+        let mut shallow_basis = HashMap::new();
+        for (k, v) in r.shallow_basis.iter() {
+            shallow_basis.insert(k.clone(), (*v).clone().try_into().context("shallow basis")?);
+        }
+
         Ok(Namespace {
             refs,
             pack: r
@@ -464,21 +903,204 @@ impl std::convert::TryFrom<&SerializedNamespace> for Namespace {
                 .transpose()
                 .context("convert pack ref")?,
             random_name: r.random_name,
+            push_options: r.push_options.clone(),
+            shallow_basis,
+            manifest: r
+                .manifest
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()
+                .context("convert pack manifest")?,
+            audit: r
+                .audit
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()
+                .context("convert audit record")?,
+            format: None,
         })
     }
 }
 
-impl std::convert::From<&Namespace> for SerializedNamespace {
-    fn from(r: &Namespace) -> SerializedNamespace {
-        SerializedNamespace {
-            refs: r
-                .refs
-                .iter()
-                .map(|(k, v)| (k.clone(), v.clone().into()))
-                .collect(),
+impl std::convert::TryFrom<&Namespace> for SerializedNamespace {
+    type Error = anyhow::Error;
+
+    fn try_from(r: &Namespace) -> Result<SerializedNamespace> {
+        let serialize_refs = |refs: &HashMap<String, Ref>| {
+            refs.iter()
+                .map(|(k, v)| {
+                    Ok((
+                        k.clone(),
+                        SerializedRef::try_from(v.clone()).context(k.clone())?,
+                    ))
+                })
+                .collect::<Result<BTreeMap<_, _>>>()
+        };
+        Ok(SerializedNamespace {
+            refs: serialize_refs(&r.refs).context("refs")?,
             pack: r.pack.as_ref().map(Into::into),
             random_name: r.random_name,
+            push_options: r.push_options.clone(),
+            shallow_basis: serialize_refs(&r.shallow_basis).context("shallow basis")?,
+            manifest: r.manifest.as_ref().map(Into::into),
+            audit: r
+                .audit
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()
+                .context("audit record")?,
+        })
+    }
+}
+
+impl std::convert::TryFrom<&SerializedAuditRecord> for AuditRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(r: &SerializedAuditRecord) -> Result<AuditRecord> {
+        let mut ref_changes = Vec::with_capacity(r.ref_changes.len());
+        for change in r.ref_changes.iter() {
+            ref_changes.push(RefChange {
+                name: change.name.clone(),
+                old: change.old.clone().map(TryInto::try_into).transpose()?,
+                new: change.new.clone().map(TryInto::try_into).transpose()?,
+            });
         }
+        Ok(AuditRecord {
+            pusher: r.pusher.clone(),
+            host: r.host.clone(),
+            time: r.time,
+            client_version: r.client_version.clone(),
+            ref_changes,
+        })
+    }
+}
+
+impl std::convert::TryFrom<&AuditRecord> for SerializedAuditRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(r: &AuditRecord) -> Result<SerializedAuditRecord> {
+        let mut ref_changes = Vec::with_capacity(r.ref_changes.len());
+        for change in r.ref_changes.iter() {
+            ref_changes.push(SerializedRefChange {
+                name: change.name.clone(),
+                old: change.old.clone().map(TryInto::try_into).transpose()?,
+                new: change.new.clone().map(TryInto::try_into).transpose()?,
+            });
+        }
+        Ok(SerializedAuditRecord {
+            pusher: r.pusher.clone(),
+            host: r.host.clone(),
+            time: r.time,
+            client_version: r.client_version.clone(),
+            ref_changes,
+        })
+    }
+}
+
+impl std::convert::TryFrom<&SerializedSegmentRef> for SegmentRef {
+    type Error = anyhow::Error;
+
+    fn try_from(r: &SerializedSegmentRef) -> Result<SegmentRef> {
+        let blob_ref = (&r.blob_ref).try_into().context("blob_ref")?;
+        Ok(SegmentRef {
+            blob_ref,
+            random_name: r.random_name,
+        })
+    }
+}
+
+impl std::convert::From<&SegmentRef> for SerializedSegmentRef {
+    fn from(r: &SegmentRef) -> SerializedSegmentRef {
+        SerializedSegmentRef {
+            blob_ref: (&r.blob_ref).into(),
+            random_name: r.random_name,
+        }
+    }
+}
+
+impl std::convert::TryFrom<&SerializedPackManifest> for PackManifest {
+    type Error = anyhow::Error;
+
+    fn try_from(r: &SerializedPackManifest) -> Result<PackManifest> {
+        Ok(PackManifest {
+            count: r.count,
+            recent: r
+                .recent
+                .iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_>>()
+                .context("recent packs")?,
+            segment: r
+                .segment
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()
+                .context("segment")?,
+            history: r
+                .history
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()
+                .context("history")?,
+        })
+    }
+}
+
+impl std::convert::From<&PackManifest> for SerializedPackManifest {
+    fn from(r: &PackManifest) -> SerializedPackManifest {
+        SerializedPackManifest {
+            count: r.count,
+            recent: r.recent.iter().map(Into::into).collect(),
+            segment: r.segment.as_ref().map(Into::into),
+            history: r.history.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl PackSegment {
+    pub fn from_bytes(buf: &[u8]) -> Result<PackSegment> {
+        let (format, buf) = crate::format::split_header(buf)?;
+        let r = bincode::deserialize::<SerializedPackSegment>(buf)?;
+        Ok(PackSegment {
+            chunk_features: crate::format::chunk_features(format.as_ref()),
+            start: r.start,
+            packs: r
+                .packs
+                .iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_>>()
+                .context("packs")?,
+            previous: r
+                .previous
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()
+                .context("previous")?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        // Segments postdate format versioning, so always have a header.
+        let segment = SerializedPackSegment {
+            start: self.start,
+            packs: self.packs.iter().map(Into::into).collect(),
+            previous: self.previous.as_ref().map(Into::into),
+        };
+        crate::format::serialize(
+            Some(&FormatHeader::current()),
+            annex_features(self.packs.iter()) | self.chunk_features,
+            0,
+            &segment,
+        )
+    }
+}
+
+// The read features needed by a blob listing `packs`.
+fn annex_features<'a>(mut packs: impl Iterator<Item = &'a PackRef>) -> u64 {
+    if packs.any(|pack| matches!(pack.blob_ref.resource_key, ResourceKey::Annex(..))) {
+        crate::format::FEATURE_ANNEX
+    } else {
+        0
     }
 }
 
@@ -508,6 +1130,90 @@ mod tests {
         assert!(format!("{err}").contains("20 bytes each"));
     }
 
+    #[test]
+    fn sha1_oids_keep_their_original_encoding() {
+        let key = ResourceKey::Git(vec![oid("1111111111111111111111111111111111111111")]);
+        assert!(matches!(
+            SerializedResourceKey::from(&key),
+            SerializedResourceKey::Git(_)
+        ));
+        let direct = oid("2222222222222222222222222222222222222222");
+        assert!(matches!(
+            SerializedRef::try_from(Ref::Direct(direct)).expect("serialize ref"),
+            SerializedRef::Direct(_)
+        ));
+        assert!(matches!(
+            SerializedRef::try_from(Ref::Symbolic("refs/heads/main".to_string(), Some(direct)))
+                .expect("serialize ref"),
+            SerializedRef::Symbolic(_, Some(_))
+        ));
+    }
+
+    #[test]
+    fn sha256_oids_decode_or_fail_cleanly() {
+        // Whether gix was built with SHA-256 support or not, decoding must not
+        // panic.
+        match Ref::try_from(SerializedRef::DirectSha256([0xcc; 32])) {
+            Ok(Ref::Direct(oid)) => {
+                assert_eq!(oid.as_bytes(), &[0xcc; 32]);
+                assert!(matches!(
+                    SerializedRef::try_from(Ref::Direct(oid)).expect("serialize ref"),
+                    SerializedRef::DirectSha256(_)
+                ));
+            }
+            Ok(other) => panic!("unexpected ref {other:?}"),
+            Err(e) => assert!(format!("{e:#}").contains("unsupported 32 byte object id")),
+        }
+
+        let serialized = SerializedResourceKey::GitSha256(vec![0xdd; 64]);
+        match ResourceKey::try_from(&serialized) {
+            Ok(key) => {
+                assert_eq!(key.to_string().matches("dd").count(), 64);
+                assert!(SerializedResourceKey::from(&key) == serialized);
+            }
+            Err(e) => assert!(format!("{e:#}").contains("unsupported 32 byte object id")),
+        }
+
+        let serialized = SerializedResourceKey::GitSha256(vec![0xdd; 40]);
+        let err = ResourceKey::try_from(&serialized).expect_err("must fail");
+        assert!(format!("{err}").contains("32 bytes each"));
+    }
+
+    #[test]
+    fn resource_key_annex_reads_single_chunks() {
+        let legacy = SerializedResourceKey::Annex("SHA256-s1--00".to_string());
+        let key = ResourceKey::try_from(&legacy).expect("legacy annex key");
+        assert_eq!(key, ResourceKey::Annex(vec!["SHA256-s1--00".to_string()]));
+
+        let chunks = ResourceKey::Annex(vec![
+            "SHA256-s1--00".to_string(),
+            "SHA256-s2--11".to_string(),
+        ]);
+        let serialized = SerializedResourceKey::from(&chunks);
+        assert!(matches!(serialized, SerializedResourceKey::AnnexChunks(_)));
+        assert_eq!(
+            ResourceKey::try_from(&serialized).expect("annex chunks"),
+            chunks
+        );
+        assert_eq!(chunks.to_string(), "annex:(SHA256-s1--00, SHA256-s2--11)");
+    }
+
+    #[test]
+    fn namespace_check_object_hash() {
+        let mut namespace = Namespace::new();
+        namespace
+            .check_object_hash(gix_hash::Kind::Sha1)
+            .expect("empty namespace takes any hash");
+        namespace.refs.insert(
+            "refs/heads/main".to_string(),
+            Ref::Direct(oid("2222222222222222222222222222222222222222")),
+        );
+        assert_eq!(namespace.object_hash(), Some(gix_hash::Kind::Sha1));
+        namespace
+            .check_object_hash(gix_hash::Kind::Sha1)
+            .expect("same hash");
+    }
+
     #[test]
     fn namespace_conversion_roundtrip() {
         let pack = PackRef {
@@ -533,15 +1239,176 @@ mod tests {
             ),
         );
 
+        let mut shallow_basis = HashMap::new();
+        shallow_basis.insert(
+            "refs/tags/base".to_string(),
+            Ref::Direct(oid("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee")),
+        );
+
+        let manifest = PackManifest {
+            count: 70,
+            recent: vec![pack.clone()],
+            segment: Some(SegmentRef {
+                blob_ref: pack.blob_ref.clone(),
+                random_name: [4; 20],
+            }),
+            history: Some(StateRef(pack.blob_ref.clone())),
+        };
+
         let namespace = Namespace {
             refs,
             pack: Some(pack),
             random_name: [9; 20],
+            push_options: vec!["release 4.2".to_string()],
+            shallow_basis,
+            manifest: Some(manifest),
+            audit: Some(AuditRecord {
+                pusher: "Test User <you@example.com>".to_string(),
+                host: Some("builder".to_string()),
+                time: 1_700_000_000,
+                client_version: "0.2.1".to_string(),
+                ref_changes: vec![
+                    RefChange {
+                        name: "refs/heads/main".to_string(),
+                        old: None,
+                        new: Some(Ref::Direct(oid("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"))),
+                    },
+                    RefChange {
+                        name: "refs/heads/old".to_string(),
+                        old: Some(Ref::Direct(oid("cccccccccccccccccccccccccccccccccccccccc"))),
+                        new: None,
+                    },
+                ],
+            }),
+            format: None,
         };
 
-        let serialized: SerializedNamespace = (&namespace).into();
+        let serialized = SerializedNamespace::try_from(&namespace).expect("serialize namespace");
         let decoded = Namespace::try_from(&serialized).expect("decode namespace");
         assert!(namespace == decoded);
+
+        let buf = bincode::serialize(&serialized).expect("serialize");
+        let decoded = SerializedNamespace::deserialize(&buf).expect("deserialize");
+        let decoded = Namespace::try_from(&decoded).expect("decode namespace");
+        assert!(namespace == decoded);
+
+        // Older clients can't be told about the manifest in the unversioned
+        // format, so it can't hold one.
+        namespace
+            .to_bytes()
+            .expect_err("unversioned bytes with a manifest");
+        let unversioned = Namespace {
+            manifest: None,
+            ..namespace.clone()
+        };
+        let buf = unversioned.to_bytes().expect("unversioned bytes");
+        assert!(Namespace::from_bytes(&buf).expect("from bytes") == unversioned);
+
+        // The manifest is flagged, so that older clients neither miss the packs
+        // it lists nor drop it.
+        let namespace = Namespace {
+            format: Some(FormatHeader {
+                read_features: crate::format::FEATURE_PACK_MANIFEST,
+                write_features: crate::format::FEATURE_PACK_MANIFEST,
+                ..FormatHeader::current()
+            }),
+            ..namespace
+        };
+        let buf = namespace.to_bytes().expect("versioned bytes");
+        assert!(Namespace::from_bytes(&buf).expect("from bytes") == namespace);
+    }
+
+    #[test]
+    fn namespace_deserializes_legacy_layout() {
+        let mut refs = BTreeMap::new();
+        refs.insert(
+            "refs/heads/main".to_string(),
+            SerializedRef::Direct([0xbb; 20]),
+        );
+        // Bincode encodes a struct the same way as a tuple of its fields.
+        let buf = bincode::serialize(&(refs, None::<SerializedPackRef>, [5u8; 20]))
+            .expect("serialize legacy");
+
+        let decoded = SerializedNamespace::deserialize(&buf).expect("deserialize");
+        let decoded = Namespace::try_from(&decoded).expect("decode namespace");
+        assert_eq!(decoded.random_name, [5; 20]);
+        assert!(decoded.push_options.is_empty());
+        assert!(decoded.shallow_basis.is_empty());
+        assert_eq!(
+            decoded.refs.get("refs/heads/main"),
+            Some(&Ref::Direct(oid(
+                "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"
+            )))
+        );
+
+        let buf = bincode::serialize(&(
+            BTreeMap::<String, SerializedRef>::new(),
+            None::<SerializedPackRef>,
+            [6u8; 20],
+            vec!["release 4.2".to_string()],
+        ))
+        .expect("serialize push options layout");
+        let decoded = SerializedNamespace::deserialize(&buf).expect("deserialize");
+        let decoded = Namespace::try_from(&decoded).expect("decode namespace");
+        assert_eq!(decoded.random_name, [6; 20]);
+        assert_eq!(decoded.push_options, vec!["release 4.2".to_string()]);
+        assert!(decoded.shallow_basis.is_empty());
+
+        let mut shallow_basis = BTreeMap::new();
+        shallow_basis.insert(
+            "refs/tags/base".to_string(),
+            SerializedRef::Direct([0xee; 20]),
+        );
+        let buf = bincode::serialize(&(
+            BTreeMap::<String, SerializedRef>::new(),
+            None::<SerializedPackRef>,
+            [7u8; 20],
+            Vec::<String>::new(),
+            shallow_basis,
+        ))
+        .expect("serialize shallow basis layout");
+        let decoded = SerializedNamespace::deserialize(&buf).expect("deserialize");
+        let decoded = Namespace::try_from(&decoded).expect("decode namespace");
+        assert_eq!(decoded.random_name, [7; 20]);
+        assert_eq!(decoded.shallow_basis.len(), 1);
+        assert!(decoded.manifest.is_none());
+
+        let buf = bincode::serialize(&(
+            BTreeMap::<String, SerializedRef>::new(),
+            None::<SerializedPackRef>,
+            [8u8; 20],
+            Vec::<String>::new(),
+            BTreeMap::<String, SerializedRef>::new(),
+            Some(SerializedPackManifest {
+                count: 0,
+                recent: Vec::new(),
+                segment: None,
+                history: None,
+            }),
+        ))
+        .expect("serialize manifest layout");
+        let decoded = SerializedNamespace::deserialize(&buf).expect("deserialize");
+        let decoded = Namespace::try_from(&decoded).expect("decode namespace");
+        assert_eq!(decoded.random_name, [8; 20]);
+        assert_eq!(decoded.manifest, Some(PackManifest::default()));
+        assert!(decoded.audit.is_none());
+    }
+
+    #[test]
+    fn namespace_reports_corrupt_appended_fields() {
+        let mut buf = bincode::serialize(&(
+            BTreeMap::<String, SerializedRef>::new(),
+            None::<SerializedPackRef>,
+            [8u8; 20],
+            Vec::<String>::new(),
+            BTreeMap::<String, SerializedRef>::new(),
+        ))
+        .expect("serialize shallow basis layout");
+        // An Option tag that is neither None nor Some, where the manifest goes.
+        buf.push(7);
+
+        let err = SerializedNamespace::deserialize(&buf).expect_err("corrupt manifest");
+        assert!(format!("{err:#}").contains("pack manifest"), "{err:#}");
     }
 
     #[test]
@@ -560,6 +1427,8 @@ mod tests {
         let state = State {
             namespaces: HashMap::new(),
             parents: vec![high.clone(), low.clone()],
+            supersedes: None,
+            format: None,
         };
 
         let serialized: SerializedState = (&state).into();
@@ -568,6 +1437,120 @@ mod tests {
         assert_eq!(decoded.parents, vec![low, high]);
     }
 
+    #[test]
+    fn state_keeps_its_format() {
+        let mut state = State::default();
+        let buf = state.to_bytes().expect("versioned bytes");
+        assert!(buf.starts_with(b"rrformat"));
+        let decoded = State::from_bytes(&buf).expect("from bytes");
+        assert_eq!(decoded.format, Some(FormatHeader::current()));
+        assert!(decoded == state);
+
+        // What the state lists was chunked with features it must announce, and
+        // keep announcing for as long as it lists them.
+        let mut chunked = state.clone();
+        crate::format::note_chunk_features(
+            &mut chunked.format,
+            crate::format::FEATURE_CHUNK_FRAMING,
+        );
+        let decoded =
+            State::from_bytes(&chunked.to_bytes().expect("versioned bytes")).expect("from bytes");
+        assert_eq!(
+            decoded.format.map(|format| format.read_features),
+            Some(crate::format::FEATURE_CHUNK_FRAMING)
+        );
+        assert!(decoded == chunked);
+
+        // Unversioned branches are written exactly as older clients expect.
+        state.format = None;
+        let buf = state.to_bytes().expect("unversioned bytes");
+        assert_eq!(
+            buf,
+            bincode::serialize(&SerializedState::from(&state)).expect("serialize")
+        );
+        assert!(State::from_bytes(&buf).expect("from bytes") == state);
+    }
+
+    #[test]
+    fn epochs_need_a_client_that_knows_them() {
+        let superseded = StateRef(BlobRef {
+            resource_key: ResourceKey::Git(vec![oid("cccccccccccccccccccccccccccccccccccccccc")]),
+            sha256: [3; 32],
+        });
+        let epoch = State {
+            supersedes: Some(superseded.clone()),
+            ..State::default()
+        };
+        let buf = epoch.to_bytes().expect("versioned bytes");
+        let decoded = State::from_bytes(&buf).expect("from bytes");
+        assert_eq!(decoded.supersedes, Some(superseded));
+        assert_eq!(
+            decoded.format.map(|format| format.read_features),
+            Some(crate::format::FEATURE_STATE_EPOCH)
+        );
+
+        // Only the epoch itself is marked, not the states pushed on top of it.
+        let buf = State::default().to_bytes().expect("versioned bytes");
+        let decoded = State::from_bytes(&buf).expect("from bytes");
+        assert_eq!(decoded.format.map(|format| format.read_features), Some(0));
+
+        let unversioned = State {
+            format: None,
+            ..epoch
+        };
+        assert!(unversioned.to_bytes().is_err());
+
+        // States from before epochs still read.
+        let buf = bincode::serialize(&(
+            BTreeMap::<String, SerializedNamespaceRef>::new(),
+            Vec::<SerializedStateRef>::new(),
+        ))
+        .expect("serialize legacy layout");
+        let decoded = State::from_bytes(&buf).expect("from bytes");
+        assert!(decoded.supersedes.is_none());
+    }
+
+    #[test]
+    fn annexed_packs_need_a_client_that_knows_them() {
+        let pack = PackRef {
+            blob_ref: BlobRef {
+                resource_key: ResourceKey::Annex(vec!["SHA256-s1--00".to_string()]),
+                sha256: [5; 32],
+            },
+            random_name: [6; 20],
+        };
+        let namespace = Namespace {
+            pack: Some(pack.clone()),
+            ..Namespace::new()
+        };
+        let buf = namespace.to_bytes().expect("versioned bytes");
+        let decoded = Namespace::from_bytes(&buf).expect("from bytes");
+        assert_eq!(
+            decoded.format.map(|format| format.read_features),
+            Some(crate::format::FEATURE_ANNEX)
+        );
+
+        let unversioned = Namespace {
+            format: None,
+            ..namespace
+        };
+        assert!(unversioned.to_bytes().is_err());
+
+        let segment = PackSegment {
+            start: 0,
+            packs: vec![pack],
+            previous: None,
+            chunk_features: crate::format::FEATURE_CHUNK_FRAMING,
+        };
+        let buf = segment.to_bytes().expect("segment bytes");
+        let (format, _) = crate::format::split_header(&buf).expect("split");
+        assert_eq!(
+            format.map(|format| format.read_features),
+            Some(crate::format::FEATURE_ANNEX | crate::format::FEATURE_CHUNK_FRAMING)
+        );
+        assert_eq!(PackSegment::from_bytes(&buf).expect("from bytes"), segment);
+    }
+
     #[test]
     fn ref_helpers_behave_as_expected() {
         let direct = Ref::Direct(oid("dddddddddddddddddddddddddddddddddddddddd"));
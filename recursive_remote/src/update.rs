@@ -1,3 +1,4 @@
+use std::cell::RefCell;
 use std::rc::Rc;
 
 use anyhow::{Context, Result};
@@ -18,7 +19,7 @@ pub enum RatchetError {
     RatchetError,
 }
 
-type BranchState = (
+pub type BranchState = (
     Option<StateRef>,
     State,
     Option<StateRef>,
@@ -28,6 +29,28 @@ type BranchState = (
 
 type ResolvedState = (ObjectId, (StateRef, State), ObjectId);
 
+/// The upstream state as of the last fetch in this helper process, shared by
+/// every command git sends during one operation. We hold the remote's lock for
+/// the lifetime of the process, so only upstream can move underneath it, and
+/// then our push fails.
+#[derive(Default)]
+pub struct StateCache(RefCell<Option<BranchState>>);
+
+impl StateCache {
+    fn get(&self) -> Option<BranchState> {
+        self.0.borrow().clone()
+    }
+
+    fn set(&self, branch_state: Option<BranchState>) {
+        *self.0.borrow_mut() = branch_state;
+    }
+
+    /// Forgets the cached state, so the next update fetches from upstream.
+    pub fn invalidate(&self) {
+        self.set(None);
+    }
+}
+
 // Force fetches the remote ref into `pushing_ref`, then validates that it is a
 // fast-forward from the current `tracking_ref` using the sha256 inner hash
 // structure. If not, this is an error. Otherwise, fetches into `tracking_ref`.`
@@ -35,12 +58,37 @@ type ResolvedState = (ObjectId, (StateRef, State), ObjectId);
 // We use the inner structure rather than git history to enforce continuous
 // logical history while allowing for rewriting git history to remove temporary
 // artifacts.
+//
+// Only the first call in a session does any of this; later calls reuse its
+// result, or the state this session has since pushed, until the cache is
+// invalidated.
 pub fn update_branches(config: &Config) -> Result<BranchState> {
+    if let Some(branch_state) = config.state_cache.get() {
+        log::trace!("Using upstream state cached earlier in this session.");
+        return Ok(branch_state);
+    }
+    refresh_branches(config)
+}
+
+// As `update_branches`, but always fetches from upstream, and caches the result
+// for the rest of the session.
+pub fn refresh_branches(config: &Config) -> Result<BranchState> {
+    config.state_cache.invalidate();
+
     log::trace!("Fetching pushing branch from underlying remote.");
     update_pushing_branch(config).context("pushing tracking branch")?;
 
+    cache_pushing_branch(config)
+}
+
+// Advances the tracking branch to the pushing branch, if the ratchet allows,
+// and caches the resulting state for the rest of the session. After we push,
+// the pushing branch is what upstream holds, so this needs no fetch.
+pub fn cache_pushing_branch(config: &Config) -> Result<BranchState> {
     log::trace!("Checking ratchet properties to update tracking branch.");
-    update_tracking_branch(config).context("update tracking branch")
+    let branch_state = update_tracking_branch(config).context("update tracking branch")?;
+    config.state_cache.set(Some(branch_state.clone()));
+    Ok(branch_state)
 }
 
 fn update_pushing_branch(config: &Config) -> Result<()> {
@@ -163,7 +211,8 @@ fn update_tracking_branch(config: &Config) -> Result<BranchState> {
 // git, since we are allowing unrelated history, but think of it as
 // a trust-on-first-use chain.
 //
-// If we can reach `current_ident` from `future_ident`, accept it.
+// If we can reach `current_ident` from `future_ident`, accept it. An epoch is
+// reached through the state it supersedes, as though that were its parent.
 fn valid_path_exists(
     config: &Config,
     tracking_repo: &Rc<gix::Repository>,
@@ -182,20 +231,41 @@ fn valid_path_exists(
         hex::encode(current.0.sha256)
     );
 
-    // This is permissive, since we only care about valid paths.
+    // This is permissive, since we only care about valid paths. A state that
+    // doesn't match its sha256 is passed over in case another path leads back,
+    // but reported, since it may be why none does.
+    let mut corrupt = Vec::new();
     while let Some(traverse) = stack.pop() {
         match encoding::decode_state(tracking_repo, &traverse, &config.nacl_keys) {
-            Ok(state) if state.parents.contains(current) => return Ok(true),
+            Ok(state)
+                if state.parents.contains(current)
+                    || state.supersedes.as_ref() == Some(current) =>
+            {
+                return Ok(true);
+            }
             Ok(mut state) => {
                 stack.append(&mut state.parents);
+                stack.extend(state.supersedes);
             }
-            Err(e) => match e.downcast_ref::<HashError>() {
-                Some(..) => {}
-                None => return Err(e).context("traverse sha256 history"),
-            },
+            Err(e)
+                if e.downcast_ref::<HashError>().is_some()
+                    || e.downcast_ref::<encoding::Sha256Mismatch>().is_some() =>
+            {
+                log::warn!("State {} is corrupt: {:#}", &traverse, e);
+                corrupt.push(traverse);
+            }
+            Err(e) => return Err(e).context("traverse sha256 history"),
         }
     }
 
+    if !corrupt.is_empty() {
+        let corrupt: Vec<_> = corrupt.iter().map(ToString::to_string).collect();
+        anyhow::bail!(
+            "found no path back to state {}, past corrupt state(s) {}",
+            current,
+            corrupt.join(", ")
+        );
+    }
     log::warn!("Failed to find a path back.");
 
     Ok(false)
@@ -207,13 +277,14 @@ mod tests {
     use std::path::Path;
 
     use super::*;
-    use crate::encoding::encode_state;
+    use crate::encoding::{EncodeOptions, encode_state};
     use crate::serialization::{BlobRef, NamespaceRef, ResourceKey};
 
     fn make_config(base: &Path) -> Config {
         Config {
             namespace: "ns".to_string(),
             user_repo_path: base.join("user"),
+            object_hash: gix_hash::Kind::Sha1,
             tracking_repo_path: base.join("tracking"),
             remote_name: "origin".to_string(),
             tracking_ref: "refs/heads/origin/tracking".to_string(),
@@ -227,6 +298,11 @@ mod tests {
             nacl_keys: EncryptionKeys { inner: None },
             shallow_basis: Vec::new(),
             max_object_size: 64,
+            encode_options: EncodeOptions::default(),
+            annex: None,
+            annex_threshold: 64,
+            audit_identity: None,
+            state_cache: StateCache::default(),
         }
     }
 
@@ -265,6 +341,7 @@ mod tests {
                 &current_state,
                 &config.nacl_keys,
                 config.max_object_size,
+                config.encode_options,
             )
             .expect("encode current"),
         );
@@ -272,6 +349,8 @@ mod tests {
         let future_state = State {
             namespaces: HashMap::new(),
             parents: vec![current.clone()],
+            supersedes: None,
+            format: None,
         };
         let future = StateRef(
             encode_state(
@@ -279,6 +358,7 @@ mod tests {
                 &future_state,
                 &config.nacl_keys,
                 config.max_object_size,
+                config.encode_options,
             )
             .expect("encode future"),
         );
@@ -302,6 +382,7 @@ mod tests {
                 &State::default(),
                 &config.nacl_keys,
                 config.max_object_size,
+                config.encode_options,
             )
             .expect("encode current"),
         );
@@ -315,6 +396,8 @@ mod tests {
                 }),
             )]),
             parents: Vec::new(),
+            supersedes: None,
+            format: None,
         };
         let future = StateRef(
             encode_state(
@@ -322,6 +405,7 @@ mod tests {
                 &unrelated,
                 &config.nacl_keys,
                 config.max_object_size,
+                config.encode_options,
             )
             .expect("encode unrelated"),
         );
@@ -330,6 +414,96 @@ mod tests {
         assert!(!ok);
     }
 
+    #[test]
+    fn valid_path_exists_through_a_superseded_chain() {
+        let tmp = tempfile::Builder::new()
+            .prefix("update-tests")
+            .tempdir()
+            .expect("tempdir");
+        let tracking_repo = Rc::new(gix::init_bare(tmp.path().join("tracking")).expect("repo"));
+        let config = make_config(tmp.path());
+        let encode = |state: &State| {
+            StateRef(
+                encode_state(
+                    &tracking_repo,
+                    state,
+                    &config.nacl_keys,
+                    config.max_object_size,
+                    config.encode_options,
+                )
+                .expect("encode state"),
+            )
+        };
+
+        let current = encode(&State::default());
+        let superseded = encode(&State {
+            parents: vec![current.clone()],
+            ..State::default()
+        });
+        let epoch = encode(&State {
+            supersedes: Some(superseded.clone()),
+            ..State::default()
+        });
+        let future = encode(&State {
+            parents: vec![epoch.clone()],
+            ..State::default()
+        });
+
+        for from in [&superseded, &current] {
+            let ok = valid_path_exists(&config, &tracking_repo, from, &future).expect("path");
+            assert!(ok);
+        }
+    }
+
+    #[test]
+    fn valid_path_exists_reports_corrupt_states() {
+        let tmp = tempfile::Builder::new()
+            .prefix("update-tests")
+            .tempdir()
+            .expect("tempdir");
+        let tracking_repo = Rc::new(gix::init_bare(tmp.path().join("tracking")).expect("repo"));
+        let config = make_config(tmp.path());
+        let encode = |state: &State| {
+            StateRef(
+                encode_state(
+                    &tracking_repo,
+                    state,
+                    &config.nacl_keys,
+                    config.max_object_size,
+                    config.encode_options,
+                )
+                .expect("encode state"),
+            )
+        };
+
+        let current = encode(&State::default());
+        let mut corrupt = encode(&State {
+            parents: vec![current.clone()],
+            ..State::default()
+        });
+        corrupt.0.sha256 = [7; 32];
+        let future = encode(&State {
+            parents: vec![corrupt.clone()],
+            ..State::default()
+        });
+
+        let err = valid_path_exists(&config, &tracking_repo, &current, &future)
+            .expect_err("corrupt path");
+        assert!(format!("{err}").contains(&format!("corrupt state(s) {}", &corrupt)));
+
+        // Another path back still counts.
+        let intact = encode(&State {
+            parents: vec![current.clone()],
+            ..State::default()
+        });
+        let future = encode(&State {
+            parents: vec![intact, corrupt],
+            ..State::default()
+        });
+        let ok = valid_path_exists(&config, &tracking_repo, &current, &future).expect("path");
+        assert!(ok);
+    }
+
     #[test]
     fn resolve_state_ref_returns_none_for_missing_ref() {
         let tmp = tempfile::Builder::new()
@@ -342,4 +516,27 @@ mod tests {
             resolve_state_ref(&tracking_repo, &keys, "refs/heads/does-not-exist").expect("resolve");
         assert!(out.is_none());
     }
+
+    #[test]
+    fn update_branches_reuses_cached_state_until_invalidated() {
+        let tmp = tempfile::Builder::new()
+            .prefix("update-tests")
+            .tempdir()
+            .expect("tempdir");
+        let config = make_config(tmp.path());
+        let root_id = ObjectId::from_hex(b"1111111111111111111111111111111111111111").expect("oid");
+        config
+            .state_cache
+            .set(Some((None, State::default(), None, Some(root_id), None)));
+
+        // There is no upstream to fetch from, so this must be the cache.
+        let (state_identifier, state, _basis, cached_root_id, _commit_id) =
+            update_branches(&config).expect("cached state");
+        assert!(state_identifier.is_none());
+        assert!(state == State::default());
+        assert_eq!(cached_root_id, Some(root_id));
+
+        config.state_cache.invalidate();
+        update_branches(&config).expect_err("fetch from missing upstream");
+    }
 }
@@ -143,6 +143,48 @@ pub fn open_create_bare_repository(path: &Path) -> anyhow::Result<gix::Repositor
     }
 }
 
+// Like open_create_bare_repository, but a new repository is created with the
+// given object format and an existing one must already use it.
+pub fn open_create_bare_repository_with_format(
+    path: &Path,
+    object_hash: gix_hash::Kind,
+) -> anyhow::Result<gix::Repository> {
+    let repo = match gix::open(path) {
+        Ok(r) => r,
+        Err(_) if object_hash == gix_hash::Kind::Sha1 => open_create_bare_repository(path)?,
+        Err(_) => {
+            // gix can only initialize SHA-1 repositories.
+            execute_subprocess2(
+                git_command()
+                    .arg("init")
+                    .arg("--bare")
+                    .arg(format!(
+                        "--object-format={}",
+                        object_format_name(object_hash)
+                    ))
+                    .arg(path),
+            )
+            .with_context(|| format!("failed to init bare repository in {}", path.display()))?;
+            gix::open(path).with_context(|| format!("open {}", path.display()))?
+        }
+    };
+
+    if repo.object_hash() != object_hash {
+        anyhow::bail!(
+            "{} uses {} object ids, but {} is needed",
+            path.display(),
+            object_format_name(repo.object_hash()),
+            object_format_name(object_hash)
+        );
+    }
+    Ok(repo)
+}
+
+/// The name git uses for an object format, as in `--object-format`.
+pub fn object_format_name(object_hash: gix_hash::Kind) -> String {
+    object_hash.to_string().to_ascii_lowercase()
+}
+
 fn debug_stream_message<S: Read>(stream: Option<S>, sn: &'static str) -> anyhow::Result<String> {
     match stream {
         Some(mut s) => {
@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+use gix_hash::ObjectId;
+
+use crate::cmd_fetch::{find_missing_objects, index_pack};
+use crate::config::Config;
+use crate::encoding::{Undecryptable, decode_namespace, decode_pack_segment, decode_state};
+use crate::progress::Progress;
+use crate::serialization::*;
+use crate::update::update_branches;
+use crate::util::*;
+
+/// Something in the history of the branch that doesn't check out.
+pub struct BrokenLink {
+    pub what: String,
+    pub error: anyhow::Error,
+}
+
+/// What `verify` checked, and what it found broken.
+#[derive(Default)]
+pub struct VerifyReport {
+    pub states: usize,
+    pub namespaces: usize,
+    pub packs: usize,
+
+    // Namespaces our keys don't read, which can't be checked.
+    pub unreadable: BTreeSet<String>,
+
+    pub broken: Vec<BrokenLink>,
+}
+
+impl VerifyReport {
+    fn record(&mut self, what: String, error: anyhow::Error) {
+        log::warn!("{} is broken: {:#}", &what, &error);
+        self.broken.push(BrokenLink { what, error });
+    }
+}
+
+// Everything a namespace has held under one name over the whole history.
+#[derive(Default)]
+struct NamespaceHistory {
+    // In the order found, so roughly newest first.
+    packs: Vec<PackRef>,
+    seen_packs: HashSet<PackRef>,
+    tips: BTreeSet<ObjectId>,
+    shallow_basis: BTreeSet<ObjectId>,
+    object_hash: Option<gix_hash::Kind>,
+}
+
+impl NamespaceHistory {
+    fn add_pack(&mut self, pack: &PackRef) {
+        if self.seen_packs.insert(pack.clone()) {
+            self.packs.push(pack.clone());
+        }
+    }
+}
+
+/// Walks every state reachable from the current one, through parents and the
+/// states epochs supersede, and checks every state, namespace, segment and pack
+/// blob against its sha256. The packs of each namespace are then indexed
+/// together, each checked with `git index-pack --verify`, and every ref the
+/// namespace ever held must be reachable from them and its shallow basis.
+pub fn verify(config: &Config) -> Result<VerifyReport> {
+    let (state_identifier, _state, _basis_state, _root_id, _commit_id) =
+        update_branches(config).context("verify")?;
+    let mut report = VerifyReport::default();
+    let Some(state_identifier) = state_identifier else {
+        return Ok(report);
+    };
+    let tracking_repo = Rc::new(config.tracking_repo()?);
+
+    let mut histories: BTreeMap<String, NamespaceHistory> = BTreeMap::new();
+    let mut seen_states = HashSet::new();
+    let mut seen_namespaces = HashSet::new();
+    let mut seen_segments = HashSet::new();
+    seen_states.insert(state_identifier.clone());
+    let mut stack = vec![state_identifier];
+    while let Some(state_ref) = stack.pop() {
+        let state = match decode_state(&tracking_repo, &state_ref, &config.nacl_keys) {
+            Ok(state) => state,
+            Err(err) => {
+                report.record(format!("state {}", &state_ref), err);
+                continue;
+            }
+        };
+        report.states += 1;
+        for parent in state.parents.iter().chain(state.supersedes.as_ref()) {
+            if seen_states.insert(parent.clone()) {
+                stack.push(parent.clone());
+            }
+        }
+
+        for (name, namespace_ref) in state.namespaces.iter() {
+            if !seen_namespaces.insert((name.clone(), namespace_ref.clone())) {
+                continue;
+            }
+            let namespace = match decode_namespace(&tracking_repo, namespace_ref, &config.nacl_keys)
+            {
+                Ok(namespace) => namespace,
+                // Another namespace may be under a key we don't have. Any
+                // other failure is a broken link like those of our own.
+                Err(err)
+                    if name != &config.namespace
+                        && err.downcast_ref::<Undecryptable>().is_some() =>
+                {
+                    report.unreadable.insert(name.clone());
+                    continue;
+                }
+                Err(err) => {
+                    report.record(format!("namespace {} in state {}", name, &state_ref), err);
+                    continue;
+                }
+            };
+            report.namespaces += 1;
+
+            let history = histories.entry(name.clone()).or_default();
+            history.object_hash = history.object_hash.or(namespace.object_hash());
+            history
+                .tips
+                .extend(namespace.refs.values().filter_map(Ref::oid_at_time));
+            history.shallow_basis.extend(
+                namespace
+                    .shallow_basis
+                    .values()
+                    .filter_map(Ref::oid_at_time),
+            );
+            if let Some(pack) = namespace.pack.as_ref() {
+                history.add_pack(pack);
+            }
+            let Some(manifest) = namespace.manifest.as_ref() else {
+                continue;
+            };
+            for pack in manifest.recent.iter().rev() {
+                history.add_pack(pack);
+            }
+            let mut segment_ref = manifest.segment.clone();
+            while let Some(segment) = segment_ref.take() {
+                if !seen_segments.insert(segment.clone()) {
+                    break;
+                }
+                match decode_pack_segment(&tracking_repo, &segment, &config.nacl_keys) {
+                    Ok(segment) => {
+                        for pack in segment.packs.iter().rev() {
+                            history.add_pack(pack);
+                        }
+                        segment_ref = segment.previous;
+                    }
+                    Err(err) => report.record(
+                        format!("pack segment {} of namespace {}", &segment.blob_ref, name),
+                        err,
+                    ),
+                }
+            }
+        }
+    }
+
+    for (name, history) in histories.iter() {
+        verify_packs(config, &tracking_repo, name, history, &mut report)
+            .with_context(|| format!("verify the packs of namespace {}", name))?;
+    }
+    Ok(report)
+}
+
+// Indexes the namespace's packs together in a scratch repo, so that each thin
+// pack finds its bases in the others, and then checks that its refs are
+// connected.
+fn verify_packs(
+    config: &Config,
+    tracking_repo: &Rc<gix::Repository>,
+    name: &str,
+    history: &NamespaceHistory,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let tmp = tempfile::Builder::new()
+        .prefix("recursive_remote_verify")
+        .tempdir()
+        .context("Unable to create temp dir.")?;
+    let object_hash = history.object_hash.unwrap_or(gix_hash::Kind::Sha1);
+    let repo_path = tmp.path().join("packs");
+    open_create_bare_repository_with_format(&repo_path, object_hash)?;
+
+    // Objects in the shallow basis were never pushed, so thin packs may have
+    // deltas against them that only the user repo can resolve. Only those are
+    // offered, so that a base found elsewhere in the user repo is still missing.
+    let supplied = shallow_basis_objects(config, &history.shallow_basis)?;
+    let basis_objects =
+        seed_shallow_basis(&config.user_repo_path, tmp.path(), &supplied, object_hash)?;
+
+    // The history is walked newest first, so the packs are indexed oldest first,
+    // but a pack whose bases are in a pack not yet indexed is tried again after
+    // the rest.
+    let mut pending: Vec<_> = history.packs.iter().rev().collect();
+    let mut failures = Vec::new();
+    loop {
+        let mut indexed_any = false;
+        for pack_ref in pending.drain(..) {
+            let mut cmd = git_command();
+            cmd.current_dir(&repo_path)
+                .env("GIT_ALTERNATE_OBJECT_DIRECTORIES", &basis_objects);
+            match index_pack(
+                config,
+                tracking_repo,
+                cmd,
+                pack_ref.clone(),
+                &mut Progress::disabled(),
+            ) {
+                Ok(pack_name) => {
+                    indexed_any = true;
+                    report.packs += 1;
+                    if let Some(pack_name) = pack_name
+                        && let Err(err) = verify_index(&repo_path, &pack_name)
+                    {
+                        report.record(
+                            format!("pack {} of namespace {}", &pack_ref.blob_ref, name),
+                            err,
+                        );
+                    }
+                }
+                Err(err) => failures.push((pack_ref, err)),
+            }
+        }
+        if !indexed_any || failures.is_empty() {
+            break;
+        }
+        pending = failures
+            .drain(..)
+            .map(|(pack_ref, _err)| pack_ref)
+            .collect();
+    }
+    for (pack_ref, err) in failures {
+        report.record(
+            format!("pack {} of namespace {}", &pack_ref.blob_ref, name),
+            err,
+        );
+    }
+
+    let repo = gix::open(&repo_path).context("open verify repo")?;
+    let tips: Vec<_> = history.tips.iter().cloned().collect();
+    let missing = find_missing_objects(&repo, |oid| supplied.contains(oid), &tips)?;
+    if !missing.is_empty() {
+        const MAX_LISTED: usize = 10;
+        let listed: Vec<_> = missing
+            .iter()
+            .take(MAX_LISTED)
+            .map(ToString::to_string)
+            .collect();
+        let mut message = format!(
+            "{} object(s) reachable from its refs are in neither its packs nor its shallow basis: {}",
+            missing.len(),
+            listed.join(", ")
+        );
+        if missing.len() > MAX_LISTED {
+            message.push_str(&format!(" and {} more", missing.len() - MAX_LISTED));
+        }
+        report.record(
+            format!("refs of namespace {}", name),
+            anyhow::anyhow!(message),
+        );
+    }
+    Ok(())
+}
+
+// Checks the pack against the index just written for it.
+fn verify_index(repo_path: &Path, pack_name: &[u8]) -> Result<()> {
+    let pack_path = repo_path
+        .join("objects")
+        .join("pack")
+        .join(format!("pack-{}.pack", hex::encode(pack_name)));
+    execute_subprocess2(
+        git_command()
+            .current_dir(repo_path)
+            .arg("index-pack")
+            .arg("--verify")
+            .arg(&pack_path),
+    )
+    .context("git index-pack --verify")?;
+    Ok(())
+}
+
+// Copies `objects` from the user repo into a scratch repo of their own in
+// `dir`, returning its objects dir.
+fn seed_shallow_basis(
+    user_repo_path: &Path,
+    dir: &Path,
+    objects: &HashSet<ObjectId>,
+    object_hash: gix_hash::Kind,
+) -> Result<PathBuf> {
+    let repo_path = dir.join("shallow_basis");
+    open_create_bare_repository_with_format(&repo_path, object_hash)?;
+    let objects_dir = repo_path.join("objects");
+    if objects.is_empty() {
+        return Ok(objects_dir);
+    }
+
+    let mut cmd = git_command()
+        .env("GIT_DIR", user_repo_path)
+        .arg("pack-objects")
+        .arg("-q")
+        .arg(objects_dir.join("pack").join("pack"))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .context("Failed to spawn git pack-objects.")?;
+    let mut stdin = cmd.stdin.take().context("No stdin.")?;
+    for oid in objects.iter() {
+        writeln!(stdin, "{}", oid).context("write objects to git pack-objects")?;
+    }
+    drop(stdin);
+    wait_subprocess(&mut cmd).context("git pack-objects the shallow basis")?;
+    Ok(objects_dir)
+}
+
+// The objects reachable from the shallow basis, which pushes leave out of the
+// packs. Only the user repo can have them, since they were never pushed.
+fn shallow_basis_objects(
+    config: &Config,
+    shallow_basis: &BTreeSet<ObjectId>,
+) -> Result<HashSet<ObjectId>> {
+    let user_repo = config.user_repo()?;
+    let mut available = Vec::new();
+    for oid in shallow_basis.iter() {
+        if user_repo.has_object(oid) {
+            available.push(oid.to_string());
+        } else {
+            log::warn!(
+                "Shallow basis {} is not in this repo, so objects it supplies are reported missing.",
+                oid
+            );
+        }
+    }
+    if available.is_empty() {
+        return Ok(HashSet::default());
+    }
+
+    let output = execute_subprocess2(
+        git_command()
+            .env("GIT_DIR", &config.user_repo_path)
+            .arg("rev-list")
+            .arg("--objects")
+            .args(available),
+    )
+    .context("git rev-list the shallow basis")?;
+    std::str::from_utf8(&output.stdout)
+        .context("decode utf-8 from git rev-list")?
+        .lines()
+        .filter_map(|line| line.split_ascii_whitespace().next())
+        .map(|oid| ObjectId::from_hex(oid.as_bytes()).context("oid"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str], stdin: &[u8]) -> std::process::Output {
+        let mut child = git_command()
+            .current_dir(dir)
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .expect("spawn git");
+        child
+            .stdin
+            .take()
+            .expect("stdin")
+            .write_all(stdin)
+            .expect("write stdin");
+        child.wait_with_output().expect("git")
+    }
+
+    fn objects_of(dir: &Path, rev: &str) -> HashSet<ObjectId> {
+        let output = git(dir, &["rev-list", "--objects", rev], b"");
+        assert!(output.status.success());
+        std::str::from_utf8(&output.stdout)
+            .expect("utf-8")
+            .lines()
+            .filter_map(|line| line.split_ascii_whitespace().next())
+            .map(|oid| ObjectId::from_hex(oid.as_bytes()).expect("oid"))
+            .collect()
+    }
+
+    #[test]
+    fn thin_packs_resolve_only_against_the_shallow_basis() {
+        let tmp = tempfile::Builder::new()
+            .prefix("verify-tests")
+            .tempdir()
+            .expect("tempdir");
+        let user = tmp.path().join("user");
+        let dest = tmp.path().join("dest");
+        gix::init(&user).expect("init user");
+        gix::init_bare(&dest).expect("init dest");
+
+        let body: String = (0..200).map(|i| format!("line {i}\n")).collect();
+        std::fs::write(user.join("file"), &body).expect("write");
+        assert!(git(&user, &["add", "file"], b"").status.success());
+        assert!(git(&user, &["commit", "-qm", "base"], b"").status.success());
+        std::fs::write(user.join("file"), body + "more\n").expect("write");
+        assert!(
+            git(&user, &["commit", "-qam", "child"], b"")
+                .status
+                .success()
+        );
+        let thin = git(
+            &user,
+            &["pack-objects", "--stdout", "--revs", "--thin"],
+            b"HEAD\n^HEAD~1\n",
+        );
+        assert!(thin.status.success());
+
+        let index = |seed: &str, objects: &HashSet<ObjectId>| {
+            let basis_objects = seed_shallow_basis(
+                &user.join(".git"),
+                &tmp.path().join(seed),
+                objects,
+                gix_hash::Kind::Sha1,
+            )
+            .expect("seed");
+            let mut cmd = git_command();
+            cmd.current_dir(&dest)
+                .env("GIT_ALTERNATE_OBJECT_DIRECTORIES", &basis_objects)
+                .args(["index-pack", "--fix-thin", "--stdin"])
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null());
+            let mut child = cmd.spawn().expect("spawn git index-pack");
+            child
+                .stdin
+                .take()
+                .expect("stdin")
+                .write_all(&thin.stdout)
+                .expect("write pack");
+            child.wait().expect("git index-pack").success()
+        };
+
+        // The base is in the user repo, but not in the shallow basis.
+        assert!(!index("unrelated", &HashSet::new()));
+        assert!(index("base", &objects_of(&user, "HEAD~1")));
+    }
+}
@@ -59,10 +59,45 @@ fn protocol_capabilities_reports_expected_features() {
         .write_stdin("capabilities\n")
         .assert()
         .success()
+        .stdout(predicate::str::contains("option"))
+        .stdout(predicate::str::contains("object-format"))
+        .stdout(predicate::str::contains("check-connectivity"))
         .stdout(predicate::str::contains("push"))
         .stdout(predicate::str::contains("fetch"));
 }
 
+#[test]
+fn protocol_option_replies_ok_or_unsupported() {
+    let paths = setup_paths();
+    let mut cmd = assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("git-remote-recursive"));
+    cmd.env("GIT_DIR", &paths.git_dir)
+        .env_remove("RUST_LOG")
+        .arg("origin")
+        .arg(&paths.remote_spec)
+        .write_stdin(
+            "option verbosity 0\noption progress false\noption no-such-option 1\noption verbosity loud\n",
+        )
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "ok\nok\nunsupported\nerror invalid verbosity",
+        ));
+}
+
+#[test]
+fn protocol_quiet_verbosity_suppresses_logging() {
+    let paths = setup_paths();
+    let mut cmd = assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("git-remote-recursive"));
+    cmd.env("GIT_DIR", &paths.git_dir)
+        .env_remove("RUST_LOG")
+        .arg("origin")
+        .arg(&paths.remote_spec)
+        .write_stdin("option verbosity 0\nlist\n\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty());
+}
+
 #[test]
 fn protocol_invalid_push_spec_returns_error() {
     let paths = setup_paths();
@@ -96,6 +131,41 @@ fn protocol_push_requires_blank_line_terminator() {
         ));
 }
 
+#[test]
+fn protocol_push_reports_every_ref_before_one_blank_line() {
+    let paths = setup_paths();
+    let user_repo = paths.git_dir.parent().expect("work tree");
+    git_command()
+        .arg("-C")
+        .arg(user_repo)
+        .args(["commit", "--allow-empty", "-m", "first"])
+        .assert()
+        .success();
+    git_command()
+        .arg("-C")
+        .arg(user_repo)
+        .args(["branch", "other"])
+        .assert()
+        .success();
+
+    let mut cmd = assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("git-remote-recursive"));
+    let assert = cmd
+        .env("GIT_DIR", &paths.git_dir)
+        .arg("origin")
+        .arg(&paths.remote_spec)
+        .write_stdin(
+            "push refs/heads/main:refs/heads/main\npush refs/heads/other:refs/heads/other\n\n",
+        )
+        .assert()
+        .success();
+    let stdout = String::from_utf8(assert.get_output().stdout.clone()).expect("utf-8");
+    let (statuses, rest) = stdout.split_once("\n\n").expect("blank line");
+    let mut statuses: Vec<_> = statuses.lines().collect();
+    statuses.sort();
+    assert_eq!(statuses, vec!["ok refs/heads/main", "ok refs/heads/other"]);
+    assert_eq!(rest, "");
+}
+
 #[test]
 fn protocol_fetch_requires_blank_line_terminator() {
     let paths = setup_paths();
@@ -125,6 +195,19 @@ fn protocol_list_works_with_empty_state() {
         .stdout(predicate::str::contains("\n"));
 }
 
+#[test]
+fn protocol_list_reports_object_format_when_asked() {
+    let paths = setup_paths();
+    let mut cmd = assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("git-remote-recursive"));
+    cmd.env("GIT_DIR", &paths.git_dir)
+        .arg("origin")
+        .arg(&paths.remote_spec)
+        .write_stdin("option object-format true\nlist\n\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("ok\n:object-format sha1\n"));
+}
+
 #[test]
 fn protocol_fetch_without_oid_does_not_panic() {
     let paths = setup_paths();
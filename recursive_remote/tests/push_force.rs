@@ -31,6 +31,7 @@ struct Harness {
     bin_dir: PathBuf,
     workdir1: PathBuf,
     workdir2: PathBuf,
+    upstream: PathBuf,
     remote_name: &'static str,
 }
 
@@ -46,7 +47,8 @@ impl Harness {
         let upstream_repo = gix::init_bare(tmp_path.join("upstream_repo")).expect("init upstream");
         let workdir1 = user_repo1.workdir().expect("workdir1").to_owned();
         let workdir2 = user_repo2.workdir().expect("workdir2").to_owned();
-        let upstream_url = format!("recursive::file://{}", upstream_repo.path().display());
+        let upstream = upstream_repo.path().to_owned();
+        let upstream_url = format!("recursive::file://{}", upstream.display());
 
         configure_remote(&mut user_repo1, "clear", &upstream_url);
         configure_remote(&mut user_repo2, "clear", &upstream_url);
@@ -71,6 +73,7 @@ impl Harness {
             bin_dir,
             workdir1,
             workdir2,
+            upstream,
             remote_name: "clear",
         }
     }
@@ -104,6 +107,74 @@ impl Harness {
         }
         cmd.arg(self.remote_name).arg(spec).assert()
     }
+
+    fn push_dry_run(&self, workdir: &Path, spec: &str) -> assert_cmd::assert::Assert {
+        git(&self.bin_dir)
+            .current_dir(workdir)
+            .arg("push")
+            .arg("--dry-run")
+            .arg(self.remote_name)
+            .arg(spec)
+            .assert()
+    }
+
+    fn pull(&self, workdir: &Path) {
+        git(&self.bin_dir)
+            .current_dir(workdir)
+            .arg("pull")
+            .arg("--rebase=false")
+            .arg(self.remote_name)
+            .assert()
+            .success();
+    }
+
+    // Runs the helper directly, as git would, so that tests can drive the
+    // protocol without git's own client-side push checks.
+    fn helper(&self, workdir: &Path) -> assert_cmd::Command {
+        let mut cmd =
+            assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("git-remote-recursive"));
+        cmd.env("GIT_DIR", workdir.join(".git"))
+            .arg(self.remote_name)
+            .arg(format!("file://{}", self.upstream.display()));
+        cmd
+    }
+
+    // A scratch dir for files such as keys that belong to no repo.
+    fn scratch_dir(&self) -> &Path {
+        self._tmp.path()
+    }
+
+    fn rev_parse(&self, workdir: &Path, rev: &str) -> String {
+        let output = git(&self.bin_dir)
+            .current_dir(workdir)
+            .arg("rev-parse")
+            .arg(rev)
+            .output()
+            .expect("git rev-parse");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .expect("utf-8")
+            .trim()
+            .to_string()
+    }
+
+    fn set_config(&self, workdir: &Path, key: ConfigKey, value: &str) {
+        git(&self.bin_dir)
+            .current_dir(workdir)
+            .arg("config")
+            .arg(format!("remote.{}.{}", self.remote_name, key))
+            .arg(value)
+            .assert()
+            .success();
+    }
+
+    fn upstream_head(&self) -> gix::ObjectId {
+        let repo = gix::open(&self.upstream).expect("open upstream");
+        let mut r = repo
+            .find_reference("refs/heads/main")
+            .expect("upstream main");
+        r.peel_to_id().expect("peel upstream main").detach()
+    }
 }
 
 fn configure_remote(repo: &mut gix::Repository, remote_name: &str, url: &str) {
@@ -216,3 +287,647 @@ fn tag_update_requires_force() {
     h.push(&h.workdir2, true, "refs/tags/v1:refs/tags/v1")
         .success();
 }
+
+#[test]
+fn dry_run_reports_status_without_touching_upstream() {
+    let h = Harness::new();
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.pull(&h.workdir2);
+    let before = h.upstream_head();
+
+    h.commit_file(&h.workdir1, "from1.txt", "from1", "from1");
+    h.push_dry_run(&h.workdir1, "main:main").success();
+    assert_eq!(h.upstream_head(), before);
+
+    h.push(&h.workdir1, false, "main:main").success();
+    let after = h.upstream_head();
+    assert_ne!(after, before);
+
+    h.commit_file(&h.workdir2, "from2.txt", "from2", "from2");
+    h.push_dry_run(&h.workdir2, "main:main").failure().stderr(
+        predicate::str::contains("rejected")
+            .or(predicate::str::contains("failed to push some refs")),
+    );
+    assert_eq!(h.upstream_head(), after);
+}
+
+#[test]
+fn atomic_push_rejects_every_ref_when_one_fails() {
+    let h = Harness::new();
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.pull(&h.workdir2);
+
+    h.commit_file(&h.workdir1, "from1.txt", "from1", "from1");
+    h.push(&h.workdir1, false, "main:main").success();
+    let before = h.upstream_head();
+
+    // Fetch without merging so that the helper knows about the upstream commit
+    // that the local main has diverged from.
+    h.commit_file(&h.workdir2, "from2.txt", "from2", "from2");
+    git(&h.bin_dir)
+        .current_dir(&h.workdir2)
+        .arg("fetch")
+        .arg(h.remote_name)
+        .assert()
+        .success();
+    git(&h.bin_dir)
+        .current_dir(&h.workdir2)
+        .arg("branch")
+        .arg("topic")
+        .assert()
+        .success();
+
+    h.helper(&h.workdir2)
+        .write_stdin(concat!(
+            "option atomic true\n",
+            "push refs/heads/main:refs/heads/main\n",
+            "push refs/heads/topic:refs/heads/topic\n",
+            "\n",
+        ))
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("ok\n"))
+        .stdout(predicate::str::contains("error refs/heads/main rejected\n"))
+        .stdout(predicate::str::contains(
+            "error refs/heads/topic atomic push failed\n",
+        ));
+    assert_eq!(h.upstream_head(), before);
+
+    // Without atomic, the new branch goes through on its own.
+    h.helper(&h.workdir2)
+        .write_stdin(concat!(
+            "push refs/heads/main:refs/heads/main\n",
+            "push refs/heads/topic:refs/heads/topic\n",
+            "\n",
+        ))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("error refs/heads/main rejected\n"))
+        .stdout(predicate::str::contains("ok refs/heads/topic\n"));
+    assert_ne!(h.upstream_head(), before);
+}
+
+#[test]
+fn force_with_lease_rejects_stale_expectation() {
+    let h = Harness::new();
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.pull(&h.workdir2);
+    let base = h.rev_parse(&h.workdir2, "HEAD");
+
+    h.commit_file(&h.workdir1, "from1.txt", "from1", "from1");
+    h.push(&h.workdir1, false, "main:main").success();
+    let from1 = h.rev_parse(&h.workdir1, "HEAD");
+    let before = h.upstream_head();
+
+    h.commit_file(&h.workdir2, "from2.txt", "from2", "from2");
+    git(&h.bin_dir)
+        .current_dir(&h.workdir2)
+        .arg("fetch")
+        .arg(h.remote_name)
+        .assert()
+        .success();
+
+    h.helper(&h.workdir2)
+        .write_stdin(format!(
+            "option cas refs/heads/main:{base}\npush +refs/heads/main:refs/heads/main\n\n"
+        ))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "error refs/heads/main stale info\n",
+        ));
+    assert_eq!(h.upstream_head(), before);
+
+    h.helper(&h.workdir2)
+        .write_stdin(format!(
+            "option cas refs/heads/main:{from1}\npush +refs/heads/main:refs/heads/main\n\n"
+        ))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("ok refs/heads/main\n"));
+    assert_ne!(h.upstream_head(), before);
+}
+
+#[test]
+fn push_options_are_recorded_in_namespace() {
+    let h = Harness::new();
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    git(&h.bin_dir)
+        .current_dir(&h.workdir1)
+        .arg("push")
+        .arg("-o")
+        .arg("release 4.2")
+        .arg("-o")
+        .arg("ticket=ABC-1")
+        .arg(h.remote_name)
+        .arg("main:main")
+        .assert()
+        .success();
+
+    h.helper(&h.workdir1)
+        .arg("-d")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Push option: release 4.2"))
+        .stderr(predicate::str::contains("Push option: ticket=ABC-1"));
+
+    // Options describe a single push, so a later push without any clears them.
+    h.commit_file(&h.workdir1, "next.txt", "next", "next");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.helper(&h.workdir1)
+        .arg("-d")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Push option:").not());
+}
+
+#[test]
+fn new_branches_use_the_versioned_format() {
+    let h = Harness::new();
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.helper(&h.workdir1)
+        .arg("-d")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Format: version 1"))
+        .stderr(predicate::str::contains("Format: unversioned").not());
+
+    // Migrating a current branch leaves it untouched.
+    let before = h.upstream_head();
+    h.helper(&h.workdir1).arg("--migrate").assert().success();
+    assert_eq!(h.upstream_head(), before);
+
+    h.pull(&h.workdir2);
+    assert_eq!(
+        h.rev_parse(&h.workdir2, "HEAD"),
+        h.rev_parse(&h.workdir1, "HEAD")
+    );
+}
+
+#[test]
+fn head_is_advertised_as_symref_and_can_be_changed() {
+    let h = Harness::new();
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    git(&h.bin_dir)
+        .current_dir(&h.workdir1)
+        .arg("branch")
+        .arg("topic")
+        .assert()
+        .success();
+    git(&h.bin_dir)
+        .current_dir(&h.workdir1)
+        .arg("push")
+        .arg(h.remote_name)
+        .arg("topic:topic")
+        .arg("main:main")
+        .assert()
+        .success();
+
+    // The first push points HEAD at the branch checked out by the pusher.
+    h.helper(&h.workdir1)
+        .write_stdin("list\n\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("@refs/heads/main HEAD\n"));
+
+    h.helper(&h.workdir1)
+        .arg("--set-head")
+        .arg("refs/heads/topic")
+        .assert()
+        .success();
+    h.helper(&h.workdir2)
+        .write_stdin("list\n\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("@refs/heads/topic HEAD\n"));
+
+    h.helper(&h.workdir1)
+        .arg("--set-head")
+        .arg("refs/heads/missing")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a ref in namespace"));
+}
+
+#[test]
+fn list_advertises_peeled_annotated_tags() {
+    let h = Harness::new();
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    git(&h.bin_dir)
+        .current_dir(&h.workdir1)
+        .arg("tag")
+        .arg("-a")
+        .arg("v1")
+        .arg("-m")
+        .arg("release v1")
+        .assert()
+        .success();
+    git(&h.bin_dir)
+        .current_dir(&h.workdir1)
+        .arg("tag")
+        .arg("lightweight")
+        .assert()
+        .success();
+    git(&h.bin_dir)
+        .current_dir(&h.workdir1)
+        .arg("push")
+        .arg(h.remote_name)
+        .arg("main:main")
+        .arg("refs/tags/v1:refs/tags/v1")
+        .arg("refs/tags/lightweight:refs/tags/lightweight")
+        .assert()
+        .success();
+
+    let commit = h.rev_parse(&h.workdir1, "main");
+    let tag = h.rev_parse(&h.workdir1, "refs/tags/v1");
+    h.helper(&h.workdir1)
+        .write_stdin("list\n\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{tag} refs/tags/v1\n{commit} refs/tags/v1^{{}}\n"
+        )))
+        .stdout(predicate::str::contains("refs/tags/lightweight^{}").not());
+}
+
+#[test]
+fn progress_is_reported_when_requested() {
+    let h = Harness::new();
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.helper(&h.workdir1)
+        .write_stdin("option progress true\npush refs/heads/main:refs/heads/main\n\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("ok refs/heads/main"))
+        .stderr(predicate::str::contains("Uploading pack:"))
+        .stderr(predicate::str::contains(", done.\n"));
+
+    let head = h.rev_parse(&h.workdir1, "main");
+    h.helper(&h.workdir2)
+        .write_stdin(format!(
+            "option progress true\nfetch {head} refs/heads/main\n\n"
+        ))
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Reading state history: 1, done.\n",
+        ))
+        .stderr(predicate::str::contains("Receiving packs: 100% (1/1),"));
+
+    // Without the option, nothing is drawn.
+    h.helper(&h.workdir2)
+        .write_stdin(format!("fetch {head} refs/heads/main\n\n"))
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Receiving packs").not());
+}
+
+#[test]
+fn large_packs_are_kept_in_the_annex_dir() {
+    let h = Harness::new();
+    let annex = h.upstream.with_file_name("annex");
+    for workdir in [&h.workdir1, &h.workdir2] {
+        h.set_config(workdir, ConfigKey::AnnexDir, annex.to_str().expect("utf-8"));
+        h.set_config(workdir, ConfigKey::AnnexThreshold, "0");
+    }
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.helper(&h.workdir1)
+        .arg("-d")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Pack: annex:SHA256-s"));
+    let blobs = walkdir::WalkDir::new(&annex)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .count();
+    assert_eq!(blobs, 1);
+
+    h.pull(&h.workdir2);
+    assert_eq!(
+        h.rev_parse(&h.workdir2, "HEAD"),
+        h.rev_parse(&h.workdir1, "HEAD")
+    );
+}
+
+#[test]
+fn audit_records_describe_each_update() {
+    let h = Harness::new();
+    h.set_config(
+        &h.workdir1,
+        ConfigKey::AuditIdentity,
+        "Test Pusher <pusher@example.com>",
+    );
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    let head = h.rev_parse(&h.workdir1, "HEAD");
+    h.helper(&h.workdir1)
+        .arg("-d")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Audit: Test Pusher <pusher@example.com> at ",
+        ))
+        .stderr(predicate::str::contains(format!(
+            "refs/heads/main: (none) -> direct:{head}"
+        )));
+
+    // A pusher without an identity leaves no record, rather than the last one.
+    h.pull(&h.workdir2);
+    h.commit_file(&h.workdir2, "next.txt", "next", "next");
+    h.push(&h.workdir2, false, "main:main").success();
+    h.helper(&h.workdir2)
+        .arg("-d")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Audit:").not());
+}
+
+#[test]
+fn ref_history_lists_each_value_newest_first() {
+    let h = Harness::new();
+    h.set_config(&h.workdir1, ConfigKey::AuditIdentity, "Test Pusher");
+
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    let first = h.rev_parse(&h.workdir1, "HEAD");
+    h.commit_file(&h.workdir1, "next.txt", "next", "next");
+    h.push(&h.workdir1, false, "main:main").success();
+    let second = h.rev_parse(&h.workdir1, "HEAD");
+
+    let output = h
+        .helper(&h.workdir1)
+        .arg("--ref-history=main")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).expect("utf-8");
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 2, "{output}");
+    assert!(lines[0].starts_with(&format!(
+        "refs/heads/main@{{0}}: direct:{second} (was direct:{first}) in state "
+    )));
+    assert!(lines[0].contains(" by Test Pusher at "), "{output}");
+    assert!(lines[1].starts_with(&format!(
+        "refs/heads/main@{{1}}: direct:{first} (was (none)) in state "
+    )));
+
+    // Without a filter, HEAD is listed too.
+    h.helper(&h.workdir1)
+        .arg("-L")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "HEAD@{0}: symbolic:refs/heads/main (was (none))",
+        ));
+}
+
+#[test]
+fn gc_begins_an_epoch_that_existing_clients_accept() {
+    let h = Harness::new();
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.commit_file(&h.workdir1, "next.txt", "next", "next");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.pull(&h.workdir2);
+
+    h.helper(&h.workdir1).arg("--gc").assert().success();
+    h.helper(&h.workdir1)
+        .arg("-d")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Supersedes: "))
+        .stderr(predicate::str::contains(
+            "Pack manifest: 1 packs, 1 since the last segment",
+        ))
+        .stderr(predicate::str::contains("Packs before the manifest from").not());
+
+    // The refs carry over, and pushes and fetches go on from the epoch.
+    h.commit_file(&h.workdir1, "after.txt", "after", "after");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.pull(&h.workdir2);
+    assert_eq!(
+        h.rev_parse(&h.workdir2, "HEAD"),
+        h.rev_parse(&h.workdir1, "HEAD")
+    );
+    assert_eq!(
+        std::fs::read_to_string(h.workdir2.join("next.txt")).expect("read"),
+        "next"
+    );
+}
+
+#[test]
+fn renamed_namespaces_keep_their_refs_packs_and_history() {
+    let h = Harness::new();
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.commit_file(&h.workdir1, "next.txt", "next", "next");
+    h.push(&h.workdir1, false, "main:main").success();
+
+    h.helper(&h.workdir1)
+        .arg("--rename-namespace=renamed_ns")
+        .assert()
+        .success();
+    for workdir in [&h.workdir1, &h.workdir2] {
+        h.set_config(workdir, ConfigKey::Namespace, "renamed_ns");
+    }
+
+    h.pull(&h.workdir2);
+    assert_eq!(
+        h.rev_parse(&h.workdir2, "HEAD"),
+        h.rev_parse(&h.workdir1, "HEAD")
+    );
+    h.helper(&h.workdir1)
+        .arg("--ref-history=main")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("refs/heads/main@{1}: "));
+
+    h.helper(&h.workdir1)
+        .arg("--rename-namespace=renamed_ns")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already exists"));
+}
+
+#[test]
+fn deleted_namespaces_leave_the_tree() {
+    let h = Harness::new();
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.helper(&h.workdir1)
+        .arg("--delete-namespace")
+        .assert()
+        .success();
+
+    let upstream = gix::open(&h.upstream).expect("open upstream");
+    let tree = upstream
+        .find_commit(h.upstream_head())
+        .expect("upstream commit")
+        .tree()
+        .expect("upstream tree");
+    for entry in tree.iter() {
+        let entry = entry.expect("tree entry");
+        assert!(!entry.filename().to_string().starts_with("ns_"));
+    }
+    h.helper(&h.workdir1)
+        .arg("-d")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Namespace push_force_ns:").not());
+
+    // Pushing again starts the namespace afresh.
+    h.push(&h.workdir1, true, "main:main").success();
+    h.pull(&h.workdir2);
+    assert_eq!(
+        h.rev_parse(&h.workdir2, "HEAD"),
+        h.rev_parse(&h.workdir1, "HEAD")
+    );
+}
+
+#[test]
+fn namespaces_are_listed_with_the_keys_that_decrypt_them() {
+    let h = Harness::new();
+    let keys = h.scratch_dir();
+    for (workdir, namespace_key) in [(&h.workdir1, "ns1.key"), (&h.workdir2, "ns2.key")] {
+        h.set_config(
+            workdir,
+            ConfigKey::StateNaclKey,
+            &format!("file://{}", keys.join("state.key").display()),
+        );
+        h.set_config(
+            workdir,
+            ConfigKey::NamespaceNaclKey,
+            &format!("file://{}", keys.join(namespace_key).display()),
+        );
+    }
+    h.set_config(&h.workdir2, ConfigKey::Namespace, "other_ns");
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.commit_file(&h.workdir2, "other.txt", "other", "other");
+    h.push(&h.workdir2, false, "main:main").success();
+
+    let output = h
+        .helper(&h.workdir1)
+        .arg("--list-namespaces")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).expect("utf-8");
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 2, "{output}");
+    assert_eq!(lines[0], "other_ns: not decryptable");
+    assert!(
+        lines[1].starts_with("push_force_ns: 2 refs, 1 packs, "),
+        "{output}"
+    );
+    assert!(lines[1].ends_with(" bytes, decryptable with the configured key"));
+
+    let keyring = keys.join("keyring");
+    std::fs::write(
+        &keyring,
+        format!("# other_ns\nfile://{}\n", keys.join("ns2.key").display()),
+    )
+    .expect("write keyring");
+    h.helper(&h.workdir1)
+        .arg("--list-namespaces")
+        .arg(format!("--keyring={}", keyring.display()))
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::is_match(
+                "other_ns: 2 refs, 1 packs, [0-9]+ bytes, decryptable with keyring key 1\n",
+            )
+            .expect("regex"),
+        );
+
+    // Nor is a configured remote needed, given the upstream and its keys.
+    assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("git-remote-recursive"))
+        .env_remove("GIT_DIR")
+        .current_dir(keys)
+        .arg("--list-namespaces")
+        .arg(format!("--state-key={}", keys.join("state.key").display()))
+        .arg(format!(
+            "--namespace-key={}",
+            keys.join("ns1.key").display()
+        ))
+        .arg(format!("file://{}", h.upstream.display()))
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "other_ns: not decryptable\npush_force_ns: 2 refs, 1 packs, ",
+        ));
+    assert_cmd::Command::new(assert_cmd::cargo::cargo_bin!("git-remote-recursive"))
+        .env_remove("GIT_DIR")
+        .arg("--list-namespaces")
+        .arg(format!(
+            "--state-key={}",
+            keys.join("missing.key").display()
+        ))
+        .arg(format!(
+            "--namespace-key={}",
+            keys.join("ns1.key").display()
+        ))
+        .arg(format!("file://{}", h.upstream.display()))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not found"));
+    assert!(!keys.join("missing.key").exists());
+}
+
+#[test]
+fn verify_reports_corrupt_packs() {
+    let h = Harness::new();
+    let annex = h.upstream.with_file_name("annex");
+    h.set_config(
+        &h.workdir1,
+        ConfigKey::AnnexDir,
+        annex.to_str().expect("utf-8"),
+    );
+    h.set_config(&h.workdir1, ConfigKey::AnnexThreshold, "0");
+    h.commit_file(&h.workdir1, "base.txt", "base", "base");
+    h.push(&h.workdir1, false, "main:main").success();
+    h.commit_file(&h.workdir1, "next.txt", "next", "next");
+    h.push(&h.workdir1, false, "main:main").success();
+
+    h.helper(&h.workdir1)
+        .arg("--verify")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains(
+            "Verified 2 states, 2 namespace versions and 2 packs.",
+        ));
+
+    let chunk = walkdir::WalkDir::new(&annex)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.file_type().is_file())
+        .expect("annexed chunk");
+    std::fs::write(chunk.path(), "corrupt").expect("corrupt chunk");
+    h.helper(&h.workdir1)
+        .arg("--verify")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("broken: pack annex:SHA256-s"))
+        .stdout(predicate::str::contains("does not match its key"));
+}
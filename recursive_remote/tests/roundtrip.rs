@@ -180,6 +180,26 @@ fn roundtrip_crypttext_churn() {
     scenario_churn(&Flavor::Encrypted, false);
 }
 
+#[test]
+fn roundtrip_cleartext_sha256_initial_sync() {
+    scenario_sha256_initial_sync(&Flavor::Plain);
+}
+
+#[test]
+fn roundtrip_crypttext_sha256_initial_sync() {
+    scenario_sha256_initial_sync(&Flavor::Encrypted);
+}
+
+#[test]
+fn roundtrip_cleartext_annotated_tag() {
+    scenario_annotated_tag(&Flavor::Plain, false);
+}
+
+#[test]
+fn roundtrip_crypttext_annotated_tag() {
+    scenario_annotated_tag(&Flavor::Encrypted, false);
+}
+
 struct ScenarioHarness {
     _tempdir: assert_fs::TempDir,
     bin_dir: PathBuf,
@@ -190,6 +210,15 @@ struct ScenarioHarness {
 
 impl ScenarioHarness {
     fn new(flavor: &Flavor, embed_config: bool) -> ScenarioHarness {
+        Self::with_object_format(flavor, embed_config, None)
+    }
+
+    // As new, with user repos in `object_format` rather than git's default.
+    fn with_object_format(
+        flavor: &Flavor,
+        embed_config: bool,
+        object_format: Option<&str>,
+    ) -> ScenarioHarness {
         let tempdir = assert_fs::TempDir::new().unwrap();
         let tmp_path = tempdir.path();
         let bin_dir = tmp_path.join("bin");
@@ -199,8 +228,20 @@ impl ScenarioHarness {
         // brittle.
         get_binary(&bin_dir);
 
-        let mut user_repo1 = gix::init(tmp_path.join("user_repo1")).unwrap();
-        let mut user_repo2 = gix::init(tmp_path.join("user_repo2")).unwrap();
+        let init = |path: PathBuf| match object_format {
+            None => gix::init(path).unwrap(),
+            Some(object_format) => {
+                git(&bin_dir)
+                    .arg("init")
+                    .arg(format!("--object-format={}", object_format))
+                    .arg(&path)
+                    .assert()
+                    .success();
+                gix::open(path).unwrap()
+            }
+        };
+        let mut user_repo1 = init(tmp_path.join("user_repo1"));
+        let mut user_repo2 = init(tmp_path.join("user_repo2"));
         let workdir1 = user_repo1.workdir().unwrap().to_owned();
         let workdir2 = user_repo2.workdir().unwrap().to_owned();
         let upstream_repo = gix::init_bare(tmp_path.join("upstream_repo")).unwrap();
@@ -371,6 +412,37 @@ impl ScenarioHarness {
             );
     }
 
+    // Pushing an annotated tag keeps the tag object itself reachable, which a
+    // branch in the all objects repo cannot point at.
+    fn run_annotated_tag(&self) {
+        git(&self.bin_dir)
+            .current_dir(&self.workdir1)
+            .args(["tag", "-a", "v1", "-m", "release v1"])
+            .assert()
+            .success();
+        pretty_print(
+            git(&self.bin_dir)
+                .current_dir(&self.workdir1)
+                .arg("push")
+                .arg(self.remote_name)
+                .arg("refs/tags/v1:refs/tags/v1"),
+        );
+
+        pretty_print(
+            git(&self.bin_dir)
+                .current_dir(&self.workdir2)
+                .arg("fetch")
+                .arg(self.remote_name)
+                .arg("refs/tags/v1:refs/tags/v1"),
+        );
+        git(&self.bin_dir)
+            .current_dir(&self.workdir2)
+            .args(["cat-file", "-t", "v1"])
+            .assert()
+            .success()
+            .stdout("tag\n");
+    }
+
     fn run_churn(&self) {
         for j in 100..115 {
             self.commit_file(j, &self.workdir1);
@@ -397,6 +469,18 @@ fn scenario_merge_roundtrip(flavor: &Flavor, embed_config: bool) {
     harness.run_merge_roundtrip();
 }
 
+fn scenario_sha256_initial_sync(flavor: &Flavor) {
+    let harness = ScenarioHarness::with_object_format(flavor, false, Some("sha256"));
+    harness.establish_two_way_sync();
+    harness.run_merge_roundtrip();
+}
+
+fn scenario_annotated_tag(flavor: &Flavor, embed_config: bool) {
+    let harness = ScenarioHarness::new(flavor, embed_config);
+    harness.establish_two_way_sync();
+    harness.run_annotated_tag();
+}
+
 fn scenario_churn(flavor: &Flavor, embed_config: bool) {
     let harness = ScenarioHarness::new(flavor, embed_config);
     harness.establish_two_way_sync();